@@ -8,8 +8,7 @@ use nom::{
     number::streaming::be_u32,
     Err, IResult,
 };
-use ovit_util::get_blocks_from_drive_and_correct_order;
-use std::fs::File;
+use ovit_util::{BlockSource, Error, Result};
 
 fn string(size: usize, input: &[u8]) -> IResult<&[u8], String> {
     let (input, str_bytes) = take(size)(input)?;
@@ -40,12 +39,12 @@ pub struct Partition {
 impl Partition {
     pub fn parse(input: &[u8]) -> IResult<&[u8], Partition> {
         let (input, _signature) = tag("PM")(input)?;
-        let (input, _reserved) = take(2 as usize)(input)?;
+        let (input, _reserved) = take(2usize)(input)?;
         let (input, partitions_total) = be_u32(input)?;
         let (input, starting_sector) = be_u32(input)?;
         let (input, sector_size) = be_u32(input)?;
-        let (input, name) = string(32 as usize, input)?;
-        let (input, r#type) = string(32 as usize, input)?;
+        let (input, name) = string(32usize, input)?;
+        let (input, r#type) = string(32usize, input)?;
         let (input, starting_data_sector) = be_u32(input)?;
         let (input, data_sectors) = be_u32(input)?;
         let (input, status) = be_u32(input)?;
@@ -56,8 +55,8 @@ impl Partition {
         let (input, boot_code_entry_point) = be_u32(input)?;
         let (input, _reserved) = be_u32(input)?;
         let (input, boot_code_checksum) = be_u32(input)?;
-        let (input, processor_type) = string(16 as usize, input)?;
-        let (input, _reserved) = take(376 as usize)(input)?;
+        let (input, processor_type) = string(16usize, input)?;
+        let (input, _reserved) = take(376usize)(input)?;
 
         Ok((
             input,
@@ -102,23 +101,73 @@ impl ApplePartitionMap {
         Ok((input, ApplePartitionMap { partitions }))
     }
 
-    pub fn read_from_file(
-        file: &mut File,
-        is_byte_swapped: bool,
-    ) -> Result<ApplePartitionMap, String> {
+    pub fn read_from_source(source: &dyn BlockSource) -> Result<ApplePartitionMap> {
         // The first block on a TiVo drive contain special TiVo magic,
         //  we're not worried about this for reconstructing the partition map.
-        let partition_map_buffer =
-            match get_blocks_from_drive_and_correct_order(file, 1, 64, is_byte_swapped) {
-                Ok(buffer) => buffer.to_vec(),
-                Err(err) => {
-                    return Err(format!("Could not read block from drive {:?}", err));
-                }
-            };
+        let partition_map_buffer = source.read_sectors(1, 64)?;
 
         match ApplePartitionMap::parse_from_driver_descriptor_map(&partition_map_buffer) {
             Ok((_, partition_map)) => Ok(partition_map),
-            Err(err) => Err(format!("Could not parse map {:?}", err)),
+            Err(err) => Err(Error::parse("partition map", &partition_map_buffer, err)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use ovit_util::{MemorySource, SECTOR_SIZE};
+
+    fn partition_entry(
+        partitions_total: u32,
+        starting_sector: u32,
+        sector_size: u32,
+        name: &str,
+        r#type: &str,
+    ) -> Vec<u8> {
+        let mut entry = vec![0; SECTOR_SIZE];
+        entry[0..2].copy_from_slice(b"PM");
+        entry[4..8].copy_from_slice(&partitions_total.to_be_bytes());
+        entry[8..12].copy_from_slice(&starting_sector.to_be_bytes());
+        entry[12..16].copy_from_slice(&sector_size.to_be_bytes());
+        entry[16..16 + name.len()].copy_from_slice(name.as_bytes());
+        entry[48..48 + r#type.len()].copy_from_slice(r#type.as_bytes());
+        entry
+    }
+
+    #[test]
+    fn test_read_from_source() {
+        let mut image = vec![0; SECTOR_SIZE];
+        image.extend(partition_entry(2, 1, 63, "Apple", "Apple_partition_map"));
+        image.extend(partition_entry(
+            2,
+            64,
+            1024,
+            "MFS application region",
+            "MFS",
+        ));
+        image.resize(SECTOR_SIZE * 65, 0);
+
+        let partition_map = ApplePartitionMap::read_from_source(&MemorySource::new(image)).unwrap();
+
+        assert_eq!(partition_map.partitions.len(), 2);
+        assert_eq!(partition_map.partitions[1].name, "MFS application region");
+        assert_eq!(partition_map.partitions[1].r#type, "MFS");
+        assert_eq!(partition_map.partitions[1].starting_sector, 64);
+        assert_eq!(partition_map.partitions[1].sector_size, 1024);
+    }
+
+    #[test]
+    fn test_read_from_source_without_partitions() {
+        let image = vec![0; SECTOR_SIZE * 65];
+
+        match ApplePartitionMap::read_from_source(&MemorySource::new(image)) {
+            Err(Error::Parse {
+                structure: "partition map",
+                offset: 0,
+                ..
+            }) => {}
+            result => panic!("Expected a parse error, got {:?}", result),
         }
     }
 }
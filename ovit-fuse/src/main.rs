@@ -5,8 +5,7 @@ extern crate log;
 
 use clap::{App, Arg};
 use log::info;
-use ovit_fuse::TiVoFS;
-use std::ffi::OsStr;
+use ovit_fuse::{TiVoFS, TiVoFSOptions};
 
 fn main() {
     env_logger::init();
@@ -27,6 +26,28 @@ fn main() {
                 .required(true)
                 .index(2),
         )
+        .arg(
+            Arg::with_name("uid")
+                .long("uid")
+                .value_name("UID")
+                .help("Sets the user owning the mounted files, defaults to the current user")
+                .takes_value(true)
+                .required(false),
+        )
+        .arg(
+            Arg::with_name("gid")
+                .long("gid")
+                .value_name("GID")
+                .help("Sets the group owning the mounted files, defaults to the current group")
+                .takes_value(true)
+                .required(false),
+        )
+        .arg(
+            Arg::with_name("db-json")
+                .long("db-json")
+                .help("Shows database objects as JSON, with references as relative paths")
+                .required(false),
+        )
         .get_matches();
 
     let tivo_drive_location = matches.value_of("TARGET").expect("No TiVo drive provided!");
@@ -34,20 +55,20 @@ fn main() {
         .value_of("MOUNT POINT")
         .expect("No mount point provided!");
 
-    let filesystem = TiVoFS {
-        drive_location: tivo_drive_location.to_string(),
-    };
+    info!("Loading TiVo drive {}", tivo_drive_location);
 
-    // TODO: Check that the drive location is actually available before mounting with FUSE
+    let mut options = TiVoFSOptions::default();
+    if let Some(uid) = matches.value_of("uid") {
+        options.uid = uid.parse().expect("UID must be a number");
+    }
+    if let Some(gid) = matches.value_of("gid") {
+        options.gid = gid.parse().expect("GID must be a number");
+    }
+    options.db_json = matches.is_present("db-json");
 
-    let fuse_args: Vec<&OsStr> = vec![&OsStr::new("-o"), &OsStr::new("auto_unmount")];
+    let filesystem = TiVoFS::new(tivo_drive_location, options).expect("Could not load TiVo drive");
 
     info!("Mounting TiVoFS with FUSE");
 
-    fuse_mt::mount(
-        fuse_mt::FuseMT::new(filesystem, 1),
-        &mount_point,
-        &fuse_args,
-    )
-    .expect("Could not mount!");
+    filesystem.mount(mount_point).expect("Could not mount!");
 }
@@ -2,248 +2,890 @@ extern crate ovit;
 extern crate rayon;
 extern crate tivo_media_file_system;
 
-use fuse_mt::{
-    DirectoryEntry, FileAttr, FileType, FilesystemMT, RequestInfo, ResultEmpty, ResultEntry,
-    ResultOpen, ResultReaddir,
+mod objects;
+use objects::*;
+
+mod recordings;
+use recordings::*;
+
+use fuse::{
+    FileAttr, FileType, Filesystem, ReplyAttr, ReplyData, ReplyDirectory, ReplyEmpty, ReplyEntry,
+    ReplyOpen, Request, FUSE_ROOT_ID,
 };
-use log::{debug, info, trace, warn};
-use ovit::TivoDrive;
-use rayon::prelude::*;
-use std::ffi::OsString;
-use std::path::{Component, Path};
+use log::{debug, info, warn};
+use ovit::{Error, LruCache, TivoDrive};
+use rayon::{ThreadPool, ThreadPoolBuilder};
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::ffi::{OsStr, OsString};
+use std::io::{self, Read, Seek, SeekFrom};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard, OnceLock};
+use std::thread;
 use time::Timespec;
-use tivo_media_file_system::MFSINodeType;
+use tivo_media_file_system::{MFSEntry, MFSINode, MFSINodeType, MFSSchema};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct TiVoFSOptions {
+    /// Who owns the mounted files.
+    pub uid: u32,
+    pub gid: u32,
+    /// Present `Db` objects as decoded JSON instead of their raw bytes.
+    pub db_json: bool,
+}
+
+impl Default for TiVoFSOptions {
+    /// Files belong to whoever mounted the drive.
+    fn default() -> TiVoFSOptions {
+        unsafe {
+            TiVoFSOptions {
+                uid: libc::getuid(),
+                gid: libc::getgid(),
+                db_json: false,
+            }
+        }
+    }
+}
 
+/// A TiVo drive opened once and shared by every request, which all read it at the same time.
+///  Directory entries are cached by the drive itself, the most recently used inodes and
+///  attributes are cached here by FSID.
+///
+/// Files on the drive use their FSID as their inode number, except that the root directory
+///  swaps places with FSID 1 since FUSE expects the root to be inode 1. Files that aren't on
+///  the drive are numbered above every FSID: `/Recordings` is `RECORDINGS_INODE` with its files
+///  after it, and objects shown as JSON start at `OBJECT_INODE`.
 pub struct TiVoFS {
-    pub drive_location: String,
+    drive: Arc<TivoDrive>,
+    options: TiVoFSOptions,
+    inodes: Mutex<LruCache<u32, Arc<MFSINode>>>,
+    attributes: Mutex<LruCache<u32, FileAttr>>,
+    /// Directories that have been looked up, by FSID.
+    directories: Mutex<HashMap<u32, DirectoryNode>>,
+
+    /// Built in the background when the mount shows objects as JSON.
+    object_paths: Arc<OnceLock<ObjectPaths>>,
+    object_inodes: Mutex<ObjectInodes>,
+    /// `Db` objects rendered as JSON, by the path they were read through.
+    objects: Mutex<LruCache<String, Arc<Vec<u8>>>>,
+    /// Built the first time `/Recordings` is looked at.
+    recordings: Mutex<Option<Arc<RecordingsView>>>,
+    /// Sizes of recordings as program streams, by FSID. Only an estimate until a recording
+    ///  has been read to the end.
+    video_sizes: Mutex<LruCache<u32, u64>>,
+    /// Recordings' closed captions as SRT, by FSID.
+    captions: Mutex<LruCache<u32, Arc<Vec<u8>>>>,
+    open_files: Mutex<HashMap<u64, Arc<Mutex<OpenFile>>>>,
+    /// Entries of open directories, listed when they're opened so reads carry on where the
+    ///  last one stopped.
+    open_directories: Mutex<HashMap<u64, Arc<Vec<DirectoryEntry>>>>,
+    next_handle: AtomicU64,
+}
+
+/// Where a directory on the drive was found.
+#[derive(Debug, Clone)]
+struct DirectoryNode {
+    parent: u64,
+    /// Empty for the root directory.
+    path: String,
+}
+
+#[derive(Debug)]
+struct DirectoryEntry {
+    inode: u64,
+    kind: FileType,
+    name: OsString,
+}
+
+/// What an inode number refers to.
+enum Node {
+    /// A file or directory on the drive.
+    Drive {
+        fsid: u32,
+    },
+    /// A `Db` object shown as JSON, as read through `path`.
+    Object {
+        fsid: u32,
+        path: String,
+    },
+    Recordings,
+    RecordingFile(RecordingFile),
 }
 
+/// A file in `/Recordings` that's been opened.
+enum OpenFile {
+    Buffer(Arc<Vec<u8>>),
+    Stream {
+        fsid: u32,
+        stream: Box<RecordingStream>,
+    },
+}
+
+const RECORDINGS_DIR: &str = "Recordings";
+/// The `/Recordings` directory, each of its files is numbered after it.
+const RECORDINGS_INODE: u64 = 1 << 32;
+/// The first object shown as JSON.
+const OBJECT_INODE: u64 = 2 << 32;
+/// Ask the kernel to pass reads straight through, for files whose size isn't known up front.
+const FOPEN_DIRECT_IO: u32 = 1;
+
+/// How many inodes and attributes to keep, enough for a few large directories.
+const INODE_CACHE_CAPACITY: usize = 16384;
+/// How many objects to keep as JSON, they're read once and can run to tens of kilobytes.
+const OBJECT_CACHE_CAPACITY: usize = 256;
+/// How many recordings to keep sizes for.
+const RECORDING_CACHE_CAPACITY: usize = 4096;
+/// How many recordings to keep captions for, an hour of SRT is around 100 KiB.
+const CAPTIONS_CACHE_CAPACITY: usize = 64;
+
 const TTL: Timespec = Timespec { sec: 1, nsec: 0 };
+/// FUSE counts blocks in 512 byte units whatever the file system's block size.
+const FUSE_BLOCK_SIZE: u64 = 512;
 
-fn get_tivo_drive(location: String) -> Result<TivoDrive, i32> {
-    match TivoDrive::from_disk_image(&location) {
-        Ok(drive) => Ok(drive),
-        Err(_err) => Err(0),
+fn errno(err: &Error) -> i32 {
+    match err {
+        Error::Io { source, .. } => source.raw_os_error().unwrap_or(libc::EIO),
+        Error::FsidNotFound(_) | Error::NotFound(_) => libc::ENOENT,
+        Error::Parse { .. }
+        | Error::BadMagic { .. }
+        | Error::ChecksumMismatch { .. }
+        | Error::VolumeSectorOutOfRange(_)
+        | Error::Schema(_) => libc::EIO,
     }
 }
 
-fn get_fsid_from_path(path: &Path, disk_location: String) -> Result<u32, i32> {
-    let mut previous_fsid: u32 = 0;
+/// Lock `mutex` even if a thread panicked while holding it, the caches stay usable either way.
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner())
+}
 
-    let fsids: Vec<Option<u32>> = path
-        .components()
-        .map(|component| match component {
-            Component::RootDir => {
-                previous_fsid = 1;
-                Some(1)
-            }
-            Component::Normal(path) => {
-                if previous_fsid == 0 {
-                    return None;
-                }
+/// Inode number of the file `fsid` on a drive whose root directory is `root`.
+fn fsid_inode(root: u32, fsid: u32) -> u64 {
+    match fsid {
+        fsid if fsid == root => FUSE_ROOT_ID,
+        1 => u64::from(root),
+        fsid => u64::from(fsid),
+    }
+}
 
-                let native_path = match path.to_owned().into_string() {
-                    Ok(string) => string,
-                    Err(_err) => return None,
-                };
+/// FSID of the file `inode` on a drive whose root directory is `root`, `None` for inodes that
+///  aren't on the drive.
+fn inode_fsid(root: u32, inode: u64) -> Option<u32> {
+    match inode {
+        FUSE_ROOT_ID => Some(root),
+        inode if inode == u64::from(root) => Some(1),
+        inode => u32::try_from(inode).ok(),
+    }
+}
 
-                match &mut get_tivo_drive(disk_location.clone()) {
-                    Ok(tivo_drive) => match tivo_drive.get_inode_from_fsid(previous_fsid) {
-                        Ok(inode) => {
-                            match inode.get_entries_from_directory(disk_location.clone()) {
-                                Ok(entries) => {
-                                    match entries.iter().find(|entry| entry.name == native_path) {
-                                        Some(entry) => {
-                                            previous_fsid = entry.fsid;
-                                            Some(entry.fsid)
-                                        }
-                                        None => None,
-                                    }
-                                }
-                                Err(_err) => None,
-                            }
-                        }
-                        Err(_err) => None,
-                    },
-                    Err(_err) => None,
-                }
-            }
-            _ => None,
-        })
-        .collect();
+fn file_type(r#type: &MFSINodeType) -> FileType {
+    match r#type {
+        MFSINodeType::Dir => FileType::Directory,
+        _ => FileType::RegularFile,
+    }
+}
+
+/// Attributes for a file that's read only to everyone.
+fn read_only_attributes(ino: u64, kind: FileType, options: &TiVoFSOptions) -> FileAttr {
+    let (perm, nlink) = match kind {
+        FileType::Directory => (0o555, 2),
+        _ => (0o444, 1),
+    };
 
-    if fsids.is_empty() {
-        return Err(0);
+    FileAttr {
+        ino,
+        size: 0,
+        blocks: 0,
+        atime: Timespec { sec: 0, nsec: 0 },
+        mtime: Timespec { sec: 0, nsec: 0 },
+        ctime: Timespec { sec: 0, nsec: 0 },
+        crtime: Timespec { sec: 0, nsec: 0 },
+        kind,
+        perm,
+        nlink,
+        uid: options.uid,
+        gid: options.gid,
+        rdev: 0,
+        flags: 0,
     }
+}
+
+fn inode_attributes(ino: u64, inode: &MFSINode, options: &TiVoFSOptions) -> FileAttr {
+    let modified = Timespec {
+        sec: inode.last_modified.timestamp(),
+        nsec: 0,
+    };
+    let allocated = u64::from(inode.blockused) * u64::from(inode.blocksize);
 
-    match fsids.last() {
-        Some(fsid_option) => match fsid_option {
-            Some(fsid) => Ok(*fsid),
-            None => Err(0),
-        },
-        None => Err(0),
+    FileAttr {
+        size: inode.stream_size(),
+        blocks: allocated.div_ceil(FUSE_BLOCK_SIZE),
+        // MFS only records when an inode last changed
+        atime: modified,
+        mtime: modified,
+        ctime: modified,
+        crtime: modified,
+        ..read_only_attributes(ino, file_type(&inode.r#type), options)
     }
 }
 
-impl FilesystemMT for TiVoFS {
-    fn init(&self, _req: RequestInfo) -> ResultEmpty {
-        trace!("init");
-        Ok(())
+impl TiVoFS {
+    pub fn new(drive_location: &str, options: TiVoFSOptions) -> ovit::Result<TiVoFS> {
+        let drive = Arc::new(TivoDrive::from_disk_image(drive_location)?);
+        let object_paths = Arc::new(OnceLock::new());
+
+        // Walking the whole tree takes a while, everything else carries on meanwhile
+        if options.db_json {
+            let drive = Arc::clone(&drive);
+            let object_paths = Arc::clone(&object_paths);
+
+            thread::Builder::new()
+                .name(String::from("object-paths"))
+                .spawn(move || {
+                    let _ = object_paths.set(ObjectPaths::new(&drive));
+                })
+                .map_err(|source| Error::Io {
+                    sector: None,
+                    source,
+                })?;
+        }
+
+        let root = DirectoryNode {
+            parent: FUSE_ROOT_ID,
+            path: String::new(),
+        };
+        let directories = HashMap::from([(drive.volume_header.root_fsid, root)]);
+
+        Ok(TiVoFS {
+            drive,
+            options,
+            inodes: Mutex::new(LruCache::new(INODE_CACHE_CAPACITY)),
+            attributes: Mutex::new(LruCache::new(INODE_CACHE_CAPACITY)),
+            directories: Mutex::new(directories),
+
+            object_paths,
+            object_inodes: Mutex::new(ObjectInodes::default()),
+            objects: Mutex::new(LruCache::new(OBJECT_CACHE_CAPACITY)),
+            recordings: Mutex::new(None),
+            video_sizes: Mutex::new(LruCache::new(RECORDING_CACHE_CAPACITY)),
+            captions: Mutex::new(LruCache::new(CAPTIONS_CACHE_CAPACITY)),
+            open_files: Mutex::new(HashMap::new()),
+            open_directories: Mutex::new(HashMap::new()),
+            next_handle: AtomicU64::new(1),
+        })
+    }
+
+    /// Mount the drive read only at `mount_point`, serving requests from a pool of threads
+    ///  until it's unmounted.
+    pub fn mount(self, mount_point: &str) -> io::Result<()> {
+        let pool = ThreadPoolBuilder::new()
+            .thread_name(|index| format!("fuse-{}", index))
+            .build()
+            .map_err(io::Error::other)?;
+        let options = [OsStr::new("-o"), OsStr::new("ro,auto_unmount")];
+
+        let workers = Workers {
+            fs: Arc::new(self),
+            pool,
+        };
+
+        fuse::mount(workers, &mount_point, &options)
+    }
+
+    fn root_fsid(&self) -> u32 {
+        self.drive.volume_header.root_fsid
+    }
+
+    /// What `ino` refers to, `None` if it's never been handed out.
+    fn node(&self, ino: u64) -> Option<Node> {
+        if ino >= OBJECT_INODE {
+            let object_inodes = lock(&self.object_inodes);
+            let (path, fsid) = object_inodes.get(ino - OBJECT_INODE)?;
+
+            return Some(Node::Object {
+                fsid,
+                path: path.to_string(),
+            });
+        }
+
+        if ino == RECORDINGS_INODE {
+            return Some(Node::Recordings);
+        }
+
+        if ino > RECORDINGS_INODE {
+            let index = usize::try_from(ino - RECORDINGS_INODE - 1).ok()?;
+            let (_, file) = self.get_recordings().files.get(index)?.clone();
+
+            return Some(Node::RecordingFile(file));
+        }
+
+        inode_fsid(self.root_fsid(), ino).map(|fsid| Node::Drive { fsid })
     }
 
-    fn destroy(&self, _req: RequestInfo) {
-        trace!("destroy");
+    /// Path of the directory `fsid` on the drive, if it's been looked up.
+    fn directory_path(&self, fsid: u32) -> Option<String> {
+        lock(&self.directories)
+            .get(&fsid)
+            .map(|directory| directory.path.clone())
     }
 
-    fn getattr(&self, _req: RequestInfo, path: &Path, _fh: Option<u64>) -> ResultEntry {
-        debug!("getattr: {:?}", path);
+    /// Inode number of `entry` in the directory `parent_fsid`, whose inode is `parent`.
+    ///  Directories and objects shown as JSON are remembered along with where they were found.
+    fn entry_inode(&self, parent: u64, parent_fsid: u32, entry: &MFSEntry) -> u64 {
+        let path = || {
+            let parent_path = self.directory_path(parent_fsid).unwrap_or_default();
+            format!("{}/{}", parent_path, entry.name)
+        };
+
+        match entry.r#type {
+            MFSINodeType::Dir => {
+                let directory = DirectoryNode {
+                    parent,
+                    path: path(),
+                };
+                lock(&self.directories).insert(entry.fsid, directory);
+            }
+            MFSINodeType::Db if self.options.db_json => {
+                return lock(&self.object_inodes).inode(OBJECT_INODE, &path(), entry.fsid);
+            }
+            _ => {}
+        }
+
+        fsid_inode(self.root_fsid(), entry.fsid)
+    }
 
-        let fsid = match get_fsid_from_path(path, self.drive_location.clone()) {
-            Ok(fsid) => fsid,
-            Err(_err) => {
-                warn!("Could not get FSID for path {:?}", path);
-                return Err(0);
+    /// Whether `inode` is shown as JSON rather than its raw bytes.
+    fn is_json_object(&self, inode: &MFSINode) -> bool {
+        self.options.db_json && inode.r#type == MFSINodeType::Db
+    }
+
+    /// `inode` decoded and rendered as JSON as seen from `path`, so references to other objects
+    ///  are relative to it. Waits for the object paths if they're still being found.
+    fn render_object_json(&self, path: &str, inode: &MFSINode) -> Option<Vec<u8>> {
+        if !self.is_json_object(inode) {
+            return None;
+        }
+
+        let object = inode.get_db_object(
+            &*self.drive.source,
+            &self.drive.volumes,
+            MFSSchema::bundled(),
+        );
+
+        // Objects that can't be decoded are still readable as bytes
+        let object = match object {
+            Ok(object) => object,
+            Err(err) => {
+                warn!("Could not decode object {}: {}", inode.fsid, err);
+                return None;
             }
         };
 
-        match get_tivo_drive(self.drive_location.clone())?.get_inode_from_fsid(fsid) {
-            Ok(inode) => Ok((
-                TTL,
-                FileAttr {
-                    size: u64::from(inode.size),
-                    blocks: u64::from(inode.blocksize),
-                    atime: TTL,
-                    mtime: Timespec {
-                        sec: inode.last_modified.timestamp(),
-                        nsec: 0,
-                    },
-                    ctime: TTL,
-                    crtime: TTL,
-                    kind: match inode.r#type {
-                        MFSINodeType::Dir => FileType::Directory,
-                        MFSINodeType::Node => FileType::RegularFile,
-                        MFSINodeType::Db => FileType::RegularFile,
-                        MFSINodeType::File => FileType::RegularFile,
-                        _ => FileType::RegularFile,
-                    },
-                    perm: 777,
-                    nlink: 0,
-                    uid: 1000,
-                    gid: 1000,
-                    rdev: 0,
-                    flags: 0,
-                },
-            )),
-            Err(_err) => {
-                warn!("getattr({:?}): File has an FSID from a parent directory, but INode could not be read. Creating a dummy file to maintain structure.", path);
+        let json = object_json(&object, path, self.object_paths.wait());
+        let mut json = serde_json::to_vec_pretty(&json).unwrap_or_default();
+        json.push(b'\n');
+
+        Some(json)
+    }
+
+    /// `inode` rendered as JSON as seen from `path`, kept for reading it again.
+    fn get_object_json(&self, path: &str, inode: &MFSINode) -> Option<Arc<Vec<u8>>> {
+        if let Some(json) = lock(&self.objects).get(path) {
+            return Some(Arc::clone(json));
+        }
+
+        let json = Arc::new(self.render_object_json(path, inode)?);
+        lock(&self.objects).insert(path.to_string(), Arc::clone(&json));
+
+        Some(json)
+    }
+
+    fn get_recordings(&self) -> Arc<RecordingsView> {
+        let mut recordings = lock(&self.recordings);
+
+        if let Some(view) = &*recordings {
+            return Arc::clone(view);
+        }
+
+        let view = match ovit::recordings(&self.drive) {
+            Ok(infos) => RecordingsView::new(&infos),
+            Err(err) => {
+                warn!("Could not list recordings: {}", err);
+                RecordingsView::default()
+            }
+        };
+
+        let view = Arc::new(view);
+        *recordings = Some(Arc::clone(&view));
+
+        view
+    }
+
+    /// Size of the recording `fsid` as a program stream. Until it's been converted to the end
+    ///  that's taken to be the size of the chunks that were written, which it's close to.
+    fn get_video_size(&self, fsid: u32) -> u64 {
+        if let Some(size) = lock(&self.video_sizes).get(&fsid) {
+            return *size;
+        }
+
+        let size = match self.drive.recording_reader(fsid) {
+            Ok(reader) => reader.len(),
+            Err(err) => {
+                warn!("Could not open recording {}: {}", fsid, err);
+                return 0;
+            }
+        };
+        lock(&self.video_sizes).insert(fsid, size);
+
+        size
+    }
+
+    /// The captions of the recording `fsid` as SRT, decoded the first time they're opened.
+    fn get_captions(&self, fsid: u32) -> Result<Arc<Vec<u8>>, Error> {
+        if let Some(srt) = lock(&self.captions).get(&fsid) {
+            return Ok(Arc::clone(srt));
+        }
+
+        let reader = self.drive.recording_reader(fsid)?;
+        let srt = Arc::new(captions(&reader)?);
+        lock(&self.captions).insert(fsid, Arc::clone(&srt));
+
+        Ok(srt)
+    }
+
+    fn open_recording_file(&self, file: &RecordingFile) -> Result<(OpenFile, u32), Error> {
+        match file {
+            RecordingFile::Metadata(metadata) => Ok((OpenFile::Buffer(Arc::clone(metadata)), 0)),
+            // Their size isn't known until they've been decoded, so the kernel mustn't
+            //  trust what getattr said before
+            RecordingFile::Captions { fsid } => {
+                Ok((OpenFile::Buffer(self.get_captions(*fsid)?), FOPEN_DIRECT_IO))
+            }
+            RecordingFile::Video { fsid } => {
+                let reader = self.drive.recording_reader(*fsid)?;
+
                 Ok((
-                    TTL,
-                    FileAttr {
-                        size: 0,
-                        blocks: 0,
-                        atime: TTL,
-                        mtime: TTL,
-                        ctime: TTL,
-                        crtime: TTL,
-                        kind: FileType::RegularFile,
-                        perm: 777,
-                        nlink: 0,
-                        uid: 1000,
-                        gid: 1000,
-                        rdev: 0,
-                        flags: 0,
+                    OpenFile::Stream {
+                        fsid: *fsid,
+                        stream: Box::new(RecordingStream::new(reader)),
                     },
+                    FOPEN_DIRECT_IO,
                 ))
-                // Err(0)
-            }
-        }
-    }
-
-    fn opendir(&self, _req: RequestInfo, path: &Path, _flags: u32) -> ResultOpen {
-        info!("opendir path: {:#?}", path);
-
-        let fsid = get_fsid_from_path(path, self.drive_location.clone())?;
-
-        Ok((u64::from(fsid), 0))
-    }
-
-    fn readdir(&self, _req: RequestInfo, path: &Path, _fh: u64) -> ResultReaddir {
-        info!("readdir path: {:#?}", path);
-
-        let fsid = get_fsid_from_path(path, self.drive_location.clone())?;
-
-        let mut tivo_drive = get_tivo_drive(self.drive_location.clone())?;
-
-        match tivo_drive.get_inode_from_fsid(fsid) {
-            Ok(inode) => match inode.get_entries_from_directory(self.drive_location.clone()) {
-                Ok(entries) => Ok(entries
-                    // .iter()
-                    .par_iter()
-                    .filter(|entry| entry.name != "")
-                    // .filter(
-                    //     |entry| match &mut get_tivo_drive(self.drive_location.clone()) {
-                    //         Ok(tivo_drive) => match tivo_drive.get_inode_from_fsid(entry.fsid) {
-                    //             Ok(inode) => true,
-                    //             Err(_err) => false,
-                    //         },
-                    //         Err(_err) => false,
-                    //     },
-                    // )
-                    .map(|entry| -> DirectoryEntry {
-                        DirectoryEntry {
-                            kind: match inode.r#type {
-                                MFSINodeType::Dir => FileType::Directory,
-                                MFSINodeType::Node => FileType::RegularFile,
-                                MFSINodeType::Db => FileType::RegularFile,
-                                MFSINodeType::File => FileType::RegularFile,
-                                _ => FileType::RegularFile,
-                            },
-                            name: OsString::from(entry.name.clone()),
-                        }
-                    })
-                    .collect()),
-                Err(_err) => Err(0),
+            }
+        }
+    }
+
+    fn read_recording_file(&self, fh: u64, offset: u64, size: u32, reply: ReplyData) {
+        let file = match lock(&self.open_files).get(&fh) {
+            Some(file) => Arc::clone(file),
+            None => return reply.error(libc::EBADF),
+        };
+        let mut file = lock(&file);
+
+        match &mut *file {
+            OpenFile::Buffer(data) => {
+                let start = (offset as usize).min(data.len());
+                let end = start.saturating_add(size as usize).min(data.len());
+                reply.data(&data[start..end])
+            }
+            OpenFile::Stream { fsid, stream } => match stream.read(offset, size as usize) {
+                Ok(data) => reply.data(data),
+                Err(err) => {
+                    warn!("Could not convert recording {}: {}", fsid, err);
+                    reply.error(errno(&err))
+                }
+            },
+        }
+    }
+
+    fn get_inode(&self, fsid: u32) -> Result<Arc<MFSINode>, Error> {
+        if let Some(inode) = lock(&self.inodes).get(&fsid) {
+            return Ok(Arc::clone(inode));
+        }
+
+        let inode = Arc::new(self.drive.get_inode_from_fsid(fsid)?);
+        lock(&self.inodes).insert(fsid, Arc::clone(&inode));
+
+        Ok(inode)
+    }
+
+    fn get_attributes(&self, fsid: u32) -> Result<FileAttr, Error> {
+        if let Some(attributes) = lock(&self.attributes).get(&fsid) {
+            return Ok(*attributes);
+        }
+
+        let inode = self.get_inode(fsid)?;
+        let attributes =
+            inode_attributes(fsid_inode(self.root_fsid(), fsid), &inode, &self.options);
+        lock(&self.attributes).insert(fsid, attributes);
+
+        Ok(attributes)
+    }
+
+    /// Attributes of `node`, whose inode number is `ino`.
+    fn node_attributes(&self, ino: u64, node: &Node) -> Result<FileAttr, Error> {
+        let mut attributes = match node {
+            Node::Drive { fsid } => return self.get_attributes(*fsid),
+            Node::Object { fsid, .. } => self.get_attributes(*fsid)?,
+            Node::Recordings => read_only_attributes(ino, FileType::Directory, &self.options),
+            Node::RecordingFile(_) => {
+                read_only_attributes(ino, FileType::RegularFile, &self.options)
+            }
+        };
+        attributes.ino = ino;
+
+        match node {
+            // Objects are only rendered when they're read, they're opened for direct IO so the
+            //  kernel doesn't go by this size until then
+            Node::Object { path, .. } => {
+                attributes.size = lock(&self.objects)
+                    .get(path.as_str())
+                    .map_or(0, |json| json.len() as u64);
+                attributes.blocks = attributes.size.div_ceil(FUSE_BLOCK_SIZE);
+            }
+            Node::RecordingFile(RecordingFile::Video { fsid }) => {
+                attributes.size = self.get_video_size(*fsid);
+            }
+            Node::RecordingFile(RecordingFile::Metadata(metadata)) => {
+                attributes.size = metadata.len() as u64;
+            }
+            Node::RecordingFile(RecordingFile::Captions { fsid }) => {
+                if let Some(srt) = lock(&self.captions).get(fsid) {
+                    attributes.size = srt.len() as u64;
+                }
+            }
+            Node::Drive { .. } | Node::Recordings => {}
+        }
+
+        Ok(attributes)
+    }
+
+    /// Attributes of whatever `name` in the directory `parent` is.
+    fn lookup_entry(&self, parent: u64, name: &OsStr) -> Result<FileAttr, i32> {
+        let name = name.to_str().ok_or(libc::ENOENT)?;
+
+        let ino = match self.node(parent) {
+            Some(Node::Drive { .. }) if parent == FUSE_ROOT_ID && name == RECORDINGS_DIR => {
+                RECORDINGS_INODE
+            }
+            Some(Node::Drive { fsid }) => match self.drive.find_entry(fsid, name) {
+                Ok(Some(entry)) => self.entry_inode(parent, fsid, &entry),
+                Ok(None) => return Err(libc::ENOENT),
+                Err(err) => return Err(errno(&err)),
             },
-            Err(_err) => Err(0),
+            Some(Node::Recordings) => match self.get_recordings().find(name) {
+                Some(index) => RECORDINGS_INODE + 1 + index as u64,
+                None => return Err(libc::ENOENT),
+            },
+            Some(_) => return Err(libc::ENOTDIR),
+            None => return Err(libc::ENOENT),
+        };
+
+        let node = self.node(ino).ok_or(libc::ENOENT)?;
+
+        // The entry is in its directory, so a missing inode is damage rather than ENOENT
+        self.node_attributes(ino, &node).map_err(|err| {
+            warn!("lookup({}): Could not read inode {}: {}", name, ino, err);
+            libc::EIO
+        })
+    }
+
+    /// Everything in the directory `ino`, including `.` and `..`.
+    fn list_directory(&self, ino: u64) -> Result<Vec<DirectoryEntry>, i32> {
+        let entry = |inode: u64, kind: FileType, name: &str| DirectoryEntry {
+            inode,
+            kind,
+            name: OsString::from(name),
+        };
+
+        match self.node(ino) {
+            Some(Node::Drive { fsid }) => {
+                let drive_entries = self.drive.directory_entries(fsid).map_err(|err| {
+                    warn!("Could not read directory {}: {}", fsid, err);
+                    errno(&err)
+                })?;
+
+                let parent = lock(&self.directories)
+                    .get(&fsid)
+                    .map_or(FUSE_ROOT_ID, |directory| directory.parent);
+                let mut entries = vec![
+                    entry(ino, FileType::Directory, "."),
+                    entry(parent, FileType::Directory, ".."),
+                ];
+
+                for drive_entry in drive_entries.iter().filter(|entry| !entry.name.is_empty()) {
+                    entries.push(entry(
+                        self.entry_inode(ino, fsid, drive_entry),
+                        file_type(&drive_entry.r#type),
+                        &drive_entry.name,
+                    ));
+                }
+
+                if ino == FUSE_ROOT_ID {
+                    entries.push(entry(RECORDINGS_INODE, FileType::Directory, RECORDINGS_DIR));
+                }
+
+                Ok(entries)
+            }
+            Some(Node::Recordings) => {
+                let mut entries = vec![
+                    entry(ino, FileType::Directory, "."),
+                    entry(FUSE_ROOT_ID, FileType::Directory, ".."),
+                ];
+
+                for (index, (name, _)) in self.get_recordings().files.iter().enumerate() {
+                    entries.push(entry(
+                        RECORDINGS_INODE + 1 + index as u64,
+                        FileType::RegularFile,
+                        name,
+                    ));
+                }
+
+                Ok(entries)
+            }
+            Some(_) => Err(libc::ENOTDIR),
+            None => Err(libc::ENOENT),
+        }
+    }
+
+    fn lookup(&self, parent: u64, name: &OsStr, reply: ReplyEntry) {
+        debug!("lookup: {} {:?}", parent, name);
+
+        match self.lookup_entry(parent, name) {
+            Ok(attributes) => reply.entry(&TTL, &attributes, 0),
+            Err(err) => reply.error(err),
+        }
+    }
+
+    fn getattr(&self, ino: u64, reply: ReplyAttr) {
+        debug!("getattr: {}", ino);
+
+        let node = match self.node(ino) {
+            Some(node) => node,
+            None => return reply.error(libc::ENOENT),
+        };
+
+        match self.node_attributes(ino, &node) {
+            Ok(attributes) => reply.attr(&TTL, &attributes),
+            Err(err) => {
+                warn!("getattr({}): Could not read inode: {}", ino, err);
+                reply.error(libc::EIO)
+            }
+        }
+    }
+
+    fn opendir(&self, ino: u64, reply: ReplyOpen) {
+        info!("opendir: {}", ino);
+
+        match self.list_directory(ino) {
+            Ok(entries) => {
+                let fh = self.next_handle.fetch_add(1, Ordering::Relaxed);
+                lock(&self.open_directories).insert(fh, Arc::new(entries));
+                reply.opened(fh, 0)
+            }
+            Err(err) => reply.error(err),
+        }
+    }
+
+    fn readdir(&self, ino: u64, fh: u64, offset: i64, mut reply: ReplyDirectory) {
+        info!("readdir: {} offset: {}", ino, offset);
+
+        let entries = match lock(&self.open_directories).get(&fh) {
+            Some(entries) => Arc::clone(entries),
+            None => return reply.error(libc::EBADF),
+        };
+
+        // Each entry's offset is where the next read carries on from
+        let skip = usize::try_from(offset).unwrap_or(0);
+        for (index, entry) in entries.iter().enumerate().skip(skip) {
+            if reply.add(entry.inode, index as i64 + 1, entry.kind, &entry.name) {
+                break;
+            }
+        }
+
+        reply.ok()
+    }
+
+    fn open(&self, ino: u64, reply: ReplyOpen) {
+        info!("open: {}", ino);
+
+        match self.node(ino) {
+            Some(Node::RecordingFile(file)) => {
+                let (open_file, flags) = match self.open_recording_file(&file) {
+                    Ok(opened) => opened,
+                    Err(err) => {
+                        warn!("open({}): {}", ino, err);
+                        return reply.error(errno(&err));
+                    }
+                };
+
+                let fh = self.next_handle.fetch_add(1, Ordering::Relaxed);
+                lock(&self.open_files).insert(fh, Arc::new(Mutex::new(open_file)));
+
+                reply.opened(fh, flags)
+            }
+            // Objects' JSON isn't rendered until it's read, so its size isn't known up front
+            Some(Node::Object { .. }) => reply.opened(0, FOPEN_DIRECT_IO),
+            Some(Node::Drive { .. }) => reply.opened(0, 0),
+            Some(Node::Recordings) => reply.error(libc::EISDIR),
+            None => reply.error(libc::ENOENT),
+        }
+    }
+
+    fn read(&self, ino: u64, fh: u64, offset: u64, size: u32, reply: ReplyData) {
+        info!("read: {} offset: {} size: {}", ino, offset, size);
+
+        let (fsid, path) = match self.node(ino) {
+            Some(Node::Drive { fsid }) => (fsid, None),
+            Some(Node::Object { fsid, path }) => (fsid, Some(path)),
+            Some(Node::RecordingFile(_)) => {
+                return self.read_recording_file(fh, offset, size, reply);
+            }
+            Some(Node::Recordings) => return reply.error(libc::EISDIR),
+            None => return reply.error(libc::ENOENT),
+        };
+
+        let inode = match self.get_inode(fsid) {
+            Ok(inode) => inode,
+            Err(err) => return reply.error(errno(&err)),
+        };
+
+        let json = path.and_then(|path| self.get_object_json(&path, &inode));
+        if let Some(json) = json {
+            let start = (offset as usize).min(json.len());
+            let end = start.saturating_add(size as usize).min(json.len());
+            return reply.data(&json[start..end]);
+        }
+
+        let mut reader = self.drive.file_reader(&inode);
+        let mut data = Vec::with_capacity(size as usize);
+
+        let read = reader
+            .seek(SeekFrom::Start(offset))
+            .and_then(|_| reader.take(u64::from(size)).read_to_end(&mut data));
+
+        match read {
+            Ok(_) => reply.data(&data),
+            Err(err) => {
+                warn!("read({}): {}", ino, err);
+                reply.error(err.raw_os_error().unwrap_or(libc::EIO))
+            }
+        }
+    }
+
+    fn release(&self, fh: u64, reply: ReplyEmpty) {
+        debug!("release: {}", fh);
+
+        let file = lock(&self.open_files).remove(&fh);
+
+        // Streams that were read to the end know their exact size from now on
+        if let Some(file) = file {
+            if let OpenFile::Stream { fsid, stream } = &*lock(&file) {
+                if let Some(length) = stream.length() {
+                    lock(&self.video_sizes).insert(*fsid, length);
+                }
+            }
         }
+
+        reply.ok()
+    }
+
+    fn releasedir(&self, fh: u64, reply: ReplyEmpty) {
+        lock(&self.open_directories).remove(&fh);
+
+        reply.ok()
+    }
+}
+
+/// Hands each request to a pool of threads, fuse reads every request on one thread so a slow
+///  read would otherwise hold up the rest.
+struct Workers {
+    fs: Arc<TiVoFS>,
+    pool: ThreadPool,
+}
+
+impl Workers {
+    fn spawn(&self, request: impl FnOnce(&TiVoFS) + Send + 'static) {
+        let fs = Arc::clone(&self.fs);
+        self.pool.spawn(move || request(&fs));
+    }
+}
+
+impl Filesystem for Workers {
+    fn lookup(&mut self, _req: &Request, parent: u64, name: &OsStr, reply: ReplyEntry) {
+        let name = name.to_os_string();
+        self.spawn(move |fs| fs.lookup(parent, &name, reply));
     }
 
-    fn open(&self, _req: RequestInfo, path: &Path, _flags: u32) -> ResultOpen {
-        info!("open path: {:#?}", path);
+    fn getattr(&mut self, _req: &Request, ino: u64, reply: ReplyAttr) {
+        self.spawn(move |fs| fs.getattr(ino, reply));
+    }
 
-        let fsid = get_fsid_from_path(path, self.drive_location.clone())?;
+    fn opendir(&mut self, _req: &Request, ino: u64, _flags: u32, reply: ReplyOpen) {
+        self.spawn(move |fs| fs.opendir(ino, reply));
+    }
 
-        Ok((u64::from(fsid), 0))
+    fn readdir(&mut self, _req: &Request, ino: u64, fh: u64, offset: i64, reply: ReplyDirectory) {
+        self.spawn(move |fs| fs.readdir(ino, fh, offset, reply));
+    }
+
+    fn releasedir(&mut self, _req: &Request, _ino: u64, fh: u64, _flags: u32, reply: ReplyEmpty) {
+        self.spawn(move |fs| fs.releasedir(fh, reply));
+    }
+
+    fn open(&mut self, _req: &Request, ino: u64, _flags: u32, reply: ReplyOpen) {
+        self.spawn(move |fs| fs.open(ino, reply));
     }
 
     fn read(
-        &self,
-        _req: RequestInfo,
-        path: &Path,
-        _fh: u64,
-        _offset: u64,
-        _size: u32,
-        result: impl FnOnce(Result<&[u8], i32>),
+        &mut self,
+        _req: &Request,
+        ino: u64,
+        fh: u64,
+        offset: i64,
+        size: u32,
+        reply: ReplyData,
     ) {
-        info!("read path: {:#?}", path);
-
-        match get_fsid_from_path(path, self.drive_location.clone()) {
-            Ok(fsid) => match &mut get_tivo_drive(self.drive_location.clone()) {
-                Ok(tivo_drive) => match tivo_drive.get_inode_from_fsid(fsid) {
-                    Ok(inode) => {
-                        if inode.r#type == MFSINodeType::Db {
-                            info!("read({:#?}): I'm a database item!", path);
-                        }
-                        match inode.get_data(self.drive_location.clone()) {
-                            Ok(data) => result(Ok(&data)),
-                            Err(_err) => result(Err(0)),
-                        }
-                    }
-                    Err(_err) => result(Err(0)),
-                },
-                Err(_err) => result(Err(0)),
-            },
-            Err(_err) => result(Err(0)),
+        let offset = match u64::try_from(offset) {
+            Ok(offset) => offset,
+            Err(_) => return reply.error(libc::EINVAL),
         };
+
+        self.spawn(move |fs| fs.read(ino, fh, offset, size, reply));
+    }
+
+    fn release(
+        &mut self,
+        _req: &Request,
+        _ino: u64,
+        fh: u64,
+        _flags: u32,
+        _lock_owner: u64,
+        _flush: bool,
+        reply: ReplyEmpty,
+    ) {
+        self.spawn(move |fs| fs.release(fh, reply));
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_fsid_inodes() {
+        // The root directory and FSID 1 swap places
+        assert_eq!(fsid_inode(12, 12), FUSE_ROOT_ID);
+        assert_eq!(fsid_inode(12, 1), 12);
+        assert_eq!(fsid_inode(12, 500), 500);
+        assert_eq!(fsid_inode(1, 1), FUSE_ROOT_ID);
+
+        for fsid in [1, 12, 500, u32::MAX] {
+            assert_eq!(inode_fsid(12, fsid_inode(12, fsid)), Some(fsid));
+            assert_eq!(inode_fsid(1, fsid_inode(1, fsid)), Some(fsid));
+        }
+        assert_eq!(inode_fsid(12, RECORDINGS_INODE), None);
     }
 }
@@ -0,0 +1,227 @@
+use log::warn;
+use ovit::TivoDrive;
+use serde_json::{json, Map, Value};
+use std::collections::{HashMap, VecDeque};
+use std::convert::TryFrom;
+use tivo_media_file_system::{
+    MFSDbObject, MFSDbValue, MFSINodeType, MFSSchema, MFSSchemaCardinality,
+};
+
+/// Where every file on the drive lives, by FSID, for pointing references at. Objects turn up
+///  in several index directories, references point at the shallowest.
+#[derive(Debug, Default)]
+pub struct ObjectPaths {
+    paths: HashMap<u32, String>,
+}
+
+impl ObjectPaths {
+    /// Walk the whole tree breadth first from the root directory.
+    pub fn new(drive: &TivoDrive) -> ObjectPaths {
+        let mut paths = HashMap::new();
+        let mut directories = VecDeque::new();
+
+        let root = drive.volume_header.root_fsid;
+        paths.insert(root, String::from("/"));
+        directories.push_back((root, String::new()));
+
+        while let Some((fsid, path)) = directories.pop_front() {
+            let entries = match drive.directory_entries(fsid) {
+                Ok(entries) => entries,
+                Err(err) => {
+                    warn!("Could not read directory {}: {}", path, err);
+                    continue;
+                }
+            };
+
+            for entry in entries {
+                if entry.name.is_empty() || paths.contains_key(&entry.fsid) {
+                    continue;
+                }
+
+                let entry_path = format!("{}/{}", path, entry.name);
+                paths.insert(entry.fsid, entry_path.clone());
+
+                if entry.r#type == MFSINodeType::Dir {
+                    directories.push_back((entry.fsid, entry_path));
+                }
+            }
+        }
+
+        ObjectPaths { paths }
+    }
+
+    pub fn path(&self, fsid: u32) -> Option<&str> {
+        self.paths.get(&fsid).map(|path| path.as_str())
+    }
+
+    /// The path to `fsid` relative to the directory holding the file at `from`.
+    pub fn relative_path(&self, from: &str, fsid: u32) -> Option<String> {
+        let to = self.path(fsid)?;
+
+        let from: Vec<&str> = from.split('/').filter(|name| !name.is_empty()).collect();
+        let to: Vec<&str> = to.split('/').filter(|name| !name.is_empty()).collect();
+        let from_directory = &from[..from.len().saturating_sub(1)];
+
+        let common = from_directory
+            .iter()
+            .zip(to.iter())
+            .take_while(|(from, to)| from == to)
+            .count();
+
+        let mut components = vec![".."; from_directory.len() - common];
+        components.extend(&to[common..]);
+
+        if components.is_empty() {
+            return Some(String::from("."));
+        }
+
+        Some(components.join("/"))
+    }
+}
+
+/// Inode numbers for objects shown as JSON, one for each path an object was found through.
+///  An object's JSON depends on where it's read from, so unlike other files its FSID can't
+///  be its inode number.
+#[derive(Debug, Default)]
+pub struct ObjectInodes {
+    /// Path and FSID of each object, the index is the inode number less the first one.
+    objects: Vec<(String, u32)>,
+    by_path: HashMap<String, u64>,
+}
+
+impl ObjectInodes {
+    /// The inode number for the object `fsid` at `path`, starting from `first_inode`.
+    pub fn inode(&mut self, first_inode: u64, path: &str, fsid: u32) -> u64 {
+        if let Some(inode) = self.by_path.get(path) {
+            return *inode;
+        }
+
+        let inode = first_inode + self.objects.len() as u64;
+        self.objects.push((path.to_string(), fsid));
+        self.by_path.insert(path.to_string(), inode);
+
+        inode
+    }
+
+    /// Path and FSID of the object `index` inodes from the first.
+    pub fn get(&self, index: u64) -> Option<(&str, u32)> {
+        self.objects
+            .get(usize::try_from(index).ok()?)
+            .map(|(path, fsid)| (path.as_str(), *fsid))
+    }
+}
+
+/// Render a decoded tyDb object as JSON, as read from `path`. Objects turn up in several
+///  directories, so references to other objects and files become paths relative to the one
+///  it was read through, or `fsid:N` when they aren't anywhere in the tree.
+pub fn object_json(object: &MFSDbObject, path: &str, paths: &ObjectPaths) -> Value {
+    let reference = |fsid: &u32| match paths.relative_path(path, *fsid) {
+        Some(relative_path) => Value::from(relative_path),
+        None => Value::from(format!("fsid:{}", fsid)),
+    };
+
+    let subobjects: Vec<Value> = object
+        .subobjects
+        .iter()
+        .map(|subobject| {
+            let schema_type = MFSSchema::bundled().get_type(subobject.r#type);
+
+            let mut attributes = Map::new();
+            for attribute in subobject.attributes.iter() {
+                let name = match &attribute.name {
+                    Some(name) => name.clone(),
+                    None => format!("attribute_{}", attribute.id),
+                };
+
+                let values: Vec<Value> = match &attribute.value {
+                    MFSDbValue::Int(values) | MFSDbValue::Subobject(values) => {
+                        values.iter().map(|value| Value::from(*value)).collect()
+                    }
+                    MFSDbValue::String(values) => values
+                        .iter()
+                        .map(|value| Value::from(value.as_str()))
+                        .collect(),
+                    MFSDbValue::Object(fsids) | MFSDbValue::File(fsids) => {
+                        fsids.iter().map(reference).collect()
+                    }
+                };
+
+                // Single valued attributes aren't wrapped in an array, so `jq .Title` just works
+                let multi = schema_type
+                    .and_then(|schema_type| schema_type.attribute(attribute.id))
+                    .is_none_or(|schema_attribute| {
+                        schema_attribute.cardinality == MFSSchemaCardinality::Multi
+                    });
+                let value = if !multi && values.len() == 1 {
+                    values.into_iter().next().unwrap()
+                } else {
+                    Value::from(values)
+                };
+
+                attributes.insert(name, value);
+            }
+
+            let type_name = match &subobject.type_name {
+                Some(type_name) => type_name.clone(),
+                None => subobject.r#type.to_string(),
+            };
+
+            json!({
+                "id": subobject.id,
+                "type": type_name,
+                "flags": subobject.flags,
+                "attributes": attributes,
+            })
+        })
+        .collect();
+
+    json!({
+        "fsid": object.fsid,
+        "path": path,
+        "type": object.primary().and_then(|primary| primary.type_name.clone()),
+        "subobjects": subobjects,
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_relative_path() {
+        let paths = ObjectPaths {
+            paths: [
+                (1, "/"),
+                (2, "/Program/12/345"),
+                (3, "/Program/12/678"),
+                (4, "/Series/9/10"),
+                (5, "/Program"),
+            ]
+            .iter()
+            .map(|(fsid, path)| (*fsid, String::from(*path)))
+            .collect(),
+        };
+
+        assert_eq!(
+            paths.relative_path("/Program/12/345", 3),
+            Some(String::from("678"))
+        );
+        assert_eq!(
+            paths.relative_path("/Program/12/345", 4),
+            Some(String::from("../../Series/9/10"))
+        );
+        assert_eq!(
+            paths.relative_path("/Program/12/345", 5),
+            Some(String::from(".."))
+        );
+        assert_eq!(
+            paths.relative_path("/Program/12/345", 1),
+            Some(String::from("../.."))
+        );
+        assert_eq!(
+            paths.relative_path("/Series/9/10", 4),
+            Some(String::from("10"))
+        );
+        assert_eq!(paths.relative_path("/Program/12/345", 6), None);
+    }
+}
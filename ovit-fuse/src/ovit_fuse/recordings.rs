@@ -0,0 +1,367 @@
+use log::warn;
+use ovit::{ClosedCaptionDecoder, ProgramStreamMuxer, RecordingInfo, RecordingReader, Result};
+use std::collections::BTreeMap;
+use std::sync::Arc;
+
+/// A file in the `/Recordings` directory, each recording has one of each.
+#[derive(Debug, Clone)]
+pub enum RecordingFile {
+    /// The recording as an MPEG-2 program stream, converted as it's read.
+    Video { fsid: u32 },
+    /// The recording's catalogue entry as JSON.
+    Metadata(Arc<Vec<u8>>),
+    /// The recording's closed captions as SRT, only decoded when opened.
+    Captions { fsid: u32 },
+}
+
+/// Names for every recording on the drive, like `Title - Episode (2004-05-01 KQED).mpg`.
+#[derive(Debug, Default)]
+pub struct RecordingsView {
+    /// Sorted by name, a file's index never changes so it can be part of its inode number.
+    pub files: Vec<(String, RecordingFile)>,
+}
+
+/// The name of a recording's files without an extension.
+fn base_name(info: &RecordingInfo) -> String {
+    let mut name = match &info.title {
+        Some(title) => title.clone(),
+        None => format!("Recording {}", info.fsid),
+    };
+
+    if let Some(episode_title) = &info.episode_title {
+        name.push_str(" - ");
+        name.push_str(episode_title);
+    }
+
+    let date = info
+        .air_date
+        .as_deref()
+        .and_then(|date| date.split(' ').next());
+    let details: Vec<&str> = date.into_iter().chain(info.channel.as_deref()).collect();
+    if !details.is_empty() {
+        name.push_str(&format!(" ({})", details.join(" ")));
+    }
+
+    name.replace(['/', '\0'], "_")
+}
+
+impl RecordingsView {
+    pub fn new(recordings: &[RecordingInfo]) -> RecordingsView {
+        let mut files = BTreeMap::new();
+
+        for info in recordings {
+            let mut name = base_name(info);
+            // Reruns share a title, episode, date and channel surprisingly often
+            if files.contains_key(&format!("{}.mpg", name)) {
+                name = format!("{} [{}]", name, info.fsid);
+            }
+
+            let metadata = serde_json::to_vec_pretty(info).unwrap_or_default();
+
+            files.insert(
+                format!("{}.mpg", name),
+                RecordingFile::Video { fsid: info.fsid },
+            );
+            files.insert(
+                format!("{}.json", name),
+                RecordingFile::Metadata(Arc::new(metadata)),
+            );
+            files.insert(
+                format!("{}.srt", name),
+                RecordingFile::Captions { fsid: info.fsid },
+            );
+        }
+
+        RecordingsView {
+            files: files.into_iter().collect(),
+        }
+    }
+
+    /// Index of the file called `name`.
+    pub fn find(&self, name: &str) -> Option<usize> {
+        self.files
+            .binary_search_by(|(file_name, _)| file_name.as_str().cmp(name))
+            .ok()
+    }
+}
+
+/// Decode every caption in a recording into an SRT file, skipping chunks that can't be read.
+pub fn captions(reader: &RecordingReader) -> Result<Vec<u8>> {
+    let mut decoder = ClosedCaptionDecoder::new();
+
+    let skipped = reader.for_each_record(|record| {
+        decoder.push(record);
+        Ok(())
+    })?;
+    if skipped > 0 {
+        warn!("Skipped {} unreadable chunks decoding captions", skipped);
+    }
+
+    let mut srt = vec![];
+    decoder.finish().write_srt(&mut srt)?;
+
+    Ok(srt)
+}
+
+/// How many chunks apart to save the conversion's place, chunks are 128 KiB.
+const CHECKPOINT_CHUNKS: u64 = 16;
+
+/// The conversion's place before a chunk, to pick it up from there again.
+#[derive(Debug, Clone)]
+struct Checkpoint {
+    chunk: u64,
+    /// Where the chunk's output starts in the program stream.
+    offset: u64,
+    muxer: ProgramStreamMuxer<Vec<u8>>,
+}
+
+/// A recording converted to an MPEG-2 program stream as it's read. Reads pick the
+///  conversion up from the closest checkpoint before them, reading past the last one
+///  converts everything in between.
+#[derive(Debug)]
+pub struct RecordingStream {
+    reader: RecordingReader,
+    /// `None` once the whole recording has been converted.
+    muxer: Option<ProgramStreamMuxer<Vec<u8>>>,
+    next_chunk: u64,
+    /// In order of chunk, the first is the start of the recording.
+    checkpoints: Vec<Checkpoint>,
+    /// Length of the program stream, once it's been converted to the end.
+    length: Option<u64>,
+
+    /// Converted output that hasn't been read past yet.
+    buffer: Vec<u8>,
+    /// Where `buffer` starts in the program stream.
+    buffer_start: u64,
+}
+
+impl RecordingStream {
+    pub fn new(reader: RecordingReader) -> RecordingStream {
+        let start = Checkpoint {
+            chunk: 0,
+            offset: 0,
+            muxer: ProgramStreamMuxer::new(vec![]),
+        };
+
+        RecordingStream {
+            reader,
+            muxer: Some(start.muxer.clone()),
+            next_chunk: 0,
+            checkpoints: vec![start],
+            length: None,
+
+            buffer: vec![],
+            buffer_start: 0,
+        }
+    }
+
+    pub fn length(&self) -> Option<u64> {
+        self.length
+    }
+
+    fn resume(&mut self, checkpoint: Checkpoint) {
+        self.muxer = Some(checkpoint.muxer);
+        self.next_chunk = checkpoint.chunk;
+        self.buffer = vec![];
+        self.buffer_start = checkpoint.offset;
+    }
+
+    /// Convert the next chunk onto the end of the buffer.
+    fn convert_chunk(&mut self) -> Result<()> {
+        let mut muxer = match self.muxer.take() {
+            Some(muxer) => muxer,
+            None => return Ok(()),
+        };
+
+        if self.next_chunk >= self.reader.chunk_count() {
+            self.buffer.extend(muxer.finish()?);
+            self.length = Some(self.buffer_start + self.buffer.len() as u64);
+            return Ok(());
+        }
+
+        let offset = self.buffer_start + self.buffer.len() as u64;
+        let last_checkpoint = self
+            .checkpoints
+            .last()
+            .map_or(0, |checkpoint| checkpoint.chunk);
+        if self.next_chunk.is_multiple_of(CHECKPOINT_CHUNKS) && self.next_chunk > last_checkpoint {
+            // The muxer's output has all been moved to the buffer, so its writer is empty
+            self.checkpoints.push(Checkpoint {
+                chunk: self.next_chunk,
+                offset,
+                muxer: muxer.clone(),
+            });
+        }
+
+        match self.reader.chunk(self.next_chunk) {
+            Ok(Some(chunk)) => {
+                for record in chunk.records.iter() {
+                    muxer.write_record(record)?;
+                }
+            }
+            Ok(None) => {}
+            Err(err) => warn!("Skipping chunk {}: {}", self.next_chunk, err),
+        }
+        self.next_chunk += 1;
+
+        self.buffer.append(muxer.get_mut());
+        self.muxer = Some(muxer);
+
+        Ok(())
+    }
+
+    /// Up to `size` bytes of the program stream starting `offset` bytes in.
+    pub fn read(&mut self, offset: u64, size: usize) -> Result<&[u8]> {
+        let converted = self.buffer_start + self.buffer.len() as u64;
+        let closest = self
+            .checkpoints
+            .partition_point(|checkpoint| checkpoint.offset <= offset);
+
+        // The first checkpoint is at offset 0, so there's always one at or before the read
+        let checkpoint = &self.checkpoints[closest.max(1) - 1];
+        if offset < self.buffer_start || checkpoint.offset > converted {
+            self.resume(checkpoint.clone());
+        }
+
+        loop {
+            // Drop whatever comes before the read so seeking ahead doesn't fill up memory
+            let skip = (offset - self.buffer_start).min(self.buffer.len() as u64);
+            self.buffer.drain(..skip as usize);
+            self.buffer_start += skip;
+
+            let buffered = self.buffer_start + self.buffer.len() as u64;
+            if buffered >= offset + size as u64 || self.muxer.is_none() {
+                break;
+            }
+
+            self.convert_chunk()?;
+        }
+
+        let length = size.min(self.buffer.len());
+        Ok(&self.buffer[..length])
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use ovit_util::{MemorySource, SECTOR_SIZE};
+    use tivo_media_file_system::{
+        MFSINode, MFSVolume, MFSVolumes, TyStream, INODE_SIGNATURE, TY_CHUNK_SIZE,
+    };
+
+    fn info(fsid: u32, title: Option<&str>, episode_title: Option<&str>) -> RecordingInfo {
+        RecordingInfo {
+            fsid,
+            state: None,
+            title: title.map(String::from),
+            episode_title: episode_title.map(String::from),
+            series: None,
+            channel: Some(String::from("KQED")),
+            air_date: Some(String::from("2004-05-01 20:00:00")),
+            duration: None,
+            size: 0,
+            stream_fsids: vec![],
+        }
+    }
+
+    #[test]
+    fn test_base_name() {
+        assert_eq!(
+            base_name(&info(1, Some("Cosmos"), Some("Heaven/Hell"))),
+            "Cosmos - Heaven_Hell (2004-05-01 KQED)"
+        );
+
+        let mut untitled = info(2, None, None);
+        untitled.air_date = None;
+        untitled.channel = None;
+        assert_eq!(base_name(&untitled), "Recording 2");
+    }
+
+    #[test]
+    fn test_duplicate_names() {
+        let view =
+            RecordingsView::new(&[info(1, Some("Cosmos"), None), info(2, Some("Cosmos"), None)]);
+
+        let names: Vec<&str> = view.files.iter().map(|(name, _)| name.as_str()).collect();
+        assert_eq!(
+            names,
+            [
+                "Cosmos (2004-05-01 KQED) [2].json",
+                "Cosmos (2004-05-01 KQED) [2].mpg",
+                "Cosmos (2004-05-01 KQED) [2].srt",
+                "Cosmos (2004-05-01 KQED).json",
+                "Cosmos (2004-05-01 KQED).mpg",
+                "Cosmos (2004-05-01 KQED).srt",
+            ]
+        );
+        assert_eq!(view.find("Cosmos (2004-05-01 KQED).mpg"), Some(4));
+        assert_eq!(view.find("Cosmos.mpg"), None);
+    }
+
+    /// A recording of `chunk_count` chunks, each holding one video record.
+    fn recording(chunk_count: u32) -> RecordingReader {
+        let mut disk = vec![];
+        for index in 0..chunk_count {
+            // One record of 3000 bytes
+            let mut chunk = vec![1, 0, 0, 0, 0x00, 0xBB, 0x80, 0xE0];
+            chunk.resize(4 + 16, 0);
+            chunk.resize(4 + 16 + 3000, index as u8 + 1);
+            chunk.resize(TY_CHUNK_SIZE, 0);
+            disk.extend(chunk);
+        }
+
+        let chunk_sectors = (TY_CHUNK_SIZE / SECTOR_SIZE) as u32;
+        let mut sector = vec![0u8; SECTOR_SIZE];
+        sector[0x1C..0x20].copy_from_slice(&(TY_CHUNK_SIZE as u32).to_be_bytes());
+        sector[0x20..0x24].copy_from_slice(&chunk_count.to_be_bytes());
+        sector[0x28] = 2;
+        sector[0x2C..0x30].copy_from_slice(&INODE_SIGNATURE.to_be_bytes());
+        sector[0x38..0x3C].copy_from_slice(&1u32.to_be_bytes());
+        sector[0x40..0x44].copy_from_slice(&(chunk_count * chunk_sectors).to_be_bytes());
+        let (_, inode) = MFSINode::parse(&sector, 0, 0, false).unwrap();
+
+        let volumes = MFSVolumes::from_volumes(vec![MFSVolume {
+            disk_sector: 0,
+            sector_start: 0,
+            sector_count: chunk_count * chunk_sectors,
+        }]);
+        let stream = TyStream::new(Arc::new(MemorySource::new(disk)), &volumes, &inode).unwrap();
+
+        RecordingReader::new(vec![stream])
+    }
+
+    #[test]
+    fn test_stream_reads() {
+        let reader = recording(40);
+        let mut muxer = ProgramStreamMuxer::new(vec![]);
+        reader
+            .for_each_record(|record| muxer.write_record(record))
+            .unwrap();
+        let expected = muxer.finish().unwrap();
+
+        let mut stream = RecordingStream::new(reader);
+        let mut read = vec![];
+        while read.len() < expected.len() {
+            let data = stream.read(read.len() as u64, 5000).unwrap().to_vec();
+            assert!(!data.is_empty());
+            read.extend(data);
+        }
+        assert_eq!(read, expected);
+        assert_eq!(stream.length(), Some(expected.len() as u64));
+        assert!(stream.read(expected.len() as u64, 5000).unwrap().is_empty());
+
+        let chunks: Vec<u64> = stream
+            .checkpoints
+            .iter()
+            .map(|checkpoint| checkpoint.chunk)
+            .collect();
+        assert_eq!(chunks, [0, 16, 32]);
+
+        // Reading back picks up from a checkpoint, then reading ahead again skips to the next
+        for offset in [expected.len() / 2, 100, expected.len() - 10_000] {
+            let data = stream.read(offset as u64, 5000).unwrap();
+            assert_eq!(data, &expected[offset..offset + 5000]);
+        }
+    }
+}
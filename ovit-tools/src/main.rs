@@ -5,8 +5,11 @@ extern crate ovit;
 extern crate tivo_media_file_system;
 
 use clap::{App, Arg, SubCommand};
+use ovit::{CacheOptions, RecordingFormat};
 use prettytable::Table;
-use tivo_media_file_system::{MFSINode, MFSINodeType};
+use std::fs::File;
+use std::io::BufWriter;
+use tivo_media_file_system::{MFSChecksummed, MFSINode, MFSINodeType, MFSSchema};
 
 fn main() {
     let matches = App::new("oViT")
@@ -40,6 +43,17 @@ fn main() {
             .arg(Arg::with_name("data")
                 .short("d")
                 .long("display-data")
+                .required(false))
+            .arg(Arg::with_name("cache-pages")
+                .long("cache-pages")
+                .value_name("NUMBER")
+                .help("Sets how many 32 KiB pages of sectors to cache")
+                .takes_value(true)
+                .required(false)
+                .default_value("1024"))
+            .arg(Arg::with_name("mmap")
+                .long("mmap")
+                .help("Memory maps the drive image instead of caching sectors")
                 .required(false)))
         .subcommand(SubCommand::with_name("fsid")
             .arg(Arg::with_name("INPUT")
@@ -57,6 +71,68 @@ fn main() {
                 .value_name("NUMBER")
                 .help("Sets the INode to lookup")
                 .required(true)))
+        .subcommand(SubCommand::with_name("ls")
+            .arg(Arg::with_name("INPUT")
+                .help("The drive image to read from")
+                .required(true))
+            .arg(Arg::with_name("PATH")
+                .help("The directory to list, such as /Recording/Active")
+                .required(false)
+                .default_value("/")))
+        .subcommand(SubCommand::with_name("extract-recording")
+            .arg(Arg::with_name("INPUT")
+                .help("The drive image to read from")
+                .required(true))
+            .arg(Arg::with_name("FSID")
+                .help("The Recording object or Stream to extract")
+                .required(true))
+            .arg(Arg::with_name("output")
+                .short("o")
+                .long("output")
+                .value_name("FILE")
+                .help("Sets the MPEG-2 file to write")
+                .takes_value(true)
+                .required(true))
+            .arg(Arg::with_name("format")
+                .short("f")
+                .long("format")
+                .value_name("FORMAT")
+                .help("Sets the container to write, by default .ts files get a transport stream")
+                .possible_values(&["ps", "ts"])
+                .takes_value(true)
+                .required(false)))
+        .subcommand(SubCommand::with_name("recordings")
+            .arg(Arg::with_name("INPUT")
+                .help("The drive image to read from")
+                .required(true))
+            .arg(Arg::with_name("format")
+                .short("f")
+                .long("format")
+                .value_name("FORMAT")
+                .help("Sets how the recordings are printed")
+                .possible_values(&["table", "json", "csv"])
+                .takes_value(true)
+                .required(false)
+                .default_value("table")))
+        .subcommand(SubCommand::with_name("extract-captions")
+            .arg(Arg::with_name("INPUT")
+                .help("The drive image to read from")
+                .required(true))
+            .arg(Arg::with_name("FSID")
+                .help("The Recording object or Stream to extract captions from")
+                .required(true))
+            .arg(Arg::with_name("srt")
+                .long("srt")
+                .value_name("FILE")
+                .help("Sets the SRT file to write")
+                .takes_value(true)
+                .required(false))
+            .arg(Arg::with_name("scc")
+                .long("scc")
+                .value_name("FILE")
+                .help("Sets the SCC file to write")
+                .takes_value(true)
+                .required(false)))
         .get_matches();
 
     match matches.subcommand() {
@@ -144,12 +220,14 @@ fn main() {
                 "Logstamp",
                 "Type",
                 "Checksum",
+                "Checksum Valid",
                 "First Sector",
                 "Last Sector",
                 "Size",
                 "Min. Allocations",
                 "Free Space",
-                "Bitmap Number"
+                "Bitmap Number",
+                "Utilisation"
             ]);
             for zone in tivo_drive.zonemap {
                 table.add_row(row![
@@ -164,12 +242,14 @@ fn main() {
                     zone.logstamp,
                     format!("{:#?}", zone.r#type),
                     zone.checksum,
+                    zone.is_checksum_valid(),
                     zone.first_sector,
                     zone.last_sector,
                     zone.size,
                     zone.min_allocations,
                     zone.free_space,
-                    zone.bitmap_num
+                    zone.bitmap_num,
+                    format!("{:.1}%", zone.utilisation() * 100.0)
                 ]);
             }
 
@@ -187,25 +267,50 @@ fn main() {
             // Create the table
             let mut table = Table::new();
 
-            let header = tivo_drive.volume_header;
+            let header = &tivo_drive.volume_header;
 
             table.add_row(row!["Variable", "Value"]);
             table.add_row(row!["State", header.state]);
+            table.add_row(row!["64-bit", header.is_64_bit]);
             table.add_row(row!["Checksum", header.checksum]);
+            table.add_row(row!["Checksum Valid", header.is_checksum_valid()]);
+            table.add_row(row![
+                "Backup Checksum Valid",
+                match &tivo_drive.backup_volume_header {
+                    Some(backup) => backup.is_checksum_valid().to_string(),
+                    None => String::from("unreadable"),
+                }
+            ]);
             table.add_row(row!["Root FSID", header.root_fsid]);
             table.add_row(row!["First Partition Size", header.firstpartsize]);
             table.add_row(row!["Partition List", header.partitionlist]);
             table.add_row(row!["Total Sectors", header.total_sectors]);
+            table.add_row(row!["Log Start", header.logstart]);
+            table.add_row(row!["Log Sectors", header.lognsectors]);
+            table.add_row(row!["Volume Header Log Stamp", header.volhdrlogstamp]);
+            table.add_row(row!["Unknown Start", header.unkstart]);
+            table.add_row(row!["Unknown Sectors", header.unksectors]);
+            table.add_row(row!["Unknown Stamp", header.unkstamp]);
             table.add_row(row!["Zonemap Sector", header.next_zonemap_sector]);
             table.add_row(row![
                 "Zonemap Backup Sector",
                 header.next_zonemap_backup_sector
             ]);
+            table.add_row(row![
+                "Zonemap Sector Length",
+                header.next_zonemap_sector_length
+            ]);
             table.add_row(row![
                 "Zonemap Partition Size",
                 header.next_zonemap_partition_size
             ]);
+            table.add_row(row![
+                "Zonemap Minimum Allocation",
+                header.next_zonemap_min_allocation
+            ]);
             table.add_row(row!["Next FSID", header.next_fsid]);
+            table.add_row(row!["Boot Cycles", header.bootcycles]);
+            table.add_row(row!["Boot Seconds", header.bootsecs]);
 
             // Print the table to stdout
             table.printstd();
@@ -217,9 +322,24 @@ fn main() {
             let input_path = sub_match.value_of("INPUT").unwrap();
             let inode_count: usize = sub_match.value_of("count").unwrap().parse().unwrap();
             let show_data = sub_match.is_present("data");
-
-            let mut tivo_drive =
-                ovit::TivoDrive::from_disk_image(input_path).expect("Could not load TiVo drive");
+            let cache_pages: usize = sub_match.value_of("cache-pages").unwrap().parse().unwrap();
+            let mmap = sub_match.is_present("mmap");
+
+            let mut tivo_drive = ovit::TivoDrive::from_disk_image_with_options(
+                input_path,
+                ovit::TivoDriveOptions {
+                    cache: if mmap {
+                        None
+                    } else {
+                        Some(CacheOptions {
+                            capacity: cache_pages,
+                            ..CacheOptions::default()
+                        })
+                    },
+                    mmap,
+                },
+            )
+            .expect("Could not load TiVo drive");
 
             // Create the table
             let mut table = Table::new();
@@ -237,10 +357,14 @@ fn main() {
                 "Type",
                 "Zone",
                 "Checksum",
+                "Checksum Valid",
                 "Flags",
                 "Number of Blocks",
                 if show_data { "Data" } else { "" },
             ]);
+            let source = tivo_drive.source.clone();
+            let volumes = tivo_drive.volumes.clone();
+
             for inode in tivo_drive
                 .raw_zonemap
                 .inode_iter()
@@ -260,10 +384,11 @@ fn main() {
                     format!("{:#?}", inode.r#type),
                     inode.zone,
                     inode.checksum,
+                    inode.is_checksum_valid(),
                     inode.flags,
                     inode.numblocks,
                     if show_data {
-                        format!("{:?}", inode.get_data(input_path.to_string()))
+                        format!("{:?}", inode.get_data(&*source, &volumes))
                     } else {
                         "".to_string()
                     }
@@ -272,6 +397,13 @@ fn main() {
 
             // Print the table to stdout
             table.printstd();
+
+            if let Some(stats) = tivo_drive.cache_stats() {
+                eprintln!(
+                    "Sector cache: {} hits, {} misses, {} pages read ahead",
+                    stats.hits, stats.misses, stats.prefetched
+                );
+            }
         }
         ("fsid", Some(sub_match)) => {
             // Calling .unwrap() is safe here because "INPUT" is required (if "INPUT" wasn't
@@ -281,7 +413,7 @@ fn main() {
 
             println!("Loading TiVo Drive");
 
-            let mut tivo_drive =
+            let tivo_drive =
                 ovit::TivoDrive::from_disk_image(input_path).expect("Could not load TiVo drive");
 
             println!("TiVo Drive Loaded!");
@@ -298,11 +430,25 @@ fn main() {
                 println!("INode is a Directory, getting directory entries.");
 
                 let entries = found_inode
-                    .get_entries_from_directory(input_path.to_string())
+                    .get_entries_from_directory(&*tivo_drive.source, &tivo_drive.volumes)
                     .unwrap();
 
                 println!("Entries: {:#?}", entries);
             }
+
+            if found_inode.r#type == MFSINodeType::Db {
+                println!("INode is a database object, decoding it.");
+
+                let object = found_inode
+                    .get_db_object(
+                        &*tivo_drive.source,
+                        &tivo_drive.volumes,
+                        MFSSchema::bundled(),
+                    )
+                    .unwrap();
+
+                println!("Object: {:#?}", object);
+            }
         }
         ("inode", Some(sub_match)) => {
             // Calling .unwrap() is safe here because "INPUT" is required (if "INPUT" wasn't
@@ -312,7 +458,7 @@ fn main() {
 
             println!("Loading TiVo Drive");
 
-            let mut tivo_drive =
+            let tivo_drive =
                 ovit::TivoDrive::from_disk_image(input_path).expect("Could not load TiVo drive");
 
             println!("TiVo Drive Loaded!");
@@ -327,15 +473,16 @@ fn main() {
 
             let sector = sector_for_inode(inode);
 
-            let found_inode = MFSINode::from_file_at_sector(
-                &mut tivo_drive.source_file,
+            let found_inode = MFSINode::from_source_at_sector(
+                &*tivo_drive.source,
                 tivo_drive
                     .volumes
                     .find_sector_volume(sector)
+                    .unwrap()
                     .disk_sector
                     .into(),
                 sector,
-                tivo_drive.is_byte_swapped,
+                tivo_drive.volume_header.is_64_bit,
             )
             .unwrap();
 
@@ -345,12 +492,150 @@ fn main() {
                 println!("INode is a Directory, getting directory entries.");
 
                 let entries = found_inode
-                    .get_entries_from_directory(input_path.to_string())
+                    .get_entries_from_directory(&*tivo_drive.source, &tivo_drive.volumes)
                     .unwrap();
 
                 println!("Entries: {:#?}", entries);
             }
         }
+        ("extract-recording", Some(sub_match)) => {
+            // Calling .unwrap() is safe here because "INPUT", "FSID" and "output" are required
+            let input_path = sub_match.value_of("INPUT").unwrap();
+            let fsid: u32 = sub_match.value_of("FSID").unwrap().parse().unwrap();
+            let output_path = sub_match.value_of("output").unwrap();
+            let format = match sub_match.value_of("format") {
+                Some("ts") => RecordingFormat::TransportStream,
+                Some(_) => RecordingFormat::ProgramStream,
+                None if output_path.ends_with(".ts") => RecordingFormat::TransportStream,
+                None => RecordingFormat::ProgramStream,
+            };
+
+            let tivo_drive =
+                ovit::TivoDrive::from_disk_image(input_path).expect("Could not load TiVo drive");
+
+            let output = File::create(output_path).expect("Could not create output file");
+
+            println!("Extracting FSID {} to {}", fsid, output_path);
+
+            let (_, skipped) = tivo_drive
+                .extract_recording(fsid, BufWriter::new(output), format)
+                .expect("Could not extract recording");
+
+            if skipped > 0 {
+                println!("Skipped {} unreadable chunks", skipped);
+            }
+            println!("Done!");
+        }
+        ("ls", Some(sub_match)) => {
+            // Calling .unwrap() is safe here because "INPUT" is required and "PATH" has a default
+            let input_path = sub_match.value_of("INPUT").unwrap();
+            let path = sub_match.value_of("PATH").unwrap();
+
+            let tivo_drive =
+                ovit::TivoDrive::from_disk_image(input_path).expect("Could not load TiVo drive");
+
+            let entries = tivo_drive.read_dir(path).expect("Could not read directory");
+
+            let mut table = Table::new();
+            table.add_row(row!["FSID", "Type", "Name"]);
+            for entry in entries {
+                table.add_row(row![entry.fsid, format!("{:?}", entry.r#type), entry.name]);
+            }
+            table.printstd();
+        }
+        ("recordings", Some(sub_match)) => {
+            // Calling .unwrap() is safe here because "INPUT" is required and "format" has a default
+            let input_path = sub_match.value_of("INPUT").unwrap();
+            let format = sub_match.value_of("format").unwrap();
+
+            let tivo_drive =
+                ovit::TivoDrive::from_disk_image(input_path).expect("Could not load TiVo drive");
+
+            let recordings = ovit::recordings(&tivo_drive).expect("Could not list recordings");
+
+            match format {
+                "json" => println!(
+                    "{}",
+                    serde_json::to_string_pretty(&recordings)
+                        .expect("Could not serialize recordings")
+                ),
+                "csv" => {
+                    println!("{}", ovit::RecordingInfo::CSV_HEADER);
+                    for recording in recordings.iter() {
+                        println!("{}", recording.csv_row());
+                    }
+                }
+                _ => {
+                    let mut table = Table::new();
+
+                    table.add_row(row![
+                        "FSID",
+                        "Title",
+                        "Episode Title",
+                        "Channel",
+                        "Air Date",
+                        "Duration",
+                        "Size",
+                        "Streams"
+                    ]);
+
+                    for recording in recordings.iter() {
+                        table.add_row(row![
+                            recording.fsid,
+                            recording.title.as_deref().unwrap_or(""),
+                            recording.episode_title.as_deref().unwrap_or(""),
+                            recording.channel.as_deref().unwrap_or(""),
+                            recording.air_date.as_deref().unwrap_or(""),
+                            recording.duration_text(),
+                            recording.size,
+                            recording.stream_fsids_text()
+                        ]);
+                    }
+
+                    table.printstd();
+                }
+            }
+        }
+        ("extract-captions", Some(sub_match)) => {
+            // Calling .unwrap() is safe here because "INPUT" and "FSID" are required
+            let input_path = sub_match.value_of("INPUT").unwrap();
+            let fsid: u32 = sub_match.value_of("FSID").unwrap().parse().unwrap();
+
+            let tivo_drive =
+                ovit::TivoDrive::from_disk_image(input_path).expect("Could not load TiVo drive");
+
+            let (captions, skipped) = tivo_drive
+                .closed_captions(fsid)
+                .expect("Could not read captions");
+
+            if skipped > 0 {
+                println!("Skipped {} unreadable chunks", skipped);
+            }
+            println!("Found {} captions", captions.captions.len());
+
+            if let Some(srt_path) = sub_match.value_of("srt") {
+                let output = File::create(srt_path).expect("Could not create SRT file");
+                captions
+                    .write_srt(BufWriter::new(output))
+                    .expect("Could not write SRT file");
+            }
+
+            if let Some(scc_path) = sub_match.value_of("scc") {
+                let output = File::create(scc_path).expect("Could not create SCC file");
+                captions
+                    .write_scc(BufWriter::new(output))
+                    .expect("Could not write SCC file");
+            }
+
+            for packet in captions.xds.iter() {
+                if let Some(name) = packet.program_name() {
+                    println!("XDS Program Name: {}", name);
+                }
+                if let Some(rating) = packet.rating() {
+                    println!("XDS Rating: {}", rating);
+                }
+            }
+        }
         _ => {
             println!("{}", matches.usage());
         }
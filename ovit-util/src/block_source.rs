@@ -0,0 +1,335 @@
+use crate::{swap_byte_order_in_place, Error, Result};
+use std::borrow::Cow;
+use std::convert::TryFrom;
+use std::fmt::Debug;
+use std::fs::File;
+use std::io::prelude::*;
+use std::io::{self, SeekFrom};
+use std::ops::Range;
+use std::sync::{Arc, Mutex};
+
+pub const SECTOR_SIZE: usize = 512;
+
+/// Where the `len` bytes starting at `sector` are in a memory backed source, `None` if they
+///  can't be addressed.
+pub(crate) fn sector_range(sector: u64, len: usize) -> Option<Range<usize>> {
+    let start = usize::try_from(sector).ok()?.checked_mul(SECTOR_SIZE)?;
+
+    Some(start..start.checked_add(len)?)
+}
+
+/// A sector addressable device that drive structures can be read from.
+///
+/// Sources are shared between the partition map, volume header, zone map and
+/// inode readers, so reads take `&self` and implementations must be thread safe.
+pub trait BlockSource: Debug + Send + Sync {
+    /// Read `count` sectors starting at `sector`.
+    fn read_sectors(&self, sector: u64, count: usize) -> Result<Vec<u8>>;
+
+    /// The length of the source in bytes.
+    fn len(&self) -> u64;
+
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    fn sector_size(&self) -> usize {
+        SECTOR_SIZE
+    }
+
+    fn read_sector(&self, sector: u64) -> Result<Vec<u8>> {
+        self.read_sectors(sector, 1)
+    }
+
+    /// Read the `len` bytes of a structure starting at `sector`.
+    ///
+    /// Memory backed sources borrow the bytes instead of copying them, and
+    /// byte swapped views only swap the `len` bytes of the structure.
+    fn read_bytes(&self, sector: u64, len: usize) -> Result<Cow<'_, [u8]>> {
+        let sector_size = self.sector_size();
+        let mut bytes = self.read_sectors(sector, len.div_ceil(sector_size))?;
+        bytes.truncate(len);
+        Ok(Cow::Owned(bytes))
+    }
+
+    /// The number of whole sectors in the source.
+    fn sector_count(&self) -> u64 {
+        self.len() / self.sector_size() as u64
+    }
+
+    /// Whether reads come straight out of memory, so caching them would only copy them again.
+    fn is_memory_backed(&self) -> bool {
+        false
+    }
+}
+
+impl<S: BlockSource + ?Sized> BlockSource for Arc<S> {
+    fn read_sectors(&self, sector: u64, count: usize) -> Result<Vec<u8>> {
+        (**self).read_sectors(sector, count)
+    }
+
+    fn read_bytes(&self, sector: u64, len: usize) -> Result<Cow<'_, [u8]>> {
+        (**self).read_bytes(sector, len)
+    }
+
+    fn len(&self) -> u64 {
+        (**self).len()
+    }
+
+    fn sector_size(&self) -> usize {
+        (**self).sector_size()
+    }
+
+    fn is_memory_backed(&self) -> bool {
+        (**self).is_memory_backed()
+    }
+}
+
+impl<S: BlockSource + ?Sized> BlockSource for Box<S> {
+    fn read_sectors(&self, sector: u64, count: usize) -> Result<Vec<u8>> {
+        (**self).read_sectors(sector, count)
+    }
+
+    fn read_bytes(&self, sector: u64, len: usize) -> Result<Cow<'_, [u8]>> {
+        (**self).read_bytes(sector, len)
+    }
+
+    fn len(&self) -> u64 {
+        (**self).len()
+    }
+
+    fn sector_size(&self) -> usize {
+        (**self).sector_size()
+    }
+
+    fn is_memory_backed(&self) -> bool {
+        (**self).is_memory_backed()
+    }
+}
+
+/// A drive or drive image on the local file system.
+#[derive(Debug)]
+pub struct FileSource {
+    file: Mutex<File>,
+    len: u64,
+}
+
+impl FileSource {
+    pub fn new(file: File) -> Result<FileSource> {
+        let len = match file.metadata() {
+            Ok(metadata) => metadata.len(),
+            Err(source) => {
+                return Err(Error::Io {
+                    sector: None,
+                    source,
+                });
+            }
+        };
+
+        Ok(FileSource {
+            file: Mutex::new(file),
+            len,
+        })
+    }
+
+    pub fn open(path: &str) -> Result<FileSource> {
+        match File::open(path) {
+            Ok(file) => FileSource::new(file),
+            Err(source) => Err(Error::Io {
+                sector: None,
+                source,
+            }),
+        }
+    }
+}
+
+impl BlockSource for FileSource {
+    fn read_sectors(&self, sector: u64, count: usize) -> Result<Vec<u8>> {
+        let mut buffer = vec![0; SECTOR_SIZE * count];
+
+        let mut file = match self.file.lock() {
+            Ok(file) => file,
+            Err(_) => {
+                return Err(Error::io(
+                    sector,
+                    io::Error::other("Drive lock was poisoned"),
+                ));
+            }
+        };
+
+        match file.seek(SeekFrom::Start(sector * SECTOR_SIZE as u64)) {
+            Ok(_) => {}
+            Err(err) => {
+                return Err(Error::io(sector, err));
+            }
+        };
+
+        match file.read_exact(&mut buffer) {
+            Ok(_) => Ok(buffer),
+            Err(err) => Err(Error::io(sector, err)),
+        }
+    }
+
+    fn len(&self) -> u64 {
+        self.len
+    }
+}
+
+/// An in-memory drive image, useful for synthetic images in tests.
+#[derive(Debug, Clone, PartialEq)]
+pub struct MemorySource {
+    data: Vec<u8>,
+}
+
+impl MemorySource {
+    pub fn new(data: Vec<u8>) -> MemorySource {
+        MemorySource { data }
+    }
+}
+
+impl BlockSource for MemorySource {
+    fn read_sectors(&self, sector: u64, count: usize) -> Result<Vec<u8>> {
+        // Too many sectors to address fails the range check in `read_bytes`
+        let len = count.saturating_mul(SECTOR_SIZE);
+        Ok(self.read_bytes(sector, len)?.into_owned())
+    }
+
+    fn read_bytes(&self, sector: u64, len: usize) -> Result<Cow<'_, [u8]>> {
+        match sector_range(sector, len).and_then(|range| self.data.get(range)) {
+            Some(bytes) => Ok(Cow::Borrowed(bytes)),
+            None => Err(Error::io(
+                sector,
+                io::Error::from(io::ErrorKind::UnexpectedEof),
+            )),
+        }
+    }
+
+    fn len(&self) -> u64 {
+        self.data.len() as u64
+    }
+
+    fn is_memory_backed(&self) -> bool {
+        true
+    }
+}
+
+/// A view over a source written by a little endian TiVo, swapping every
+/// 16-bit word back into the big endian order the MFS structures use.
+#[derive(Debug, Clone)]
+pub struct ByteSwappedSource<S: BlockSource> {
+    inner: S,
+}
+
+impl<S: BlockSource> ByteSwappedSource<S> {
+    pub fn new(inner: S) -> ByteSwappedSource<S> {
+        ByteSwappedSource { inner }
+    }
+
+    pub fn into_inner(self) -> S {
+        self.inner
+    }
+}
+
+impl<S: BlockSource> BlockSource for ByteSwappedSource<S> {
+    fn read_sectors(&self, sector: u64, count: usize) -> Result<Vec<u8>> {
+        let mut sectors = self.inner.read_sectors(sector, count)?;
+        swap_byte_order_in_place(&mut sectors);
+        Ok(sectors)
+    }
+
+    fn read_bytes(&self, sector: u64, len: usize) -> Result<Cow<'_, [u8]>> {
+        let mut bytes = self.inner.read_bytes(sector, len)?.into_owned();
+        swap_byte_order_in_place(&mut bytes);
+        Ok(Cow::Owned(bytes))
+    }
+
+    fn len(&self) -> u64 {
+        self.inner.len()
+    }
+
+    fn sector_size(&self) -> usize {
+        self.inner.sector_size()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn numbered_sectors(count: usize) -> Vec<u8> {
+        (0..count)
+            .flat_map(|sector| vec![sector as u8; SECTOR_SIZE])
+            .collect()
+    }
+
+    #[test]
+    fn test_memory_source_reads_sectors() {
+        let source = MemorySource::new(numbered_sectors(4));
+
+        let sectors = source.read_sectors(1, 2).unwrap();
+
+        assert_eq!(sectors.len(), 2 * SECTOR_SIZE);
+        assert_eq!(sectors[0], 1);
+        assert_eq!(sectors[SECTOR_SIZE], 2);
+        assert_eq!(source.sector_count(), 4);
+    }
+
+    #[test]
+    fn test_memory_source_read_past_end() {
+        let source = MemorySource::new(numbered_sectors(4));
+
+        match source.read_sectors(3, 2) {
+            Err(Error::Io {
+                sector: Some(3), ..
+            }) => {}
+            result => panic!("Expected an I/O error, got {:?}", result),
+        }
+    }
+
+    #[test]
+    fn test_memory_source_unaddressable_reads() {
+        let source = MemorySource::new(numbered_sectors(4));
+
+        assert!(source.read_bytes(u64::MAX, 16).is_err());
+        assert!(source.read_bytes(1, usize::MAX).is_err());
+        assert!(source.read_sectors(1, usize::MAX).is_err());
+        assert!(sector_range(u64::MAX / 2, 1).is_none());
+    }
+
+    #[test]
+    fn test_byte_swapped_source() {
+        let mut image = vec![0; SECTOR_SIZE];
+        image[0] = 0x92;
+        image[1] = 0x14;
+        let source = ByteSwappedSource::new(MemorySource::new(image));
+
+        assert_eq!(source.read_sector(0).unwrap()[0..2], [0x14, 0x92]);
+    }
+
+    #[test]
+    fn test_memory_source_borrows_bytes() {
+        let source = MemorySource::new(numbered_sectors(4));
+
+        match source.read_bytes(2, 16).unwrap() {
+            Cow::Borrowed(bytes) => assert_eq!(bytes, [2; 16]),
+            Cow::Owned(_) => panic!("Expected borrowed bytes"),
+        }
+    }
+
+    #[test]
+    fn test_byte_swapped_source_read_bytes() {
+        let mut image = vec![0; SECTOR_SIZE * 2];
+        image[SECTOR_SIZE..SECTOR_SIZE + 4].copy_from_slice(&[0xAB, 0xBA, 0xFE, 0xED]);
+        let source = ByteSwappedSource::new(MemorySource::new(image));
+
+        assert_eq!(*source.read_bytes(1, 4).unwrap(), [0xBA, 0xAB, 0xED, 0xFE]);
+    }
+
+    #[test]
+    fn test_shared_source() {
+        let source: Arc<dyn BlockSource> = Arc::new(MemorySource::new(numbered_sectors(2)));
+
+        assert_eq!(source.read_sector(1).unwrap()[0], 1);
+        assert_eq!(source.len(), 2 * SECTOR_SIZE as u64);
+    }
+}
@@ -0,0 +1,377 @@
+use crate::{BlockSource, Error, LruCache, Result};
+use std::io;
+use std::sync::{Mutex, MutexGuard};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct CacheOptions {
+    /// How many pages to keep before evicting the least recently used.
+    pub capacity: usize,
+    /// How many sectors make up a single page.
+    pub page_sectors: usize,
+    /// How many extra pages to read when pages are requested sequentially.
+    pub read_ahead: usize,
+}
+
+impl Default for CacheOptions {
+    fn default() -> CacheOptions {
+        // 32 MiB of 32 KiB pages
+        CacheOptions {
+            capacity: 1024,
+            page_sectors: 64,
+            read_ahead: 8,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct CacheStats {
+    pub hits: u64,
+    pub misses: u64,
+    /// Pages loaded by read-ahead before they were requested.
+    pub prefetched: u64,
+    pub evictions: u64,
+}
+
+#[derive(Debug)]
+struct CacheState {
+    pages: LruCache<u64, Vec<u8>>,
+    last_missed_page: Option<u64>,
+    stats: CacheStats,
+}
+
+impl CacheState {
+    fn new(capacity: usize) -> CacheState {
+        CacheState {
+            pages: LruCache::new(capacity),
+            last_missed_page: None,
+            stats: CacheStats::default(),
+        }
+    }
+}
+
+/// An LRU page cache in front of another source, with read-ahead for
+/// sequential access patterns like walking the inode zone.
+///
+/// Memory backed sources are already as fast as the cache, so there's no
+/// point putting one in front of them.
+#[derive(Debug)]
+pub struct CachedSource<S: BlockSource> {
+    inner: S,
+    options: CacheOptions,
+    state: Mutex<CacheState>,
+}
+
+impl<S: BlockSource> CachedSource<S> {
+    pub fn new(inner: S, options: CacheOptions) -> CachedSource<S> {
+        CachedSource {
+            inner,
+            options: CacheOptions {
+                capacity: options.capacity.max(1),
+                page_sectors: options.page_sectors.max(1),
+                read_ahead: options.read_ahead,
+            },
+            state: Mutex::new(CacheState::new(options.capacity)),
+        }
+    }
+
+    pub fn options(&self) -> CacheOptions {
+        self.options
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        self.lock().stats
+    }
+
+    pub fn clear(&self) {
+        if let Ok(mut state) = self.state.lock() {
+            *state = CacheState::new(self.options.capacity);
+        }
+    }
+
+    fn lock(&self) -> MutexGuard<'_, CacheState> {
+        match self.state.lock() {
+            Ok(state) => state,
+            Err(poisoned) => poisoned.into_inner(),
+        }
+    }
+
+    /// Read `count` pages starting at `page` from the underlying source,
+    /// stopping early at the end of the source.
+    fn load_pages(&self, page: u64, count: usize) -> Result<Vec<Vec<u8>>> {
+        let page_sectors = self.options.page_sectors as u64;
+        let first_sector = page * page_sectors;
+        let total_sectors = self.inner.sector_count();
+
+        let sectors = if total_sectors == 0 {
+            count as u64 * page_sectors
+        } else {
+            (count as u64 * page_sectors).min(total_sectors.saturating_sub(first_sector))
+        };
+
+        if sectors == 0 {
+            return Err(Error::io(
+                first_sector,
+                io::Error::from(io::ErrorKind::UnexpectedEof),
+            ));
+        }
+
+        let data = self.inner.read_sectors(first_sector, sectors as usize)?;
+
+        Ok(data
+            .chunks(self.options.page_sectors * self.inner.sector_size())
+            .map(|chunk| chunk.to_vec())
+            .collect())
+    }
+}
+
+impl<S: BlockSource> BlockSource for CachedSource<S> {
+    fn read_sectors(&self, sector: u64, count: usize) -> Result<Vec<u8>> {
+        let sector_size = self.inner.sector_size();
+        let page_sectors = self.options.page_sectors as u64;
+        let mut buffer = Vec::with_capacity(count * sector_size);
+
+        let mut next_sector = sector;
+        let last_sector = sector + count as u64;
+
+        while next_sector < last_sector {
+            let page = next_sector / page_sectors;
+            let offset = (next_sector % page_sectors) as usize * sector_size;
+            let wanted = ((last_sector - next_sector)
+                .min(page_sectors - next_sector % page_sectors)) as usize
+                * sector_size;
+
+            // Only hold the lock to look the page up, reads from the source happen without it
+            let read_ahead = {
+                let mut state = self.lock();
+                match state.pages.get(&page) {
+                    Some(data) => match data.get(offset..offset + wanted) {
+                        Some(bytes) => {
+                            buffer.extend_from_slice(bytes);
+                            state.stats.hits += 1;
+                            next_sector += (wanted / sector_size) as u64;
+                            continue;
+                        }
+                        None => {
+                            return Err(Error::io(
+                                next_sector,
+                                io::Error::from(io::ErrorKind::UnexpectedEof),
+                            ));
+                        }
+                    },
+                    None => {
+                        state.stats.misses += 1;
+
+                        let sequential =
+                            matches!(state.last_missed_page, Some(last) if last + 1 == page);
+                        let read_ahead = if sequential {
+                            self.options.read_ahead
+                        } else {
+                            0
+                        };
+                        state.last_missed_page = Some(page + read_ahead as u64);
+                        read_ahead
+                    }
+                }
+            };
+
+            // Read-ahead is best effort, if it fails only the requested page is read
+            let pages = match self.load_pages(page, 1 + read_ahead) {
+                Ok(pages) => pages,
+                Err(_) if read_ahead > 0 => self.load_pages(page, 1)?,
+                Err(err) => return Err(err),
+            };
+
+            match pages[0].get(offset..offset + wanted) {
+                Some(bytes) => buffer.extend_from_slice(bytes),
+                None => {
+                    return Err(Error::io(
+                        next_sector,
+                        io::Error::from(io::ErrorKind::UnexpectedEof),
+                    ));
+                }
+            }
+
+            let mut state = self.lock();
+            state.stats.prefetched += pages.len() as u64 - 1;
+
+            // Insert the requested page last so read-ahead can't evict it
+            for (index, data) in pages.into_iter().enumerate().rev() {
+                if state.pages.insert(page + index as u64, data).is_some() {
+                    state.stats.evictions += 1;
+                }
+            }
+
+            next_sector += (wanted / sector_size) as u64;
+        }
+
+        Ok(buffer)
+    }
+
+    fn len(&self) -> u64 {
+        self.inner.len()
+    }
+
+    fn sector_size(&self) -> usize {
+        self.inner.sector_size()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{MemorySource, SECTOR_SIZE};
+
+    fn numbered_sectors(count: usize) -> MemorySource {
+        MemorySource::new(
+            (0..count)
+                .flat_map(|sector| vec![sector as u8; SECTOR_SIZE])
+                .collect(),
+        )
+    }
+
+    #[test]
+    fn test_cached_reads_match_source() {
+        let source = CachedSource::new(
+            numbered_sectors(32),
+            CacheOptions {
+                capacity: 2,
+                page_sectors: 4,
+                read_ahead: 1,
+            },
+        );
+
+        for sector in (0..30).rev() {
+            let sectors = source.read_sectors(sector, 3).unwrap();
+
+            assert_eq!(sectors.len(), 3 * SECTOR_SIZE);
+            assert_eq!(sectors[0], sector as u8);
+            assert_eq!(sectors[2 * SECTOR_SIZE], sector as u8 + 2);
+        }
+    }
+
+    #[test]
+    fn test_cache_hits_and_misses() {
+        let source = CachedSource::new(
+            numbered_sectors(16),
+            CacheOptions {
+                capacity: 8,
+                page_sectors: 4,
+                read_ahead: 0,
+            },
+        );
+
+        source.read_sector(0).unwrap();
+        source.read_sector(1).unwrap();
+        source.read_sector(5).unwrap();
+
+        assert_eq!(
+            source.stats(),
+            CacheStats {
+                hits: 1,
+                misses: 2,
+                prefetched: 0,
+                evictions: 0
+            }
+        );
+    }
+
+    #[test]
+    fn test_sequential_read_ahead() {
+        let source = CachedSource::new(
+            numbered_sectors(64),
+            CacheOptions {
+                capacity: 16,
+                page_sectors: 4,
+                read_ahead: 4,
+            },
+        );
+
+        for sector in 0..28 {
+            assert_eq!(source.read_sector(sector).unwrap()[0], sector as u8);
+        }
+
+        let stats = source.stats();
+        assert_eq!(stats.misses, 3);
+        assert_eq!(stats.prefetched, 8);
+    }
+
+    #[test]
+    fn test_lru_eviction() {
+        let source = CachedSource::new(
+            numbered_sectors(16),
+            CacheOptions {
+                capacity: 2,
+                page_sectors: 4,
+                read_ahead: 0,
+            },
+        );
+
+        source.read_sector(0).unwrap();
+        source.read_sector(4).unwrap();
+        source.read_sector(0).unwrap();
+        source.read_sector(8).unwrap();
+        source.read_sector(0).unwrap();
+        source.read_sector(4).unwrap();
+
+        let stats = source.stats();
+        assert_eq!(stats.hits, 2);
+        assert_eq!(stats.misses, 4);
+        assert_eq!(stats.evictions, 2);
+    }
+
+    /// Fails any read that touches a sector from `bad` on.
+    #[derive(Debug)]
+    struct BadSectorsSource {
+        inner: MemorySource,
+        bad: u64,
+    }
+
+    impl BlockSource for BadSectorsSource {
+        fn read_sectors(&self, sector: u64, count: usize) -> Result<Vec<u8>> {
+            if sector + count as u64 > self.bad {
+                return Err(Error::io(sector, io::Error::other("bad sector")));
+            }
+            self.inner.read_sectors(sector, count)
+        }
+
+        fn len(&self) -> u64 {
+            self.inner.len()
+        }
+    }
+
+    #[test]
+    fn test_failed_read_ahead() {
+        let source = CachedSource::new(
+            BadSectorsSource {
+                inner: numbered_sectors(32),
+                bad: 12,
+            },
+            CacheOptions {
+                capacity: 8,
+                page_sectors: 4,
+                read_ahead: 2,
+            },
+        );
+
+        // Reading page 1 sequentially reads ahead into the bad sectors
+        assert_eq!(source.read_sector(0).unwrap()[0], 0);
+        assert_eq!(source.read_sector(4).unwrap()[0], 4);
+        assert_eq!(source.read_sector(8).unwrap()[0], 8);
+        assert!(source.read_sector(12).is_err());
+    }
+
+    #[test]
+    fn test_partial_last_page() {
+        let source = CachedSource::new(
+            numbered_sectors(6),
+            CacheOptions {
+                capacity: 4,
+                page_sectors: 4,
+                read_ahead: 2,
+            },
+        );
+
+        assert_eq!(source.read_sector(5).unwrap()[0], 5);
+        assert!(source.read_sector(6).is_err());
+    }
+}
@@ -0,0 +1,134 @@
+use nom::error::ErrorKind;
+use std::fmt;
+use std::io;
+
+/// Everything that can go wrong while reading a TiVo drive.
+#[derive(Debug)]
+pub enum Error {
+    /// The underlying source could not be read.
+    Io {
+        sector: Option<u64>,
+        source: io::Error,
+    },
+    /// A structure could not be parsed, `offset` is relative to the start of the structure.
+    Parse {
+        structure: &'static str,
+        offset: usize,
+        kind: ErrorKind,
+    },
+    /// A structure didn't start with any of the `expected` magic numbers.
+    BadMagic {
+        structure: &'static str,
+        expected: &'static [u32],
+        found: u32,
+    },
+    ChecksumMismatch {
+        structure: &'static str,
+        expected: u32,
+        actual: u32,
+    },
+    FsidNotFound(u32),
+    /// A structure the drive should contain, like the MFS partitions or the inode zone.
+    NotFound(&'static str),
+    /// A sector that doesn't belong to any of the MFS volumes.
+    VolumeSectorOutOfRange(u64),
+    /// A database object doesn't fit the type it was read as.
+    Schema(Box<dyn std::error::Error + Send + Sync>),
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+impl Error {
+    pub fn io(sector: u64, source: io::Error) -> Error {
+        Error::Io {
+            sector: Some(sector),
+            source,
+        }
+    }
+
+    /// Convert a nom error raised while parsing `input` into an `Error::Parse`.
+    pub fn parse(
+        structure: &'static str,
+        input: &[u8],
+        err: nom::Err<(&[u8], ErrorKind)>,
+    ) -> Error {
+        let (offset, kind) = match err {
+            nom::Err::Error((remaining, kind)) | nom::Err::Failure((remaining, kind)) => {
+                (input.len() - remaining.len(), kind)
+            }
+            nom::Err::Incomplete(_) => (input.len(), ErrorKind::Eof),
+        };
+
+        Error::Parse {
+            structure,
+            offset,
+            kind,
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::Io {
+                sector: Some(sector),
+                source,
+            } => write!(f, "Could not read sector {}: {}", sector, source),
+            Error::Io {
+                sector: None,
+                source,
+            } => write!(f, "Could not read drive: {}", source),
+            Error::Parse {
+                structure,
+                offset,
+                kind,
+            } => write!(
+                f,
+                "Could not parse {} at offset {:#X}: {:?}",
+                structure, offset, kind
+            ),
+            Error::BadMagic {
+                structure,
+                expected,
+                found,
+            } => {
+                let expected: Vec<String> = expected
+                    .iter()
+                    .map(|magic| format!("{:#X}", magic))
+                    .collect();
+                write!(
+                    f,
+                    "Bad {} magic, expected {} but found {:#X}",
+                    structure,
+                    expected.join(" or "),
+                    found
+                )
+            }
+            Error::ChecksumMismatch {
+                structure,
+                expected,
+                actual,
+            } => write!(
+                f,
+                "Bad {} checksum, expected {:#X} but computed {:#X}",
+                structure, expected, actual
+            ),
+            Error::FsidNotFound(fsid) => write!(f, "Could not find INode for FSID {}", fsid),
+            Error::NotFound(structure) => write!(f, "Could not find {}", structure),
+            Error::VolumeSectorOutOfRange(sector) => {
+                write!(f, "Could not find volume containing sector {}", sector)
+            }
+            Error::Schema(err) => write!(f, "{}", err),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Io { source, .. } => Some(source),
+            Error::Schema(err) => Some(&**err),
+            _ => None,
+        }
+    }
+}
@@ -1,102 +1,37 @@
-use std::convert::TryInto;
-use std::fs::File;
-use std::io::prelude::*;
-use std::io::SeekFrom;
+extern crate nom;
 
-const APM_BLOCK_SIZE: usize = 512;
+mod block_source;
+pub use block_source::*;
+
+mod cache;
+pub use cache::*;
+
+mod error;
+pub use error::*;
+
+mod lru;
+pub use lru::*;
+
+mod mmap;
+pub use mmap::*;
 
 pub fn correct_byte_order(raw_buffer: &[u8], is_byte_swapped: bool) -> Vec<u8> {
     raw_buffer
         .chunks_exact(2)
-        .map(|chunk| u16::from_be_bytes(chunk[0..2].try_into().unwrap()))
-        .map(|byte| {
+        .flat_map(|chunk| {
             if is_byte_swapped {
-                byte
+                [chunk[1], chunk[0]]
             } else {
-                byte.swap_bytes()
+                [chunk[0], chunk[1]]
             }
         })
-        .flat_map(|byte| -> Vec<u8> { byte.to_ne_bytes().to_vec() })
         .collect()
 }
 
-pub fn get_block_from_file(
-    path: &str,
-    location: u64,
-    is_byte_swapped: bool,
-) -> Result<Vec<u8>, String> {
-    get_blocks_from_file(path, location, 1, is_byte_swapped)
-}
-
-pub fn get_block_from_drive_and_correct_order(
-    file: &mut File,
-    location: u64,
-    is_byte_swapped: bool,
-) -> Result<Vec<u8>, String> {
-    Ok(correct_byte_order(
-        &get_block_from_drive(file, location)?,
-        is_byte_swapped,
-    ))
-}
-
-pub fn get_block_from_drive(file: &mut File, location: u64) -> Result<Vec<u8>, String> {
-    get_blocks_from_drive(file, location, 1)
-}
-
-pub fn get_blocks_from_file(
-    path: &str,
-    location: u64,
-    count: usize,
-    is_byte_swapped: bool,
-) -> Result<Vec<u8>, String> {
-    let mut file = match File::open(path) {
-        Ok(file) => file,
-        Err(_) => {
-            return Err("Couldn't open drive".to_string());
-        }
-    };
-
-    Ok(correct_byte_order(
-        &get_blocks_from_drive(&mut file, location, count)?,
-        is_byte_swapped,
-    ))
-}
-
-pub fn get_blocks_from_drive_and_correct_order(
-    file: &mut File,
-    location: u64,
-    count: usize,
-    is_byte_swapped: bool,
-) -> Result<Vec<u8>, String> {
-    Ok(correct_byte_order(
-        &get_blocks_from_drive(file, location, count)?,
-        is_byte_swapped,
-    ))
-}
-
-pub fn get_blocks_from_drive(
-    file: &mut File,
-    location: u64,
-    count: usize,
-) -> Result<Vec<u8>, String> {
-    let mut buffer = vec![0; APM_BLOCK_SIZE * count];
-
-    match file.seek(SeekFrom::Start(location * APM_BLOCK_SIZE as u64)) {
-        Ok(_) => {}
-        Err(_) => {
-            return Err(format!(
-                "Could not set file cursor to location {}",
-                location
-            ));
-        }
-    };
-
-    match file.read(&mut buffer) {
-        Ok(_) => Ok(buffer),
-        Err(_) => Err(format!(
-            "Could not read block from file at location {}",
-            location
-        )),
+/// Swap every 16-bit word of `buffer` without allocating a copy.
+pub fn swap_byte_order_in_place(buffer: &mut [u8]) {
+    for chunk in buffer.chunks_exact_mut(2) {
+        chunk.swap(0, 1);
     }
 }
 
@@ -119,4 +54,12 @@ mod test {
 
         assert_eq!(corrected_bytes, [0x14, 0x92]);
     }
+
+    #[test]
+    fn test_swap_byte_order_in_place() {
+        let mut bytes: [u8; 4] = [0x92, 0x14, 0xAB, 0xBA];
+        swap_byte_order_in_place(&mut bytes);
+
+        assert_eq!(bytes, [0x14, 0x92, 0xBA, 0xAB]);
+    }
 }
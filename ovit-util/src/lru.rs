@@ -0,0 +1,98 @@
+use std::borrow::Borrow;
+use std::collections::{BTreeMap, HashMap};
+use std::hash::Hash;
+
+/// A map holding at most `capacity` values, the least recently used is evicted to make
+/// room for new ones.
+#[derive(Debug)]
+pub struct LruCache<K, V> {
+    capacity: usize,
+    values: HashMap<K, (V, u64)>,
+    recently_used: BTreeMap<u64, K>,
+    tick: u64,
+}
+
+impl<K: Hash + Eq + Clone, V> LruCache<K, V> {
+    pub fn new(capacity: usize) -> LruCache<K, V> {
+        LruCache {
+            capacity: capacity.max(1),
+            values: HashMap::new(),
+            recently_used: BTreeMap::new(),
+            tick: 0,
+        }
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    pub fn len(&self) -> usize {
+        self.values.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.values.is_empty()
+    }
+
+    /// The value for `key`, which becomes the most recently used.
+    pub fn get<Q>(&mut self, key: &Q) -> Option<&V>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        self.tick += 1;
+        let tick = self.tick;
+
+        match self.values.get_mut(key) {
+            Some((value, last_used)) => {
+                if let Some(key) = self.recently_used.remove(last_used) {
+                    self.recently_used.insert(tick, key);
+                }
+                *last_used = tick;
+                Some(value)
+            }
+            None => None,
+        }
+    }
+
+    /// Insert `value` as the most recently used, returning whatever was evicted for it.
+    pub fn insert(&mut self, key: K, value: V) -> Option<(K, V)> {
+        self.tick += 1;
+        let tick = self.tick;
+
+        if let Some((_, last_used)) = self.values.insert(key.clone(), (value, tick)) {
+            self.recently_used.remove(&last_used);
+        }
+        self.recently_used.insert(tick, key);
+
+        if self.values.len() <= self.capacity {
+            return None;
+        }
+
+        let oldest = *self.recently_used.keys().next()?;
+        let key = self.recently_used.remove(&oldest)?;
+        self.values.remove(&key).map(|(value, _)| (key, value))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_least_recently_used_is_evicted() {
+        let mut cache = LruCache::new(2);
+
+        assert_eq!(cache.insert(1, "one"), None);
+        assert_eq!(cache.insert(2, "two"), None);
+        assert_eq!(cache.get(&1), Some(&"one"));
+        assert_eq!(cache.insert(3, "three"), Some((2, "two")));
+        // Replacing a value doesn't evict anything
+        assert_eq!(cache.insert(1, "uno"), None);
+
+        assert_eq!(cache.len(), 2);
+        assert_eq!(cache.get(&2), None);
+        assert_eq!(cache.get(&1), Some(&"uno"));
+        assert_eq!(cache.get(&3), Some(&"three"));
+    }
+}
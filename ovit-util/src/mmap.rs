@@ -0,0 +1,99 @@
+use crate::{sector_range, BlockSource, Error, Result, SECTOR_SIZE};
+use memmap::Mmap;
+use std::borrow::Cow;
+use std::fs::File;
+use std::io;
+
+/// A read-only, memory mapped drive image.
+///
+/// Reads don't share a file cursor, so the map can be read from many threads
+/// at once, and structures can be borrowed straight out of the map.
+#[derive(Debug)]
+pub struct MmapSource {
+    map: Mmap,
+}
+
+impl MmapSource {
+    pub fn new(file: &File) -> Result<MmapSource> {
+        // The map is read only, but another process truncating or writing to the
+        //  image while it's mapped would still be undefined behaviour.
+        match unsafe { Mmap::map(file) } {
+            Ok(map) => Ok(MmapSource { map }),
+            Err(source) => Err(Error::Io {
+                sector: None,
+                source,
+            }),
+        }
+    }
+
+    pub fn open(path: &str) -> Result<MmapSource> {
+        match File::open(path) {
+            Ok(file) => MmapSource::new(&file),
+            Err(source) => Err(Error::Io {
+                sector: None,
+                source,
+            }),
+        }
+    }
+
+    /// Borrow `count` sectors straight out of the map.
+    pub fn sectors(&self, sector: u64, count: usize) -> Option<&[u8]> {
+        let start = sector as usize * SECTOR_SIZE;
+
+        self.map.get(start..start + count * SECTOR_SIZE)
+    }
+}
+
+impl BlockSource for MmapSource {
+    fn read_sectors(&self, sector: u64, count: usize) -> Result<Vec<u8>> {
+        // Too many sectors to address fails the range check in `read_bytes`
+        let len = count.saturating_mul(SECTOR_SIZE);
+        Ok(self.read_bytes(sector, len)?.into_owned())
+    }
+
+    fn read_bytes(&self, sector: u64, len: usize) -> Result<Cow<'_, [u8]>> {
+        match sector_range(sector, len).and_then(|range| self.map.get(range)) {
+            Some(bytes) => Ok(Cow::Borrowed(bytes)),
+            None => Err(Error::io(
+                sector,
+                io::Error::from(io::ErrorKind::UnexpectedEof),
+            )),
+        }
+    }
+
+    fn len(&self) -> u64 {
+        self.map.len() as u64
+    }
+
+    fn is_memory_backed(&self) -> bool {
+        true
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::io::Write;
+
+    #[test]
+    fn test_mmap_source_borrows_sectors() {
+        let path = std::env::temp_dir().join("ovit-util-test-mmap-source.img");
+        let mut file = File::create(&path).unwrap();
+        file.write_all(&[0x14; SECTOR_SIZE]).unwrap();
+        file.write_all(&[0x92; SECTOR_SIZE]).unwrap();
+        drop(file);
+
+        let source = MmapSource::open(path.to_str().unwrap()).unwrap();
+
+        assert_eq!(source.len(), 2 * SECTOR_SIZE as u64);
+        assert_eq!(source.read_sector(1).unwrap(), vec![0x92; SECTOR_SIZE]);
+        match source.read_bytes(1, 4).unwrap() {
+            Cow::Borrowed(bytes) => assert_eq!(bytes, [0x92; 4]),
+            Cow::Owned(_) => panic!("Expected borrowed bytes"),
+        }
+        assert!(source.read_sector(2).is_err());
+        assert!(source.read_bytes(u64::MAX, 4).is_err());
+
+        std::fs::remove_file(path).unwrap();
+    }
+}
@@ -0,0 +1,573 @@
+use crate::{ElementaryStreamDemuxer, Error, Result};
+use std::collections::HashMap;
+use std::io::Write;
+use tivo_media_file_system::{TyRecord, TyRecordType};
+
+/// 90 kHz ticks per NTSC frame.
+const TICKS_PER_FRAME: u64 = 3003;
+
+/// A caption and when it was on screen, in 90 kHz ticks from the start of the recording.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Caption {
+    pub start: u64,
+    pub end: u64,
+    pub text: String,
+}
+
+/// A complete XDS packet.
+#[derive(Debug, Clone, PartialEq)]
+pub struct XdsPacket {
+    /// When the packet finished, in 90 kHz ticks from the start of the recording.
+    pub time: u64,
+    /// The start code's class, 0x01 for the current program.
+    pub class: u8,
+    pub r#type: u8,
+    pub data: Vec<u8>,
+}
+
+impl XdsPacket {
+    fn is_current_program(&self) -> bool {
+        self.class == 0x01
+    }
+
+    pub fn program_name(&self) -> Option<String> {
+        if !self.is_current_program() || self.r#type != 0x03 {
+            return None;
+        }
+
+        let name: String = self
+            .data
+            .iter()
+            .filter(|byte| **byte >= 0x20)
+            .map(|byte| char::from(*byte))
+            .collect();
+
+        Some(name.trim().to_string())
+    }
+
+    /// The program's content advisory, as an MPAA or US TV parental guidelines rating.
+    pub fn rating(&self) -> Option<String> {
+        if !self.is_current_program() || self.r#type != 0x05 || self.data.len() < 2 {
+            return None;
+        }
+
+        let rating = match (self.data[0] >> 3) & 0x03 {
+            0x01 => match self.data[1] & 0x07 {
+                1 => "TV-Y",
+                2 => "TV-Y7",
+                3 => "TV-G",
+                4 => "TV-PG",
+                5 => "TV-14",
+                6 => "TV-MA",
+                _ => "None",
+            },
+            0x03 => "Canadian",
+            _ => match self.data[0] & 0x07 {
+                1 => "G",
+                2 => "PG",
+                3 => "PG-13",
+                4 => "R",
+                5 => "NC-17",
+                6 => "X",
+                7 => "Not Rated",
+                _ => "N/A",
+            },
+        };
+
+        Some(rating.to_string())
+    }
+}
+
+/// Map the few characters where line 21 differs from ASCII.
+fn basic_character(byte: u8) -> char {
+    match byte {
+        0x2A => 'á',
+        0x5C => 'é',
+        0x5E => 'í',
+        0x5F => 'ó',
+        0x60 => 'ú',
+        0x7B => 'ç',
+        0x7C => '÷',
+        0x7D => 'Ñ',
+        0x7E => 'ñ',
+        0x7F => '█',
+        byte => char::from(byte),
+    }
+}
+
+fn special_character(byte: u8) -> char {
+    match byte {
+        0x30 => '®',
+        0x31 => '°',
+        0x32 => '½',
+        0x33 => '¿',
+        0x34 => '™',
+        0x35 => '¢',
+        0x36 => '£',
+        0x37 => '♪',
+        0x38 => 'à',
+        0x39 => ' ',
+        0x3A => 'è',
+        0x3B => 'â',
+        0x3C => 'ê',
+        0x3D => 'î',
+        0x3E => 'ô',
+        _ => 'û',
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum CaptionMode {
+    PopOn,
+    RollUp,
+    PaintOn,
+}
+
+/// Decodes the CC1 channel of EIA-608 closed captions and the XDS packets sent
+///  alongside them.
+#[derive(Debug)]
+pub struct ClosedCaptionDecoder {
+    demuxer: ElementaryStreamDemuxer,
+    first_pts: Option<u64>,
+    /// Time of the latest video frame.
+    now: u64,
+
+    mode: CaptionMode,
+    displayed: String,
+    displayed_since: u64,
+    non_displayed: String,
+    /// Control codes are sent twice, the repeat is ignored.
+    last_control: Option<[u8; 2]>,
+    /// Whether the data channel is currently CC1, rather than CC2.
+    in_cc1: bool,
+
+    /// XDS packets being assembled, by class and type.
+    xds_pending: HashMap<(u8, u8), Vec<u8>>,
+    xds_current: Option<(u8, u8)>,
+
+    captions: Vec<Caption>,
+    pairs: Vec<(u64, [u8; 2])>,
+    xds: Vec<XdsPacket>,
+}
+
+impl Default for ClosedCaptionDecoder {
+    fn default() -> ClosedCaptionDecoder {
+        ClosedCaptionDecoder {
+            demuxer: ElementaryStreamDemuxer::new(),
+            first_pts: None,
+            now: 0,
+
+            mode: CaptionMode::PopOn,
+            displayed: String::new(),
+            displayed_since: 0,
+            non_displayed: String::new(),
+            last_control: None,
+            in_cc1: true,
+
+            xds_pending: HashMap::new(),
+            xds_current: None,
+
+            captions: vec![],
+            pairs: vec![],
+            xds: vec![],
+        }
+    }
+}
+
+impl ClosedCaptionDecoder {
+    pub fn new() -> ClosedCaptionDecoder {
+        ClosedCaptionDecoder::default()
+    }
+
+    /// Move what's on screen into a caption, ending now.
+    fn end_displayed(&mut self) {
+        let text = self.displayed.trim();
+
+        if !text.is_empty() {
+            self.captions.push(Caption {
+                start: self.displayed_since,
+                end: self.now.max(self.displayed_since + TICKS_PER_FRAME),
+                text: text.to_string(),
+            });
+        }
+
+        self.displayed.clear();
+        self.displayed_since = self.now;
+    }
+
+    fn buffer(&mut self) -> &mut String {
+        match self.mode {
+            CaptionMode::PopOn => &mut self.non_displayed,
+            CaptionMode::RollUp | CaptionMode::PaintOn => &mut self.displayed,
+        }
+    }
+
+    fn new_row(&mut self) {
+        let buffer = self.buffer();
+        if !buffer.is_empty() && !buffer.ends_with('\n') {
+            buffer.push('\n');
+        }
+    }
+
+    fn command(&mut self, command: u8) {
+        match command {
+            // Resume caption loading
+            0x20 => self.mode = CaptionMode::PopOn,
+            // Backspace
+            0x21 => {
+                self.buffer().pop();
+            }
+            // Roll-up captions, 2 to 4 rows
+            0x25..=0x27 => {
+                if self.mode != CaptionMode::RollUp {
+                    self.end_displayed();
+                }
+                self.mode = CaptionMode::RollUp;
+            }
+            // Resume direct captioning
+            0x29 => self.mode = CaptionMode::PaintOn,
+            // Erase displayed memory
+            0x2C => self.end_displayed(),
+            // Carriage return, the rolled up row becomes a caption of its own
+            0x2D => match self.mode {
+                CaptionMode::RollUp => self.end_displayed(),
+                _ => self.new_row(),
+            },
+            // Erase non-displayed memory
+            0x2E => self.non_displayed.clear(),
+            // End of caption, flip the memories
+            0x2F => {
+                self.end_displayed();
+                self.displayed = std::mem::take(&mut self.non_displayed);
+                self.mode = CaptionMode::PopOn;
+            }
+            _ => {}
+        }
+    }
+
+    fn control(&mut self, first: u8, second: u8) {
+        // Control codes are repeated in case one is lost
+        if self.last_control == Some([first, second]) {
+            self.last_control = None;
+            return;
+        }
+        self.last_control = Some([first, second]);
+
+        self.in_cc1 = first & 0x08 == 0;
+        if !self.in_cc1 {
+            return;
+        }
+
+        match (first & 0x07, second) {
+            (0x04, 0x20..=0x2F) => self.command(second),
+            // Mid-row style change, shown as a space
+            (0x01, 0x20..=0x2F) => self.buffer().push(' '),
+            (0x01, 0x30..=0x3F) => {
+                let character = special_character(second);
+                self.buffer().push(character)
+            }
+            // Extended characters replace the standard one sent before them, they're shown as '?'
+            (0x02, 0x20..=0x3F) | (0x03, 0x20..=0x3F) => {
+                let buffer = self.buffer();
+                buffer.pop();
+                buffer.push('?');
+            }
+            // A preamble address code starts a row
+            (_, 0x40..=0x7F) => self.new_row(),
+            _ => {}
+        }
+    }
+
+    fn caption_pair(&mut self, pair: [u8; 2]) {
+        self.pairs.push((self.now, pair));
+
+        let (first, second) = (pair[0] & 0x7F, pair[1] & 0x7F);
+
+        match first {
+            0x00 => {}
+            0x10..=0x1F => self.control(first, second),
+            _ if self.in_cc1 => {
+                self.last_control = None;
+                for byte in [first, second].iter().filter(|byte| **byte >= 0x20) {
+                    let character = basic_character(*byte);
+                    self.buffer().push(character);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn xds_pair(&mut self, pair: [u8; 2]) {
+        let (first, second) = (pair[0] & 0x7F, pair[1] & 0x7F);
+
+        match first {
+            // Start of a packet
+            0x01..=0x0E if first % 2 == 1 => {
+                let key = (first, second);
+                self.xds_pending.insert(key, vec![first, second]);
+                self.xds_current = Some(key);
+            }
+            // Continuing a packet that was interrupted
+            0x02..=0x0E => {
+                let key = (first - 1, second);
+                self.xds_current = if self.xds_pending.contains_key(&key) {
+                    Some(key)
+                } else {
+                    None
+                };
+            }
+            // End of the packet and its checksum
+            0x0F => {
+                if let Some(mut packet) = self
+                    .xds_current
+                    .take()
+                    .and_then(|key| self.xds_pending.remove(&key))
+                {
+                    packet.extend(&[first, second]);
+
+                    // Everything including the checksum sums to zero
+                    let sum = packet.iter().fold(0u8, |sum, byte| sum.wrapping_add(*byte));
+                    if sum & 0x7F == 0 {
+                        self.xds.push(XdsPacket {
+                            time: self.now,
+                            class: packet[0],
+                            r#type: packet[1],
+                            data: packet[2..packet.len() - 2].to_vec(),
+                        });
+                    }
+                }
+            }
+            0x20..=0x7F => {
+                if let Some(packet) = self
+                    .xds_current
+                    .and_then(|key| self.xds_pending.get_mut(&key))
+                {
+                    packet.push(first);
+                    if second != 0 {
+                        packet.push(second);
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+
+    pub fn push(&mut self, record: &TyRecord) {
+        let pair = match record.data[..] {
+            [first, second, ..] => [first, second],
+            _ => [0, 0],
+        };
+
+        match record.r#type {
+            TyRecordType::Video => {
+                // Captions are timed by the frame they were sent with
+                for packet in self.demuxer.push(record) {
+                    if let Some(pts) = packet.pts {
+                        let first_pts = *self.first_pts.get_or_insert(pts);
+                        self.now = pts.saturating_sub(first_pts);
+                    }
+                }
+            }
+            TyRecordType::ClosedCaption => self.caption_pair(pair),
+            TyRecordType::Xds => self.xds_pair(pair),
+            _ => {}
+        }
+    }
+
+    pub fn finish(mut self) -> ClosedCaptions {
+        self.end_displayed();
+
+        ClosedCaptions {
+            captions: self.captions,
+            pairs: self.pairs,
+            xds: self.xds,
+        }
+    }
+}
+
+fn srt_time(ticks: u64) -> String {
+    let milliseconds = ticks / 90;
+
+    format!(
+        "{:02}:{:02}:{:02},{:03}",
+        milliseconds / 3_600_000,
+        milliseconds / 60_000 % 60,
+        milliseconds / 1000 % 60,
+        milliseconds % 1000
+    )
+}
+
+/// SMPTE drop frame timecode, which keeps 29.97 fps in step with the clock.
+fn scc_time(ticks: u64) -> String {
+    const FRAMES_PER_10_MINUTES: u64 = 17_982;
+    const FRAMES_PER_MINUTE: u64 = 1798;
+
+    let mut frame = ticks / TICKS_PER_FRAME;
+    let tens = frame / FRAMES_PER_10_MINUTES;
+    let remainder = frame % FRAMES_PER_10_MINUTES;
+
+    // Frames 0 and 1 are skipped at the start of every minute but every tenth
+    frame += 18 * tens;
+    if remainder > 1 {
+        frame += 2 * ((remainder - 2) / FRAMES_PER_MINUTE);
+    }
+
+    format!(
+        "{:02}:{:02}:{:02};{:02}",
+        frame / 108_000,
+        frame / 1800 % 60,
+        frame / 30 % 60,
+        frame % 30
+    )
+}
+
+fn io_error(source: std::io::Error) -> Error {
+    Error::Io {
+        sector: None,
+        source,
+    }
+}
+
+/// The captions and XDS data of a recording.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct ClosedCaptions {
+    pub captions: Vec<Caption>,
+    /// Every caption pair as sent, parity bits included, for SCC.
+    pub pairs: Vec<(u64, [u8; 2])>,
+    pub xds: Vec<XdsPacket>,
+}
+
+impl ClosedCaptions {
+    pub fn write_srt<W: Write>(&self, mut writer: W) -> Result<()> {
+        for (index, caption) in self.captions.iter().enumerate() {
+            writeln!(
+                writer,
+                "{}\n{} --> {}\n{}\n",
+                index + 1,
+                srt_time(caption.start),
+                srt_time(caption.end),
+                caption.text
+            )
+            .map_err(io_error)?;
+        }
+
+        Ok(())
+    }
+
+    /// Write Scenarist SCC, a line for each run of pairs broken up by padding.
+    pub fn write_scc<W: Write>(&self, mut writer: W) -> Result<()> {
+        write!(writer, "Scenarist_SCC V1.0").map_err(io_error)?;
+
+        let mut in_line = false;
+        for (time, pair) in self.pairs.iter() {
+            if pair[0] & 0x7F == 0 && pair[1] & 0x7F == 0 {
+                in_line = false;
+                continue;
+            }
+
+            if !in_line {
+                write!(writer, "\n\n{}\t", scc_time(*time)).map_err(io_error)?;
+                in_line = true;
+            } else {
+                write!(writer, " ").map_err(io_error)?;
+            }
+            write!(writer, "{:02x}{:02x}", pair[0], pair[1]).map_err(io_error)?;
+        }
+
+        writeln!(writer).map_err(io_error)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn record(r#type: TyRecordType, data: Vec<u8>) -> TyRecord {
+        TyRecord {
+            r#type,
+            subtype: 0,
+            size: data.len() as u32,
+            timestamp: 0,
+            data,
+            chunk: 0,
+        }
+    }
+
+    fn video(pts: u64) -> TyRecord {
+        let mut data = vec![];
+        crate::write_pes_header(&mut data, 0xE0, Some(pts), None, 0);
+        data.extend(&[0, 0, 1, 0x00]);
+        record(TyRecordType::Video, data)
+    }
+
+    #[test]
+    fn test_pop_on_caption() {
+        let mut decoder = ClosedCaptionDecoder::new();
+        let pairs: [[u8; 2]; 6] = [
+            [0x94, 0x20],
+            [0x94, 0x20],
+            [0xC8, 0xE9],
+            [0x94, 0x2F],
+            [0x94, 0x2F],
+            [0x80, 0x80],
+        ];
+
+        decoder.push(&video(900_000));
+        for pair in pairs.iter() {
+            decoder.push(&record(TyRecordType::ClosedCaption, pair.to_vec()));
+        }
+        decoder.push(&video(900_000 + 2 * 90_000));
+        decoder.push(&record(TyRecordType::ClosedCaption, vec![0x94, 0x2C]));
+
+        let captions = decoder.finish();
+        assert_eq!(
+            captions.captions,
+            vec![Caption {
+                start: 0,
+                end: 2 * 90_000,
+                text: String::from("Hi"),
+            }]
+        );
+
+        let mut srt = vec![];
+        captions.write_srt(&mut srt).unwrap();
+        assert_eq!(
+            String::from_utf8(srt).unwrap(),
+            "1\n00:00:00,000 --> 00:00:02,000\nHi\n\n"
+        );
+
+        let mut scc = vec![];
+        captions.write_scc(&mut scc).unwrap();
+        assert_eq!(
+            String::from_utf8(scc).unwrap(),
+            "Scenarist_SCC V1.0\n\n00:00:00;00\t9420 9420 c8e9 942f 942f\n\n00:00:01;29\t942c\n"
+        );
+    }
+
+    #[test]
+    fn test_xds_program_name() {
+        let mut decoder = ClosedCaptionDecoder::new();
+        let mut pairs = vec![[0x01, 0x03], [b'N', b'e'], [b'w', b's']];
+        let sum = pairs
+            .iter()
+            .flatten()
+            .fold(0x0Fu8, |sum, byte| sum.wrapping_add(*byte));
+        pairs.push([0x0F, (0x80 - (sum & 0x7F)) & 0x7F]);
+
+        for pair in pairs.iter() {
+            decoder.push(&record(TyRecordType::Xds, pair.to_vec()));
+        }
+
+        let captions = decoder.finish();
+        assert_eq!(captions.xds.len(), 1);
+        assert_eq!(captions.xds[0].program_name(), Some(String::from("News")));
+        assert_eq!(captions.xds[0].rating(), None);
+    }
+
+    #[test]
+    fn test_drop_frame_timecode() {
+        assert_eq!(scc_time(1799 * TICKS_PER_FRAME), "00:00:59;29");
+        assert_eq!(scc_time(1800 * TICKS_PER_FRAME), "00:01:00;02");
+        assert_eq!(scc_time(17_982 * TICKS_PER_FRAME), "00:10:00;00");
+    }
+}
@@ -0,0 +1,110 @@
+use ovit_util::{Error, LruCache, Result};
+use std::collections::HashMap;
+use tivo_media_file_system::MFSEntry;
+
+#[derive(Debug)]
+struct CachedDirectory {
+    entries: Vec<MFSEntry>,
+    /// Index into `entries` by name.
+    by_name: HashMap<String, usize>,
+}
+
+/// How many directories to keep, `/Program` and `/Recording/Active` alone can hold
+///  thousands of entries.
+const DIRECTORY_CACHE_CAPACITY: usize = 256;
+
+/// The most recently read directories' entries, by the directory's FSID. Drives are only
+///  ever read, so entries never go stale.
+#[derive(Debug)]
+pub(crate) struct DirectoryCache {
+    directories: LruCache<u32, CachedDirectory>,
+}
+
+impl Default for DirectoryCache {
+    fn default() -> DirectoryCache {
+        DirectoryCache {
+            directories: LruCache::new(DIRECTORY_CACHE_CAPACITY),
+        }
+    }
+}
+
+impl DirectoryCache {
+    pub(crate) fn entries(&mut self, fsid: u32) -> Option<&[MFSEntry]> {
+        self.directories
+            .get(&fsid)
+            .map(|directory| directory.entries.as_slice())
+    }
+
+    /// The entry called `name` in the directory `fsid`, `None` if the directory isn't cached.
+    pub(crate) fn find(&mut self, fsid: u32, name: &str) -> Option<Option<&MFSEntry>> {
+        self.directories.get(&fsid).map(|directory| {
+            directory
+                .by_name
+                .get(name)
+                .map(|index| &directory.entries[*index])
+        })
+    }
+
+    pub(crate) fn insert(&mut self, fsid: u32, entries: Vec<MFSEntry>) {
+        let by_name = entries
+            .iter()
+            .enumerate()
+            .map(|(index, entry)| (entry.name.clone(), index))
+            .collect();
+
+        self.directories
+            .insert(fsid, CachedDirectory { entries, by_name });
+    }
+}
+
+/// Resolve `path` to an FSID starting from the `root` directory, `find` looks up a name in a
+///  directory. Empty and `.` components are skipped and `..` goes back up, stopping at `root`.
+pub(crate) fn resolve_path<F>(root: u32, path: &str, mut find: F) -> Result<u32>
+where
+    F: FnMut(u32, &str) -> Result<Option<u32>>,
+{
+    let mut parents = vec![];
+    let mut fsid = root;
+
+    for component in path.split('/') {
+        match component {
+            "" | "." => {}
+            ".." => fsid = parents.pop().unwrap_or(root),
+            name => {
+                let child = find(fsid, name)?.ok_or(Error::NotFound("path"))?;
+                parents.push(fsid);
+                fsid = child;
+            }
+        }
+    }
+
+    Ok(fsid)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_resolve_path() {
+        let tree: HashMap<(u32, &str), u32> = [
+            ((1, "Recording"), 2),
+            ((2, "Active"), 3),
+            ((1, "Program"), 4),
+        ]
+        .iter()
+        .cloned()
+        .collect();
+        let find = |fsid, name: &str| Ok(tree.get(&(fsid, name)).copied());
+
+        assert_eq!(resolve_path(1, "/", find).unwrap(), 1);
+        assert_eq!(resolve_path(1, "/Recording/Active", find).unwrap(), 3);
+        assert_eq!(resolve_path(1, "Recording//./Active/", find).unwrap(), 3);
+        assert_eq!(
+            resolve_path(1, "/Recording/Active/../../Program", find).unwrap(),
+            4
+        );
+        assert_eq!(resolve_path(1, "/../Program", find).unwrap(), 4);
+        assert!(resolve_path(1, "/Recording/Missing", find).is_err());
+    }
+}
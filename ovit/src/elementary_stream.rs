@@ -0,0 +1,316 @@
+use std::collections::HashMap;
+use tivo_media_file_system::{TyRecord, TyRecordType};
+
+const PES_START_CODE: [u8; 3] = [0x00, 0x00, 0x01];
+/// How far the system clock may trail the timestamps of the data being sent, 700ms.
+const MAX_DECODER_DELAY: u64 = 63_000;
+pub(crate) const TIMESTAMP_MASK: u64 = 0x1_FFFF_FFFF;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ElementaryStreamKind {
+    Video,
+    MpegAudio,
+    Ac3Audio,
+}
+
+impl ElementaryStreamKind {
+    /// The PES stream ID TiVo uses for this kind of stream.
+    pub fn stream_id(self) -> u8 {
+        match self {
+            ElementaryStreamKind::Video => 0xE0,
+            ElementaryStreamKind::MpegAudio => 0xC0,
+            ElementaryStreamKind::Ac3Audio => 0xBD,
+        }
+    }
+}
+
+/// A piece of an elementary stream, with the timestamps of the access unit starting in it.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ElementaryPacket {
+    pub kind: ElementaryStreamKind,
+    /// 33-bit, 90 kHz presentation timestamp.
+    pub pts: Option<u64>,
+    /// 33-bit, 90 kHz decoding timestamp, only sent when it differs from the PTS.
+    pub dts: Option<u64>,
+    /// When the TiVo received the record the data came from, in 90 kHz units.
+    pub record_time: Option<u64>,
+    pub data: Vec<u8>,
+}
+
+/// Convert the nanosecond timestamp of a tyStream record to 90 kHz units, `None` if the
+///  record wasn't stamped.
+fn record_time(record: &TyRecord) -> Option<u64> {
+    match record.timestamp {
+        0 => None,
+        timestamp => Some((u128::from(timestamp) * 9 / 100_000) as u64),
+    }
+}
+
+fn read_timestamp(bytes: &[u8]) -> u64 {
+    (u64::from(bytes[0] & 0x0E) << 29)
+        | (u64::from(bytes[1]) << 22)
+        | (u64::from(bytes[2] & 0xFE) << 14)
+        | (u64::from(bytes[3]) << 7)
+        | (u64::from(bytes[4]) >> 1)
+}
+
+/// A PES header found in a record.
+#[derive(Debug, PartialEq)]
+enum PesHeader {
+    /// Where the header starts and ends, along with its timestamps.
+    Complete {
+        start: usize,
+        end: usize,
+        pts: Option<u64>,
+        dts: Option<u64>,
+    },
+    /// A header, or what may be the start of one, running off the end of the record.
+    Incomplete { start: usize },
+}
+
+/// Find a PES header for `stream_id` in `data`.
+fn find_pes_header(data: &[u8], stream_id: u8) -> Option<PesHeader> {
+    let start_code = [
+        PES_START_CODE[0],
+        PES_START_CODE[1],
+        PES_START_CODE[2],
+        stream_id,
+    ];
+
+    let start = match data.windows(4).position(|window| window == start_code) {
+        Some(start) => start,
+        None => {
+            // The start code itself may be split between records
+            let length = (1..4)
+                .rev()
+                .find(|length| data.ends_with(&start_code[..*length]))?;
+            return Some(PesHeader::Incomplete {
+                start: data.len() - length,
+            });
+        }
+    };
+    let header = &data[start..];
+
+    if header.len() < 9 {
+        return Some(PesHeader::Incomplete { start });
+    }
+
+    let flags = header[7];
+    let end = 9 + header[8] as usize;
+
+    if header.len() < end {
+        return Some(PesHeader::Incomplete { start });
+    }
+
+    let pts = if flags & 0x80 != 0 && end >= 14 {
+        Some(read_timestamp(&header[9..14]))
+    } else {
+        None
+    };
+    let dts = if flags & 0x40 != 0 && end >= 19 {
+        Some(read_timestamp(&header[14..19]))
+    } else {
+        None
+    };
+
+    Some(PesHeader::Complete {
+        start,
+        end: start + end,
+        pts,
+        dts,
+    })
+}
+
+/// Turns TiVo records back into elementary streams, stripping out the TiVo's PES headers
+///  and tagging the data that follows them with their timestamps.
+#[derive(Debug, Clone, Default)]
+pub struct ElementaryStreamDemuxer {
+    /// Timestamps from a header that hasn't been followed by any data yet.
+    pending: HashMap<ElementaryStreamKind, (Option<u64>, Option<u64>)>,
+    /// The start of a header cut off at the end of the last record.
+    unfinished: HashMap<ElementaryStreamKind, Vec<u8>>,
+}
+
+impl ElementaryStreamDemuxer {
+    pub fn new() -> ElementaryStreamDemuxer {
+        ElementaryStreamDemuxer::default()
+    }
+
+    fn packet(
+        &mut self,
+        kind: ElementaryStreamKind,
+        timestamps: (Option<u64>, Option<u64>),
+        record_time: Option<u64>,
+        data: &[u8],
+    ) -> Option<ElementaryPacket> {
+        let (pts, dts) = match timestamps {
+            (None, None) => self.pending.remove(&kind).unwrap_or((None, None)),
+            timestamps => timestamps,
+        };
+
+        if data.is_empty() {
+            if pts.is_some() {
+                self.pending.insert(kind, (pts, dts));
+            }
+            return None;
+        }
+
+        Some(ElementaryPacket {
+            kind,
+            pts,
+            dts: if dts == pts { None } else { dts },
+            record_time,
+            data: data.to_vec(),
+        })
+    }
+
+    /// The elementary stream data in a record, nothing for records that aren't audio or video.
+    pub fn push(&mut self, record: &TyRecord) -> Vec<ElementaryPacket> {
+        let kind = match record.r#type {
+            TyRecordType::Video => ElementaryStreamKind::Video,
+            TyRecordType::Audio if record.is_ac3() => ElementaryStreamKind::Ac3Audio,
+            TyRecordType::Audio => ElementaryStreamKind::MpegAudio,
+            _ => return vec![],
+        };
+
+        let record_time = record_time(record);
+        let mut packets = vec![];
+        let mut buffer = self.unfinished.remove(&kind).unwrap_or_default();
+        buffer.extend(&record.data);
+        let mut data: &[u8] = &buffer;
+        let mut timestamps = (None, None);
+
+        loop {
+            match find_pes_header(data, kind.stream_id()) {
+                Some(PesHeader::Complete {
+                    start,
+                    end,
+                    pts,
+                    dts,
+                }) => {
+                    packets.extend(self.packet(kind, timestamps, record_time, &data[..start]));
+
+                    timestamps = (pts, dts);
+                    data = &data[end..];
+
+                    // AC-3 in a private stream may carry a DVD style substream header
+                    if kind == ElementaryStreamKind::Ac3Audio
+                        && data.len() >= 4
+                        && data[0] & 0xF8 == 0x80
+                    {
+                        data = &data[4..];
+                    }
+                }
+                // Finished off by the next record of the same kind
+                Some(PesHeader::Incomplete { start }) => {
+                    packets.extend(self.packet(kind, timestamps, record_time, &data[..start]));
+                    self.unfinished.insert(kind, data[start..].to_vec());
+                    break;
+                }
+                None => {
+                    packets.extend(self.packet(kind, timestamps, record_time, data));
+                    break;
+                }
+            }
+        }
+
+        packets
+    }
+}
+
+/// The 90 kHz system clock a muxer stamps its output with, the SCR of a program stream
+///  or the PCR of a transport stream.
+#[derive(Debug, Clone)]
+pub(crate) struct SystemClock {
+    next: Option<u64>,
+    bytes_per_second: u64,
+    /// How far the PES timestamps run ahead of the record times, from the first packet
+    ///  that has both.
+    offset: Option<i64>,
+}
+
+impl SystemClock {
+    pub(crate) fn new(bytes_per_second: u64) -> SystemClock {
+        SystemClock {
+            next: None,
+            bytes_per_second,
+            offset: None,
+        }
+    }
+
+    /// The clock at the start of `length` bytes carrying `packet`. It follows the times the
+    ///  TiVo received each record, moved into the time base of the PES timestamps, falling
+    ///  back to the timestamps themselves for unstamped records. It has to increase
+    ///  monotonically but mustn't fall so far behind the data's timestamps that the
+    ///  decoder's buffers overflow.
+    pub(crate) fn tick(&mut self, packet: &ElementaryPacket, length: usize) -> u64 {
+        let timestamp = packet.dts.or(packet.pts);
+
+        if let (None, Some(timestamp), Some(record_time)) =
+            (self.offset, timestamp, packet.record_time)
+        {
+            self.offset = Some(timestamp as i64 - record_time as i64);
+        }
+
+        let target = match (self.offset, packet.record_time) {
+            (Some(offset), Some(record_time)) => Some((record_time as i64 + offset).max(0) as u64),
+            _ => timestamp,
+        };
+
+        let clock = match (self.next, target) {
+            (Some(next), Some(target)) => next.max(target.saturating_sub(MAX_DECODER_DELAY)),
+            (Some(next), None) => next,
+            (None, Some(target)) => target.saturating_sub(MAX_DECODER_DELAY),
+            (None, None) => 0,
+        };
+
+        // Time taken to deliver these bytes at the mux rate
+        self.next = Some(clock + length as u64 * 90_000 / self.bytes_per_second);
+
+        clock & TIMESTAMP_MASK
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn video_record(data: &[u8]) -> TyRecord {
+        TyRecord {
+            r#type: TyRecordType::Video,
+            subtype: 0,
+            size: data.len() as u32,
+            timestamp: 0,
+            data: data.to_vec(),
+            chunk: 0,
+        }
+    }
+
+    #[test]
+    fn test_header_split_between_records() {
+        // A PES header with a PTS of 90000
+        let header = [
+            0x00, 0x00, 0x01, 0xE0, 0x00, 0x00, 0x80, 0x80, 0x05, 0x21, 0x00, 0x05, 0xBF, 0x21,
+        ];
+        let mut demuxer = ElementaryStreamDemuxer::new();
+
+        for split in [2, 6, 11] {
+            let first = [&[0xAA, 0xAA], &header[..split]].concat();
+            let second = [&header[split..], &[0xBB, 0xBB]].concat();
+
+            let packets = demuxer.push(&video_record(&first));
+            assert_eq!(packets.len(), 1);
+            assert_eq!(packets[0].data, [0xAA, 0xAA]);
+
+            let packets = demuxer.push(&video_record(&second));
+            assert_eq!(packets.len(), 1);
+            assert_eq!(packets[0].pts, Some(90_000));
+            assert_eq!(packets[0].data, [0xBB, 0xBB]);
+        }
+
+        // Zeros at the end of a record that don't start a header are still data
+        demuxer.push(&video_record(&[0xCC, 0x00, 0x00]));
+        let packets = demuxer.push(&video_record(&[0xDD]));
+        assert_eq!(packets[0].data, [0x00, 0x00, 0xDD]);
+    }
+}
@@ -2,16 +2,41 @@ extern crate apple_partition_map;
 extern crate rayon;
 extern crate tivo_media_file_system;
 
+mod closed_captions;
+pub use closed_captions::*;
+
+mod directory_cache;
+pub(crate) use directory_cache::*;
+
+mod elementary_stream;
+pub use elementary_stream::*;
+
+mod program_stream;
+pub use program_stream::*;
+
+mod recording_reader;
+pub use recording_reader::*;
+
+mod recordings;
+pub use recordings::*;
+
+mod transport_stream;
+pub use transport_stream::*;
+
 use apple_partition_map::ApplePartitionMap;
 use log::{info, warn};
+use ovit_util::{BlockSource, ByteSwappedSource, CachedSource, FileSource, MmapSource};
 use std::convert::TryInto;
-use std::fs::File;
-use std::io::prelude::*;
-use std::iter::FromIterator;
+use std::io::Write;
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
 use tivo_media_file_system::{
-    MFSINode, MFSVolumeHeader, MFSVolumes, MFSZone, MFSZoneMap, MFSZoneType, INODE_CHAINED_FLAG,
+    mfs_checksum_fallback, MFSDbError, MFSDbObject, MFSEntry, MFSFileReader, MFSFreeExtent,
+    MFSINode, MFSINodeType, MFSSchema, MFSVolumeHeader, MFSVolumes, MFSZone, MFSZoneMap,
+    MFSZoneType, Recording, RecordingPart, TyStream, INODE_CHAINED_FLAG,
 };
 
+pub use ovit_util::{CacheOptions, CacheStats, Error, LruCache, Result};
+
 pub const TIVO_BOOT_MAGIC: u16 = 0x1492;
 pub const TIVO_BOOT_AMIGC: u16 = 0x9214;
 
@@ -22,46 +47,110 @@ fn fsid_hash(fsid: u32, size: u32) -> u32 {
     fsid.wrapping_mul(FSID_HASH) & (size)
 }
 
+/// Container formats recordings can be extracted to.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RecordingFormat {
+    /// MPEG-2 program stream, `.mpg`.
+    ProgramStream,
+    /// MPEG-2 transport stream, `.ts`.
+    TransportStream,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct TivoDriveOptions {
+    /// Cache sectors read from the drive, `None` reads straight from the source.
+    ///  Memory backed sources are never cached, unless they need byte swapping.
+    pub cache: Option<CacheOptions>,
+    /// Memory map disk images instead of reading them through a file cursor.
+    pub mmap: bool,
+}
+
+impl Default for TivoDriveOptions {
+    fn default() -> TivoDriveOptions {
+        TivoDriveOptions {
+            cache: Some(CacheOptions::default()),
+            mmap: false,
+        }
+    }
+}
+
 #[derive(Debug)]
 pub struct TivoDrive {
-    pub source_file: File,
+    pub source: Arc<dyn BlockSource>,
+    cache: Option<Arc<CachedSource<Arc<dyn BlockSource>>>>,
     pub partition_map: ApplePartitionMap,
+    /// The volume header in use, the backup is used when the primary is damaged.
     pub volume_header: MFSVolumeHeader,
+    pub backup_volume_header: Option<MFSVolumeHeader>,
     pub raw_zonemap: MFSZoneMap,
     pub volumes: MFSVolumes,
     pub zonemap: Vec<MFSZone>,
     pub is_byte_swapped: bool,
     inode_count: u32,
+    /// Locked only while looking up or storing entries, never while reading the drive.
+    directory_cache: Mutex<DirectoryCache>,
 }
 
 impl TivoDrive {
-    fn check_byte_order(file: &mut File) -> Result<bool, String> {
-        let mut buffer = [0; 2];
-        match file.read_exact(&mut buffer) {
-            Ok(_) => {}
-            Err(_) => {
-                return Err("Could not read first two bytes from file".to_string());
-            }
-        };
+    fn check_byte_order(source: &dyn BlockSource) -> Result<bool> {
+        let buffer = source.read_bytes(0, 2)?;
 
         match u16::from_be_bytes(buffer[0..2].try_into().unwrap()) {
             TIVO_BOOT_MAGIC => Ok(false),
             TIVO_BOOT_AMIGC => Ok(true),
-            _ => Err("Not a TiVo Drive".to_string()),
+            magic => Err(Error::BadMagic {
+                structure: "boot sector",
+                expected: &[TIVO_BOOT_MAGIC as u32, TIVO_BOOT_AMIGC as u32],
+                found: u32::from(magic),
+            }),
         }
     }
 
-    pub fn from_disk_image(path: &str) -> Result<TivoDrive, String> {
-        let mut file = match File::open(path) {
-            Ok(file) => file,
-            Err(_) => {
-                return Err("Couldn't open drive".to_string());
-            }
+    pub fn from_disk_image(path: &str) -> Result<TivoDrive> {
+        TivoDrive::from_disk_image_with_options(path, TivoDriveOptions::default())
+    }
+
+    pub fn from_disk_image_with_options(
+        path: &str,
+        options: TivoDriveOptions,
+    ) -> Result<TivoDrive> {
+        let source: Arc<dyn BlockSource> = if options.mmap {
+            Arc::new(MmapSource::open(path)?)
+        } else {
+            Arc::new(FileSource::open(path)?)
         };
 
-        let is_byte_swapped = TivoDrive::check_byte_order(&mut file)?;
+        TivoDrive::from_source_with_options(source, options)
+    }
+
+    pub fn from_source(source: Arc<dyn BlockSource>) -> Result<TivoDrive> {
+        TivoDrive::from_source_with_options(source, TivoDriveOptions::default())
+    }
+
+    pub fn from_source_with_options(
+        source: Arc<dyn BlockSource>,
+        options: TivoDriveOptions,
+    ) -> Result<TivoDrive> {
+        let is_byte_swapped = TivoDrive::check_byte_order(&*source)?;
+
+        let source: Arc<dyn BlockSource> = if is_byte_swapped {
+            Arc::new(ByteSwappedSource::new(source))
+        } else {
+            source
+        };
+
+        // Cache the already byte swapped sectors so swapping only happens once per read from disk
+        let cache = options
+            .cache
+            .filter(|_| !source.is_memory_backed())
+            .map(|cache_options| Arc::new(CachedSource::new(source.clone(), cache_options)));
+
+        let source: Arc<dyn BlockSource> = match &cache {
+            Some(cache) => cache.clone(),
+            None => source,
+        };
 
-        let partition_map = ApplePartitionMap::read_from_file(&mut file, is_byte_swapped)?;
+        let partition_map = ApplePartitionMap::read_from_source(&*source)?;
 
         let mfs_partitions: MFSVolumes = MFSVolumes::new(&partition_map);
 
@@ -69,129 +158,301 @@ impl TivoDrive {
             .partitions
             .iter()
             .find(|partition| partition.r#type == "MFS")
-            .unwrap();
-
-        let volume_header =
-            MFSVolumeHeader::from_partition(app_region, &mut file, is_byte_swapped)?;
+            .ok_or(Error::NotFound("MFS partition"))?;
+
+        // The backup is always read so it can be reported on, even when the primary is fine
+        let backup = MFSVolumeHeader::backup_from_partition(app_region, &*source);
+        let backup_volume_header = backup.as_ref().ok().cloned();
+        let volume_header = mfs_checksum_fallback(
+            u64::from(app_region.starting_sector),
+            MFSVolumeHeader::from_partition(app_region, &*source),
+            || backup,
+        )?;
 
         let raw_zonemap = MFSZoneMap::new(
-            path,
+            Arc::clone(&source),
             &mfs_partitions,
             volume_header.next_zonemap_sector,
             volume_header.next_zonemap_backup_sector,
-            volume_header.next_zonemap_partition_size as usize,
-            is_byte_swapped,
+            volume_header.is_64_bit,
         )?;
 
         // Messy but fine
-        let zonemap: Vec<MFSZone> = Vec::from_iter(MFSZoneMap::new(
-            path,
-            &mfs_partitions,
-            volume_header.next_zonemap_sector,
-            volume_header.next_zonemap_backup_sector,
-            volume_header.next_zonemap_partition_size as usize,
-            is_byte_swapped,
-        )?);
+        let zonemap = raw_zonemap.clone().collect::<Result<Vec<MFSZone>>>()?;
 
         let inode_count = zonemap
             .iter()
             .filter(|zone| zone.r#type == MFSZoneType::INode)
-            .map(|zone| zone.size)
-            .sum::<u32>();
+            .map(|zone| zone.size / 2)
+            .sum::<u64>()
+            .try_into()
+            .unwrap_or(u32::MAX);
 
         Ok(TivoDrive {
-            source_file: file,
+            source,
+            cache,
             partition_map,
             volume_header,
+            backup_volume_header,
             volumes: mfs_partitions,
             raw_zonemap,
             zonemap,
             is_byte_swapped,
             inode_count,
+            directory_cache: Mutex::default(),
         })
     }
 
-    fn sector_for_inode(&mut self, inode: u32, backup: bool) -> u64 {
-        let inode_count = self.inode_count;
-        let sector: u64 = u64::from(inode) * 2;
+    /// Hit and miss counts for the sector cache, if the drive was opened with one.
+    pub fn cache_stats(&self) -> Option<CacheStats> {
+        self.cache.as_ref().map(|cache| cache.stats())
+    }
+
+    /// Whether `sector` (in MFS volume space) is allocated, according to the zone bitmaps.
+    ///  `None` if the sector isn't part of any zone.
+    pub fn is_sector_allocated(&self, sector: u64) -> Option<bool> {
+        self.zonemap
+            .iter()
+            .find_map(|zone| zone.is_sector_allocated(sector))
+    }
+
+    /// Free runs of sectors across every zone of the given type.
+    pub fn free_extents(&self, zone_type: MFSZoneType) -> Vec<MFSFreeExtent> {
+        self.zonemap
+            .iter()
+            .filter(|zone| zone.r#type == zone_type)
+            .flat_map(|zone| zone.free_extents())
+            .collect()
+    }
+
+    /// A reader over the contents of `inode`.
+    pub fn file_reader(&self, inode: &MFSINode) -> MFSFileReader {
+        MFSFileReader::new(Arc::clone(&self.source), &self.volumes, inode)
+    }
+
+    /// The records of a recording's `Stream` inode.
+    pub fn ty_stream(&self, inode: &MFSINode) -> Result<TyStream> {
+        if inode.r#type != MFSINodeType::Stream {
+            return Err(Error::NotFound("stream inode"));
+        }
+
+        TyStream::new(Arc::clone(&self.source), &self.volumes, inode)
+    }
+
+    /// The `Stream` inodes holding a recording, in order. `fsid` may be a `Recording` object
+    ///  or a single stream.
+    pub fn recording_streams(&self, fsid: u32) -> Result<Vec<MFSINode>> {
+        let inode = self.get_inode_from_fsid(fsid)?;
+
+        match inode.r#type {
+            MFSINodeType::Stream => return Ok(vec![inode]),
+            MFSINodeType::Db => {}
+            _ => return Err(Error::NotFound("recording")),
+        }
+
+        let object = inode.get_db_object(&*self.source, &self.volumes, MFSSchema::bundled())?;
+        let recording = Recording::from_object(&object)?;
+
+        self.recording_object_streams(&object, &recording)
+    }
+
+    /// The `Stream` inodes holding `recording`, which was decoded from `object`.
+    pub fn recording_object_streams(
+        &self,
+        object: &MFSDbObject,
+        recording: &Recording,
+    ) -> Result<Vec<MFSINode>> {
+        let mut parts = recording
+            .part
+            .iter()
+            .filter_map(|id| object.subobject(*id))
+            .map(RecordingPart::from_subobject)
+            .collect::<std::result::Result<Vec<RecordingPart>, MFSDbError>>()?;
+
+        // Older recordings don't reference their parts from the recording itself
+        if parts.is_empty() {
+            parts = object
+                .subobjects
+                .iter()
+                .filter(|subobject| subobject.r#type == RecordingPart::TYPE_ID)
+                .map(RecordingPart::from_subobject)
+                .collect::<std::result::Result<Vec<RecordingPart>, MFSDbError>>()?;
+        }
+
+        if parts.is_empty() {
+            return Err(Error::NotFound("recording part"));
+        }
+
+        parts.sort_by_key(|part| part.begin);
+
+        parts
+            .iter()
+            .map(|part| self.get_inode_from_fsid(part.file))
+            .collect()
+    }
+
+    /// The parts of the recording `fsid` joined into one stream.
+    pub fn recording_reader(&self, fsid: u32) -> Result<RecordingReader> {
+        let streams = self
+            .recording_streams(fsid)?
+            .iter()
+            .map(|inode| self.ty_stream(inode))
+            .collect::<Result<Vec<TyStream>>>()?;
 
-        if inode >= inode_count {
-            return 0;
+        Ok(RecordingReader::new(streams))
+    }
+
+    /// Write the recording `fsid` to `writer` as an MPEG-2 program or transport stream,
+    ///  along with how many unreadable chunks were left out.
+    pub fn extract_recording<W: Write>(
+        &self,
+        fsid: u32,
+        writer: W,
+        format: RecordingFormat,
+    ) -> Result<(W, u64)> {
+        let reader = self.recording_reader(fsid)?;
+
+        match format {
+            RecordingFormat::ProgramStream => {
+                let mut muxer = ProgramStreamMuxer::new(writer);
+                let skipped = reader.for_each_record(|record| muxer.write_record(record))?;
+                Ok((muxer.finish()?, skipped))
+            }
+            RecordingFormat::TransportStream => {
+                let mut muxer = TransportStreamMuxer::new(writer);
+                let skipped = reader.for_each_record(|record| muxer.write_record(record))?;
+                Ok((muxer.finish()?, skipped))
+            }
         }
+    }
+
+    /// Decode the closed captions and XDS data of the recording `fsid`, along with how many
+    ///  unreadable chunks were left out.
+    pub fn closed_captions(&self, fsid: u32) -> Result<(ClosedCaptions, u64)> {
+        let mut decoder = ClosedCaptionDecoder::new();
+        let skipped = self.recording_reader(fsid)?.for_each_record(|record| {
+            decoder.push(record);
+            Ok(())
+        })?;
+
+        Ok((decoder.finish(), skipped))
+    }
 
+    /// Sector of `inode` in MFS volume space, the redundant copy is in the sector after it.
+    fn sector_for_inode(&self, inode: u32) -> Result<u64> {
+        let mut inode = u64::from(inode);
+
+        // Each inode takes two sectors, itself and its copy
         for zone in self
             .zonemap
             .iter()
             .filter(|zone| zone.r#type == MFSZoneType::INode)
         {
-            if sector < zone.size.into() {
-                if backup {
-                    return sector + zone.backup_sector + 1;
-                } else {
-                    return sector + zone.first_sector;
-                }
+            let zone_inodes = zone.size / 2;
+
+            if inode < zone_inodes {
+                return Ok(zone.first_sector + inode * 2);
             }
+
+            inode -= zone_inodes;
         }
 
-        0
+        Err(Error::NotFound("inode zone"))
     }
 
-    pub fn get_inode_from_fsid(&mut self, queried_fsid: u32) -> Result<MFSINode, String> {
-        let inode = fsid_hash(queried_fsid, self.inode_count - 1);
-        let sector = self.sector_for_inode(inode, false);
-
-        let volume = self.volumes.find_sector_volume(sector);
+    fn read_inode(&self, inode: u32) -> Result<MFSINode> {
+        let sector = self.sector_for_inode(inode)?;
+        let volume = self.volumes.find_sector_volume(sector)?;
 
-        let hashed_inode = MFSINode::from_file_at_sector(
-            &mut self.source_file,
+        MFSINode::from_source_at_sector_with_fallback(
+            &*self.source,
             volume.disk_sector.into(),
             sector - u64::from(volume.sector_start),
-            self.is_byte_swapped,
-        )?;
+            self.volume_header.is_64_bit,
+        )
+    }
 
-        if hashed_inode.fsid == queried_fsid {
-            return Ok(hashed_inode);
+    fn directory_cache(&self) -> MutexGuard<'_, DirectoryCache> {
+        // The cache is only ever added to, so it's still sound after a panic
+        self.directory_cache
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Entries of the directory `fsid`, only read from the drive the first time they're asked for.
+    pub fn directory_entries(&self, fsid: u32) -> Result<Vec<MFSEntry>> {
+        if let Some(entries) = self.directory_cache().entries(fsid) {
+            return Ok(entries.to_vec());
         }
 
-        warn!("Couldn't find INode for FSID {} using hash.", queried_fsid);
+        let inode = self.get_inode_from_fsid(fsid)?;
+        if inode.r#type != MFSINodeType::Dir {
+            return Err(Error::NotFound("directory"));
+        }
 
-        let sector = self.sector_for_inode(inode, true);
+        let entries = inode.get_entries_from_directory(&*self.source, &self.volumes)?;
+        self.directory_cache().insert(fsid, entries.clone());
 
-        let volume = self.volumes.find_sector_volume(sector);
+        Ok(entries)
+    }
 
-        let hashed_inode = MFSINode::from_file_at_sector(
-            &mut self.source_file,
-            volume.disk_sector.into(),
-            sector - u64::from(volume.sector_start),
-            self.is_byte_swapped,
-        )?;
+    /// The entry called `name` in the directory `fsid`.
+    pub fn find_entry(&self, fsid: u32, name: &str) -> Result<Option<MFSEntry>> {
+        if let Some(entry) = self.directory_cache().find(fsid, name) {
+            return Ok(entry.cloned());
+        }
+
+        let entries = self.directory_entries(fsid)?;
+
+        Ok(entries.into_iter().find(|entry| entry.name == name))
+    }
+
+    /// FSID of the file at `path`, which is relative to the root directory.
+    pub fn lookup_fsid(&self, path: &str) -> Result<u32> {
+        let root = self.volume_header.root_fsid;
+
+        resolve_path(root, path, |fsid, name| {
+            Ok(self.find_entry(fsid, name)?.map(|entry| entry.fsid))
+        })
+    }
+
+    /// The inode at `path`, such as `/Recording/Active`.
+    pub fn lookup(&self, path: &str) -> Result<MFSINode> {
+        let fsid = self.lookup_fsid(path)?;
+
+        self.get_inode_from_fsid(fsid)
+    }
+
+    /// Entries of the directory at `path`.
+    pub fn read_dir(&self, path: &str) -> Result<Vec<MFSEntry>> {
+        let fsid = self.lookup_fsid(path)?;
+
+        self.directory_entries(fsid)
+    }
+
+    pub fn get_inode_from_fsid(&self, queried_fsid: u32) -> Result<MFSINode> {
+        if self.inode_count == 0 {
+            return Err(Error::NotFound("inode zone"));
+        }
+
+        let inode = fsid_hash(queried_fsid, self.inode_count - 1);
+
+        let hashed_inode = self.read_inode(inode)?;
 
         if hashed_inode.fsid == queried_fsid {
             return Ok(hashed_inode);
         }
 
-        warn!(
-            "Couldn't find INode for FSID {} using hash at backup location.",
-            queried_fsid
-        );
+        warn!("Couldn't find INode for FSID {} using hash.", queried_fsid);
 
-        let inode_id_base = hashed_inode.inode;
         let mut current_inode_id = inode;
         let mut current_inode = hashed_inode;
 
-        while current_inode.fsid != queried_fsid
-            && current_inode.flags == INODE_CHAINED_FLAG
-            && ((current_inode_id + 1) % self.inode_count) != inode_id_base
+        while current_inode.flags & INODE_CHAINED_FLAG != 0
+            && (current_inode_id + 1) % self.inode_count != inode
         {
-            current_inode_id += 1;
-            let sector = self.sector_for_inode(current_inode_id, false).into();
-
-            current_inode = MFSINode::from_file_at_sector(
-                &mut self.source_file,
-                self.volumes.find_sector_volume(sector).disk_sector.into(),
-                sector,
-                self.is_byte_swapped,
-            )?;
+            current_inode_id = (current_inode_id + 1) % self.inode_count;
+            current_inode = self.read_inode(current_inode_id)?;
 
             if current_inode.fsid == queried_fsid {
                 info!(
@@ -207,18 +468,6 @@ impl TivoDrive {
             queried_fsid
         );
 
-        // Err(format!("Could not get INode for FSID {}", queried_fsid))
-
-        info!(
-            "Returning the original hashed INode even though the FSID {} was not found.",
-            queried_fsid
-        );
-
-        Ok(MFSINode::from_file_at_sector(
-            &mut self.source_file,
-            self.volumes.find_sector_volume(sector).disk_sector.into(),
-            sector.into(),
-            self.is_byte_swapped,
-        )?)
+        Err(Error::FsidNotFound(queried_fsid))
     }
 }
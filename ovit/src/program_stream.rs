@@ -0,0 +1,326 @@
+use crate::{
+    ElementaryPacket, ElementaryStreamDemuxer, ElementaryStreamKind, SystemClock, TIMESTAMP_MASK,
+};
+use ovit_util::{Error, Result};
+use std::io::Write;
+use tivo_media_file_system::TyRecord;
+
+const PACK_START_CODE: [u8; 4] = [0x00, 0x00, 0x01, 0xBA];
+const SYSTEM_HEADER_START_CODE: [u8; 4] = [0x00, 0x00, 0x01, 0xBB];
+const PROGRAM_END_CODE: [u8; 4] = [0x00, 0x00, 0x01, 0xB9];
+
+/// The DVD maximum, in units of 50 bytes per second.
+const MUX_RATE: u64 = 25_200;
+/// Packs, headers included, fit in a 2048 byte sector.
+const PACK_SIZE: usize = 2048;
+const PACK_HEADER_LENGTH: usize = 14;
+/// A PES header with both a PTS and a DTS.
+const MAX_PES_HEADER_LENGTH: usize = 19;
+/// Substream ID for the first AC-3 stream in private stream 1.
+const AC3_SUBSTREAM_ID: u8 = 0x80;
+
+pub(crate) fn write_timestamp(buffer: &mut Vec<u8>, prefix: u8, timestamp: u64) {
+    let timestamp = timestamp & TIMESTAMP_MASK;
+
+    buffer.extend(&[
+        (prefix << 4) | ((timestamp >> 29) as u8 & 0x0E) | 0x01,
+        (timestamp >> 22) as u8,
+        ((timestamp >> 14) as u8 & 0xFE) | 0x01,
+        (timestamp >> 7) as u8,
+        ((timestamp << 1) as u8 & 0xFE) | 0x01,
+    ]);
+}
+
+/// Write a PES packet header for `payload_length` bytes of data.
+pub(crate) fn write_pes_header(
+    buffer: &mut Vec<u8>,
+    stream_id: u8,
+    pts: Option<u64>,
+    dts: Option<u64>,
+    payload_length: usize,
+) {
+    let (flags, header_length) = match (pts, dts) {
+        (Some(_), Some(_)) => (0xC0, 10),
+        (Some(_), None) => (0x80, 5),
+        _ => (0x00, 0),
+    };
+    // Video packets too long to describe have a length of 0
+    let packet_length = match 3 + header_length + payload_length {
+        length if length > 0xFFFF => 0,
+        length => length as u16,
+    };
+
+    buffer.extend(&[0x00, 0x00, 0x01, stream_id]);
+    buffer.extend(&packet_length.to_be_bytes());
+    // '10', not scrambled, data aligned, original
+    buffer.extend(&[0x85, flags, header_length as u8]);
+
+    match (pts, dts) {
+        (Some(pts), Some(dts)) => {
+            write_timestamp(buffer, 0x3, pts);
+            write_timestamp(buffer, 0x1, dts);
+        }
+        (Some(pts), None) => write_timestamp(buffer, 0x2, pts),
+        _ => {}
+    }
+}
+
+/// Muxes the records of TiVo streams into an MPEG-2 program stream. Cloning a muxer with
+///  an empty writer saves its place, the clone carries on exactly as the original would.
+#[derive(Debug, Clone)]
+pub struct ProgramStreamMuxer<W: Write> {
+    writer: W,
+    demuxer: ElementaryStreamDemuxer,
+    scr: SystemClock,
+    wrote_system_header: bool,
+}
+
+impl<W: Write> ProgramStreamMuxer<W> {
+    pub fn new(writer: W) -> ProgramStreamMuxer<W> {
+        ProgramStreamMuxer {
+            writer,
+            demuxer: ElementaryStreamDemuxer::new(),
+            scr: SystemClock::new(MUX_RATE * 50),
+            wrote_system_header: false,
+        }
+    }
+
+    /// The writer being muxed into, for taking the output as it's produced.
+    pub fn get_mut(&mut self) -> &mut W {
+        &mut self.writer
+    }
+
+    fn write_all(&mut self, buffer: &[u8]) -> Result<()> {
+        self.writer.write_all(buffer).map_err(|source| Error::Io {
+            sector: None,
+            source,
+        })
+    }
+
+    fn pack_header(&self, buffer: &mut Vec<u8>, scr: u64) {
+        buffer.extend(&PACK_START_CODE);
+        buffer.extend(&[
+            0x44 | ((scr >> 27) as u8 & 0x38) | ((scr >> 28) as u8 & 0x03),
+            (scr >> 20) as u8,
+            ((scr >> 12) as u8 & 0xF8) | 0x04 | ((scr >> 13) as u8 & 0x03),
+            (scr >> 5) as u8,
+            ((scr << 3) as u8 & 0xF8) | 0x04,
+            0x01,
+            (MUX_RATE >> 14) as u8,
+            (MUX_RATE >> 6) as u8,
+            ((MUX_RATE << 2) as u8 & 0xFC) | 0x03,
+            // No stuffing
+            0xF8,
+        ]);
+    }
+
+    fn system_header(&self, buffer: &mut Vec<u8>) {
+        // Stream ID, then the buffer size scale and bound for each stream
+        let streams: [[u8; 3]; 3] = [
+            [0xE0, 0xE0, 0xE8], // 232 KiB video buffer
+            [0xC0, 0xC0, 0x20], // 4 KiB MPEG audio buffer
+            [0xBD, 0xE0, 0x3A], // 58 KiB private stream buffer
+        ];
+
+        buffer.extend(&SYSTEM_HEADER_START_CODE);
+        buffer.extend(&(6 + 3 * streams.len() as u16).to_be_bytes());
+        buffer.extend(&[
+            0x80 | (MUX_RATE >> 15) as u8,
+            (MUX_RATE >> 7) as u8,
+            ((MUX_RATE << 1) as u8) | 0x01,
+            // Up to 2 audio streams, variable rate
+            0x08,
+            // Audio and video locked to the SCR, 1 video stream
+            0xE1,
+            0x7F,
+        ]);
+        for stream in streams.iter() {
+            buffer.extend(stream);
+        }
+    }
+
+    pub fn write_packet(&mut self, packet: &ElementaryPacket) -> Result<()> {
+        let stream_id = packet.kind.stream_id();
+        let mut data: &[u8] = &packet.data;
+        let mut first = true;
+
+        while !data.is_empty() {
+            // The first pack of the stream also carries the system header
+            let mut system_header = vec![];
+            if !self.wrote_system_header {
+                self.system_header(&mut system_header);
+                self.wrote_system_header = true;
+            }
+
+            let mut max_payload =
+                PACK_SIZE - PACK_HEADER_LENGTH - system_header.len() - MAX_PES_HEADER_LENGTH;
+            if packet.kind == ElementaryStreamKind::Ac3Audio {
+                max_payload -= 4;
+            }
+            let (payload, rest) = data.split_at(data.len().min(max_payload));
+            data = rest;
+
+            // Only the first packet carries the timestamps of the access unit
+            let (pts, dts) = if first {
+                (packet.pts, packet.dts)
+            } else {
+                (None, None)
+            };
+            first = false;
+
+            let mut pes = vec![];
+            if packet.kind == ElementaryStreamKind::Ac3Audio {
+                write_pes_header(&mut pes, stream_id, pts, dts, payload.len() + 4);
+                pes.extend(&[AC3_SUBSTREAM_ID, 0x01, 0x00, 0x01]);
+            } else {
+                write_pes_header(&mut pes, stream_id, pts, dts, payload.len());
+            }
+            pes.extend(payload);
+
+            let mut buffer = vec![];
+            let scr = self
+                .scr
+                .tick(packet, PACK_HEADER_LENGTH + system_header.len() + pes.len());
+            self.pack_header(&mut buffer, scr);
+            buffer.extend(system_header);
+            buffer.extend(pes);
+
+            self.write_all(&buffer)?;
+        }
+
+        Ok(())
+    }
+
+    pub fn write_record(&mut self, record: &TyRecord) -> Result<()> {
+        for packet in self.demuxer.push(record) {
+            self.write_packet(&packet)?;
+        }
+
+        Ok(())
+    }
+
+    /// End the program stream, returning the writer.
+    pub fn finish(mut self) -> Result<W> {
+        self.write_all(&PROGRAM_END_CODE)?;
+        self.writer.flush().map_err(|source| Error::Io {
+            sector: None,
+            source,
+        })?;
+
+        Ok(self.writer)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use tivo_media_file_system::TyRecordType;
+
+    fn record(r#type: TyRecordType, data: Vec<u8>) -> TyRecord {
+        TyRecord {
+            r#type,
+            subtype: 0,
+            size: data.len() as u32,
+            timestamp: 0,
+            data,
+            chunk: 0,
+        }
+    }
+
+    #[test]
+    fn test_mux_video_record() {
+        let mut pes_header = vec![];
+        write_pes_header(&mut pes_header, 0xE0, Some(900_000), None, 0);
+
+        let mut muxer = ProgramStreamMuxer::new(vec![]);
+        // The PES header and the frame it belongs to arrive in separate records
+        muxer
+            .write_record(&record(TyRecordType::Video, pes_header))
+            .unwrap();
+        muxer
+            .write_record(&record(TyRecordType::Video, vec![0, 0, 1, 0xB3, 1, 2]))
+            .unwrap();
+        let output = muxer.finish().unwrap();
+
+        assert_eq!(output[0..4], PACK_START_CODE);
+        assert_eq!(output[14..18], SYSTEM_HEADER_START_CODE);
+
+        let pes = &output[14 + 6 + 15..];
+        assert_eq!(pes[0..4], [0, 0, 1, 0xE0]);
+        // Header flags, 5 bytes of PTS then the frame
+        assert_eq!(pes[4..6], [0, 3 + 5 + 6]);
+        assert_eq!(pes[7], 0x80);
+
+        let mut pts = vec![];
+        write_timestamp(&mut pts, 0x2, 900_000);
+        assert_eq!(pes[9..14], pts[..]);
+        assert_eq!(pes[14..20], [0, 0, 1, 0xB3, 1, 2]);
+        assert_eq!(pes[20..24], PROGRAM_END_CODE);
+    }
+
+    #[test]
+    fn test_packs_fit_in_a_sector() {
+        let mut muxer = ProgramStreamMuxer::new(vec![]);
+        muxer
+            .write_packet(&ElementaryPacket {
+                kind: ElementaryStreamKind::Video,
+                pts: Some(900_000),
+                dts: Some(896_997),
+                record_time: None,
+                data: vec![0xFF; 3 * PACK_SIZE],
+            })
+            .unwrap();
+        let output = muxer.finish().unwrap();
+
+        let packs: Vec<usize> = output
+            .windows(4)
+            .enumerate()
+            .filter(|(_, window)| *window == PACK_START_CODE)
+            .map(|(offset, _)| offset)
+            .collect();
+
+        assert_eq!(packs.len(), 4);
+        // The first pack is as full as it can be with the system header in it
+        assert_eq!(packs[1], PACK_SIZE);
+        assert!(packs
+            .windows(2)
+            .all(|packs| packs[1] - packs[0] <= PACK_SIZE));
+    }
+
+    #[test]
+    fn test_scr_follows_record_times() {
+        let packet = |pts, record_time| ElementaryPacket {
+            kind: ElementaryStreamKind::MpegAudio,
+            pts: Some(pts),
+            dts: None,
+            record_time: Some(record_time),
+            data: vec![0xFF; 16],
+        };
+
+        let mut muxer = ProgramStreamMuxer::new(vec![]);
+        muxer.write_packet(&packet(900_000, 10_000)).unwrap();
+        // Received a second later, its PTS only half a second on
+        muxer.write_packet(&packet(945_000, 100_000)).unwrap();
+        let output = muxer.finish().unwrap();
+
+        let packs: Vec<usize> = output
+            .windows(4)
+            .enumerate()
+            .filter(|(_, window)| *window == PACK_START_CODE)
+            .map(|(offset, _)| offset)
+            .collect();
+        let scr = |pack: usize| {
+            let bytes = &output[pack + 4..pack + 9];
+            (u64::from(bytes[0] & 0x38) << 27)
+                | (u64::from(bytes[0] & 0x03) << 28)
+                | (u64::from(bytes[1]) << 20)
+                | (u64::from(bytes[2] & 0xF8) << 12)
+                | (u64::from(bytes[2] & 0x03) << 13)
+                | (u64::from(bytes[3]) << 5)
+                | (u64::from(bytes[4]) >> 3)
+        };
+
+        assert_eq!(scr(packs[0]), 900_000 - 63_000);
+        assert_eq!(scr(packs[1]), 990_000 - 63_000);
+    }
+}
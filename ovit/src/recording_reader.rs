@@ -0,0 +1,391 @@
+use log::warn;
+use ovit_util::{Error, Result};
+use std::io::{self, Read, Seek, SeekFrom};
+use tivo_media_file_system::{
+    TyChunk, TyRecord, TyRecordType, TyStream, TY_CHUNK_SIZE, TY_STREAM_HEADER_MAGIC,
+};
+
+/// How many chunks at the end of a part are checked for a copy at the start of the next.
+const OVERLAP_WINDOW: u64 = 4;
+
+/// A run of chunks from one part that make it into the logical stream.
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct RecordingSegment {
+    part: usize,
+    first_chunk: u64,
+    chunk_count: u64,
+}
+
+fn is_unwritten(chunk: &[u8]) -> bool {
+    chunk.iter().all(|byte| *byte == 0)
+}
+
+fn is_stream_header(chunk: &[u8]) -> bool {
+    chunk.len() >= 4 && chunk[0..4] == TY_STREAM_HEADER_MAGIC.to_be_bytes()
+}
+
+/// What a repeated chunk has in common with the original, its sequence number and the type,
+///  size and time of every record. Payloads aren't compared so a damaged byte in a copy
+///  doesn't hide the repeat.
+#[derive(Debug, PartialEq)]
+struct ChunkKey {
+    sequence: u16,
+    records: Vec<(TyRecordType, u8, u32, u64)>,
+}
+
+fn chunk_key(chunk: &[u8]) -> Option<ChunkKey> {
+    match TyChunk::from_bytes(chunk, 0) {
+        Ok(Some(chunk)) => Some(ChunkKey {
+            sequence: chunk.sequence,
+            records: chunk
+                .records
+                .iter()
+                .map(|record| (record.r#type, record.subtype, record.size, record.timestamp))
+                .collect(),
+        }),
+        _ => None,
+    }
+}
+
+/// Work out which chunks of each part to keep. Parts are allocated ahead of time so they can
+///  end in chunks that were never written, later parts start with their own stream header,
+///  and the TiVo repeats a few chunks when it switches from one part to the next. Chunks that
+///  can't be read are kept, for the reader to skip later, rather than losing the recording.
+fn segments<F>(chunk_counts: &[u64], mut read_chunk: F) -> Vec<RecordingSegment>
+where
+    F: FnMut(usize, u64) -> Result<Vec<u8>>,
+{
+    let mut read_chunk = |part, chunk| match read_chunk(part, chunk) {
+        Ok(data) => Some(data),
+        Err(err) => {
+            warn!("Couldn't read chunk {} of part {}: {}", chunk, part, err);
+            None
+        }
+    };
+
+    let mut segments = vec![];
+    let mut previous_tail: Vec<Option<ChunkKey>> = vec![];
+
+    for (part, chunk_count) in chunk_counts.iter().enumerate() {
+        let mut first_chunk = 0;
+        let mut last_chunk = *chunk_count;
+
+        while last_chunk > first_chunk
+            && read_chunk(part, last_chunk - 1).is_some_and(|chunk| is_unwritten(&chunk))
+        {
+            last_chunk -= 1;
+        }
+
+        if part > 0
+            && first_chunk < last_chunk
+            && read_chunk(part, 0).is_some_and(|chunk| is_stream_header(&chunk))
+        {
+            first_chunk += 1;
+        }
+
+        if first_chunk < last_chunk {
+            let leading = read_chunk(part, first_chunk).and_then(|chunk| chunk_key(&chunk));
+
+            // Skip however many chunks were repeated from the end of the last part
+            if let Some(position) = leading.and_then(|leading| {
+                previous_tail
+                    .iter()
+                    .position(|key| key.as_ref() == Some(&leading))
+            }) {
+                first_chunk += (previous_tail.len() - position) as u64;
+            }
+        }
+
+        if first_chunk >= last_chunk {
+            continue;
+        }
+
+        previous_tail = (last_chunk.saturating_sub(OVERLAP_WINDOW).max(first_chunk)..last_chunk)
+            .map(|chunk| read_chunk(part, chunk).and_then(|chunk| chunk_key(&chunk)))
+            .collect();
+
+        segments.push(RecordingSegment {
+            part,
+            first_chunk,
+            chunk_count: last_chunk - first_chunk,
+        });
+    }
+
+    segments
+}
+
+/// The parts of a recording joined into one continuous stream of chunks.
+#[derive(Debug)]
+pub struct RecordingReader {
+    streams: Vec<TyStream>,
+    segments: Vec<RecordingSegment>,
+    chunk_count: u64,
+
+    position: u64,
+    /// The chunk last read from, by its logical index.
+    current: Option<(u64, Vec<u8>)>,
+}
+
+impl RecordingReader {
+    /// Join `streams`, the parts of a recording in order.
+    pub fn new(streams: Vec<TyStream>) -> RecordingReader {
+        let chunk_counts: Vec<u64> = streams.iter().map(|stream| stream.chunk_count()).collect();
+        let segments = segments(&chunk_counts, |part, chunk| streams[part].read_chunk(chunk));
+        let chunk_count = segments.iter().map(|segment| segment.chunk_count).sum();
+
+        RecordingReader {
+            streams,
+            segments,
+            chunk_count,
+
+            position: 0,
+            current: None,
+        }
+    }
+
+    pub fn chunk_count(&self) -> u64 {
+        self.chunk_count
+    }
+
+    /// Length of the joined stream in bytes.
+    pub fn len(&self) -> u64 {
+        self.chunk_count * TY_CHUNK_SIZE as u64
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.chunk_count == 0
+    }
+
+    /// Read the raw bytes of chunk number `index` of the joined stream.
+    pub fn read_chunk(&self, index: u64) -> Result<Vec<u8>> {
+        let mut index = index;
+
+        for segment in self.segments.iter() {
+            if index < segment.chunk_count {
+                return self.streams[segment.part].read_chunk(segment.first_chunk + index);
+            }
+
+            index -= segment.chunk_count;
+        }
+
+        Err(Error::NotFound("ty chunk"))
+    }
+
+    pub fn chunk(&self, index: u64) -> Result<Option<TyChunk>> {
+        TyChunk::from_bytes(&self.read_chunk(index)?, index)
+    }
+
+    /// Call `f` with every record of the recording in order. Chunks that can't be read or
+    ///  parsed are skipped with a warning rather than losing the rest of the recording,
+    ///  returns how many were skipped.
+    pub fn for_each_record<F>(&self, f: F) -> Result<u64>
+    where
+        F: FnMut(&TyRecord) -> Result<()>,
+    {
+        for_each_record(self.chunk_count, |index| self.chunk(index), f)
+    }
+}
+
+fn for_each_record<C, F>(chunk_count: u64, mut chunk: C, mut f: F) -> Result<u64>
+where
+    C: FnMut(u64) -> Result<Option<TyChunk>>,
+    F: FnMut(&TyRecord) -> Result<()>,
+{
+    let mut skipped = 0;
+
+    for index in 0..chunk_count {
+        match chunk(index) {
+            Ok(Some(chunk)) => {
+                for record in chunk.records.iter() {
+                    f(record)?;
+                }
+            }
+            Ok(None) => {}
+            Err(err) => {
+                warn!("Skipping chunk {}: {}", index, err);
+                skipped += 1;
+            }
+        }
+    }
+
+    Ok(skipped)
+}
+
+impl Read for RecordingReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.position >= self.len() || buf.is_empty() {
+            return Ok(0);
+        }
+
+        let index = self.position / TY_CHUNK_SIZE as u64;
+        let offset = (self.position % TY_CHUNK_SIZE as u64) as usize;
+
+        let chunk = match self.current.take() {
+            Some((current, chunk)) if current == index => chunk,
+            _ => self.read_chunk(index).map_err(io::Error::other)?,
+        };
+
+        let length = buf.len().min(chunk.len().saturating_sub(offset));
+        buf[..length].copy_from_slice(&chunk[offset..offset + length]);
+
+        self.position += length as u64;
+        self.current = Some((index, chunk));
+
+        Ok(length)
+    }
+}
+
+impl Seek for RecordingReader {
+    fn seek(&mut self, position: SeekFrom) -> io::Result<u64> {
+        let (base, offset) = match position {
+            SeekFrom::Start(offset) => {
+                self.position = offset;
+                return Ok(offset);
+            }
+            SeekFrom::End(offset) => (self.len(), offset),
+            SeekFrom::Current(offset) => (self.position, offset),
+        };
+
+        let position = if offset >= 0 {
+            base.checked_add(offset as u64)
+        } else {
+            base.checked_sub(offset.unsigned_abs())
+        };
+
+        match position {
+            Some(position) => {
+                self.position = position;
+                Ok(position)
+            }
+            None => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "invalid seek to a negative or overflowing position",
+            )),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// A chunk with sequence number `sequence` holding one video record, all zeros for 0.
+    fn chunk(sequence: u8) -> Vec<u8> {
+        if sequence == 0 {
+            return vec![0; 21];
+        }
+
+        let mut chunk = vec![1, 0, sequence, 0, 0, 0, 0x10, 0xE0, 0, 0, 0, 0];
+        chunk.extend(&u64::from(sequence).to_be_bytes());
+        chunk.push(sequence);
+        chunk
+    }
+
+    #[test]
+    fn test_segments_across_parts() {
+        let mut header = TY_STREAM_HEADER_MAGIC.to_be_bytes().to_vec();
+        header.resize(21, 0);
+        // The copy of a chunk needn't match it byte for byte
+        let mut damaged = chunk(3);
+        damaged[20] = 0xFF;
+
+        let parts = [
+            vec![header.clone(), chunk(1), chunk(2), chunk(3), chunk(0)],
+            // Repeats chunks 2 and 3 after its header
+            vec![
+                header.clone(),
+                chunk(2),
+                damaged,
+                chunk(4),
+                chunk(0),
+                chunk(0),
+            ],
+            vec![chunk(0)],
+            vec![header, chunk(5)],
+        ];
+        let chunk_counts: Vec<u64> = parts.iter().map(|part| part.len() as u64).collect();
+
+        let segments = segments(&chunk_counts, |part, chunk| {
+            Ok(parts[part][chunk as usize].clone())
+        });
+
+        assert_eq!(
+            segments,
+            vec![
+                RecordingSegment {
+                    part: 0,
+                    first_chunk: 0,
+                    chunk_count: 4,
+                },
+                RecordingSegment {
+                    part: 1,
+                    first_chunk: 3,
+                    chunk_count: 1,
+                },
+                RecordingSegment {
+                    part: 3,
+                    first_chunk: 1,
+                    chunk_count: 1,
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_segments_with_unreadable_chunks() {
+        let parts = [
+            vec![Ok(chunk(1)), Ok(chunk(2)), Err(()), Ok(chunk(0))],
+            vec![Err(()), Ok(chunk(3))],
+        ];
+        let chunk_counts: Vec<u64> = parts.iter().map(|part| part.len() as u64).collect();
+
+        let segments = segments(&chunk_counts, |part, chunk| {
+            parts[part][chunk as usize]
+                .clone()
+                .map_err(|_| Error::NotFound("ty chunk"))
+        });
+
+        // Unreadable chunks are kept for the reader to skip
+        assert_eq!(
+            segments,
+            vec![
+                RecordingSegment {
+                    part: 0,
+                    first_chunk: 0,
+                    chunk_count: 3,
+                },
+                RecordingSegment {
+                    part: 1,
+                    first_chunk: 0,
+                    chunk_count: 2,
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_for_each_record_skips_bad_chunks() {
+        // A chunk with one video record of a single byte
+        let mut bytes = vec![1, 0, 0, 0, 0, 0, 0x10, 0xE0];
+        bytes.resize(4 + 16 + 1, 0);
+
+        let mut records = 0;
+        let skipped = for_each_record(
+            4,
+            |index| match index {
+                1 => Err(Error::NotFound("ty chunk")),
+                2 => Ok(None),
+                _ => TyChunk::from_bytes(&bytes, index),
+            },
+            |record| {
+                assert_eq!(record.size, 1);
+                records += 1;
+                Ok(())
+            },
+        )
+        .unwrap();
+
+        assert_eq!(records, 2);
+        assert_eq!(skipped, 1);
+    }
+}
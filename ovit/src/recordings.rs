@@ -0,0 +1,265 @@
+use crate::{Result, TivoDrive};
+use chrono::{TimeZone, Utc};
+use log::warn;
+use serde::Serialize;
+use std::collections::HashSet;
+use tivo_media_file_system::{
+    MFSDbError, MFSDbObject, MFSINodeType, MFSSchema, Program, Recording, Series, Showing, Station,
+};
+
+/// What the catalogue knows about a recording, joined from its Showing, Program, Series
+///  and Station objects. Objects that can't be read leave their fields empty.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct RecordingInfo {
+    pub fsid: u32,
+    pub state: Option<u32>,
+    pub title: Option<String>,
+    pub episode_title: Option<String>,
+    pub series: Option<String>,
+    /// The station's call sign.
+    pub channel: Option<String>,
+    /// When the showing started, in UTC.
+    pub air_date: Option<String>,
+    /// Length of the showing in seconds.
+    pub duration: Option<u32>,
+    /// Total size of the recording's streams in bytes.
+    pub size: u64,
+    pub stream_fsids: Vec<u32>,
+}
+
+/// Quote a CSV field if it holds a separator, quote or line break.
+fn csv_field(value: &str) -> String {
+    if value.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value.to_string()
+    }
+}
+
+fn text(value: &Option<String>) -> &str {
+    value.as_deref().unwrap_or("")
+}
+
+impl RecordingInfo {
+    pub const CSV_HEADER: &'static str =
+        "fsid,state,title,episode_title,series,channel,air_date,duration,size,stream_fsids";
+
+    /// The recording as a row of CSV, in the columns of `CSV_HEADER`.
+    pub fn csv_row(&self) -> String {
+        let fields = [
+            self.fsid.to_string(),
+            self.state
+                .map(|state| state.to_string())
+                .unwrap_or_default(),
+            text(&self.title).to_string(),
+            text(&self.episode_title).to_string(),
+            text(&self.series).to_string(),
+            text(&self.channel).to_string(),
+            text(&self.air_date).to_string(),
+            self.duration
+                .map(|duration| duration.to_string())
+                .unwrap_or_default(),
+            self.size.to_string(),
+            self.stream_fsids_text(),
+        ];
+
+        fields
+            .iter()
+            .map(|field| csv_field(field))
+            .collect::<Vec<String>>()
+            .join(",")
+    }
+
+    /// The duration as `H:MM:SS`, empty when it isn't known.
+    pub fn duration_text(&self) -> String {
+        self.duration
+            .map(|duration| {
+                format!(
+                    "{}:{:02}:{:02}",
+                    duration / 3600,
+                    duration / 60 % 60,
+                    duration % 60
+                )
+            })
+            .unwrap_or_default()
+    }
+
+    /// The FSIDs of the recording's streams separated by spaces.
+    pub fn stream_fsids_text(&self) -> String {
+        self.stream_fsids
+            .iter()
+            .map(|fsid| fsid.to_string())
+            .collect::<Vec<String>>()
+            .join(" ")
+    }
+}
+
+/// Showings store their start as days since the epoch and seconds into that day.
+fn air_date(date: u32, time: u32) -> Option<String> {
+    let timestamp = i64::from(date) * 86_400 + i64::from(time);
+
+    Utc.timestamp_opt(timestamp, 0)
+        .single()
+        .map(|date| date.format("%Y-%m-%d %H:%M:%S").to_string())
+}
+
+fn db_object(tivo_drive: &TivoDrive, fsid: u32) -> Result<MFSDbObject> {
+    let inode = tivo_drive.get_inode_from_fsid(fsid)?;
+
+    inode.get_db_object(
+        &*tivo_drive.source,
+        &tivo_drive.volumes,
+        MFSSchema::bundled(),
+    )
+}
+
+/// FSIDs of every `Db` object under `fsid`, descending into subdirectories. Subdirectories
+///  that can't be read are skipped with a warning.
+fn walk_objects(
+    tivo_drive: &TivoDrive,
+    fsid: u32,
+    visited: &mut HashSet<u32>,
+    objects: &mut Vec<u32>,
+) -> Result<()> {
+    if !visited.insert(fsid) {
+        return Ok(());
+    }
+
+    for entry in tivo_drive.directory_entries(fsid)? {
+        match entry.r#type {
+            MFSINodeType::Dir => {
+                if let Err(err) = walk_objects(tivo_drive, entry.fsid, visited, objects) {
+                    warn!("Couldn't read directory {}: {}", entry.name, err);
+                }
+            }
+            MFSINodeType::Db if visited.insert(entry.fsid) => objects.push(entry.fsid),
+            _ => {}
+        }
+    }
+
+    Ok(())
+}
+
+fn recording_info(tivo_drive: &TivoDrive, fsid: u32) -> Result<Option<RecordingInfo>> {
+    let object = db_object(tivo_drive, fsid)?;
+
+    // The index directories also hold other kinds of object
+    let recording = match Recording::from_object(&object) {
+        Ok(recording) => recording,
+        Err(MFSDbError::WrongObjectType { .. }) => return Ok(None),
+        Err(err) => return Err(err.into()),
+    };
+
+    let mut info = RecordingInfo {
+        fsid,
+        state: recording.state,
+        title: None,
+        episode_title: None,
+        series: None,
+        channel: None,
+        air_date: None,
+        duration: None,
+        size: 0,
+        stream_fsids: vec![],
+    };
+
+    if let Ok(streams) = tivo_drive.recording_object_streams(&object, &recording) {
+        info.size = streams.iter().map(|inode| inode.stream_size()).sum();
+        info.stream_fsids = streams.iter().map(|inode| inode.fsid).collect();
+    }
+
+    let showing = match recording
+        .showing
+        .and_then(|showing| object.subobject(showing))
+        .map(Showing::from_subobject)
+    {
+        Some(Ok(showing)) => showing,
+        _ => return Ok(Some(info)),
+    };
+
+    if let (Some(date), Some(time)) = (showing.date, showing.time) {
+        info.air_date = air_date(date, time);
+    }
+    info.duration = showing.duration;
+
+    if let Some(station) = showing
+        .station
+        .and_then(|station| db_object(tivo_drive, station).ok())
+        .and_then(|object| Station::from_object(&object).ok())
+    {
+        info.channel = station.call_sign;
+    }
+
+    if let Some(program) = showing
+        .program
+        .and_then(|program| db_object(tivo_drive, program).ok())
+        .and_then(|object| Program::from_object(&object).ok())
+    {
+        info.title = program.title;
+        info.episode_title = program.episode_title;
+
+        if let Some(series) = program
+            .series
+            .and_then(|series| db_object(tivo_drive, series).ok())
+            .and_then(|object| Series::from_object(&object).ok())
+        {
+            info.series = series.title;
+        }
+    }
+
+    Ok(Some(info))
+}
+
+/// Every recording on the drive, found by walking `/Recording`.
+pub fn recordings(tivo_drive: &TivoDrive) -> Result<Vec<RecordingInfo>> {
+    let recording_dir = tivo_drive.lookup_fsid("/Recording")?;
+
+    let mut objects = vec![];
+    walk_objects(tivo_drive, recording_dir, &mut HashSet::new(), &mut objects)?;
+
+    let mut recordings = vec![];
+    for fsid in objects {
+        match recording_info(tivo_drive, fsid) {
+            Ok(Some(info)) => recordings.push(info),
+            Ok(None) => {}
+            Err(err) => warn!("Couldn't read recording {}: {}", fsid, err),
+        }
+    }
+
+    Ok(recordings)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_formatting() {
+        let info = RecordingInfo {
+            fsid: 4242,
+            state: Some(4),
+            title: Some(String::from("Cosmos")),
+            episode_title: Some(String::from("Heaven, \"and\"\r\nHell")),
+            series: None,
+            channel: Some(String::from("KQED")),
+            air_date: None,
+            duration: Some(3_725),
+            size: 1024,
+            stream_fsids: vec![10, 11],
+        };
+
+        assert_eq!(info.duration_text(), "1:02:05");
+        assert_eq!(
+            info.csv_row(),
+            "4242,4,Cosmos,\"Heaven, \"\"and\"\"\r\nHell\",,KQED,,3725,1024,10 11"
+        );
+    }
+
+    #[test]
+    fn test_air_date() {
+        assert_eq!(
+            air_date(12_539, 72_000),
+            Some(String::from("2004-05-01 20:00:00"))
+        );
+    }
+}
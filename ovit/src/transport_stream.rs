@@ -0,0 +1,369 @@
+use crate::{
+    write_pes_header, ElementaryPacket, ElementaryStreamDemuxer, ElementaryStreamKind, SystemClock,
+};
+use ovit_util::{Error, Result};
+use std::collections::HashMap;
+use std::io::Write;
+use tivo_media_file_system::{crc32, TyRecord};
+
+pub const TS_PACKET_SIZE: usize = 188;
+const TS_SYNC_BYTE: u8 = 0x47;
+const TS_PAYLOAD_SIZE: usize = TS_PACKET_SIZE - 4;
+
+const PAT_PID: u16 = 0x0000;
+const PMT_PID: u16 = 0x1000;
+const TRANSPORT_STREAM_ID: u16 = 1;
+const PROGRAM_NUMBER: u16 = 1;
+
+/// 12 Mbps, comfortably above the TiVo's highest quality setting.
+const BYTES_PER_SECOND: u64 = 1_500_000;
+/// Repeat the PAT and PMT every 100ms of the system clock.
+const TABLE_INTERVAL: u64 = 9_000;
+
+impl ElementaryStreamKind {
+    /// The transport stream PID the muxer puts this kind of stream on.
+    pub fn pid(self) -> u16 {
+        match self {
+            ElementaryStreamKind::Video => 0x0100,
+            ElementaryStreamKind::MpegAudio => 0x0101,
+            ElementaryStreamKind::Ac3Audio => 0x0102,
+        }
+    }
+
+    /// The PMT stream type, AC-3 follows ATSC since DirecTiVos record US broadcasts.
+    pub fn stream_type(self) -> u8 {
+        match self {
+            ElementaryStreamKind::Video => 0x02,
+            ElementaryStreamKind::MpegAudio => 0x03,
+            ElementaryStreamKind::Ac3Audio => 0x81,
+        }
+    }
+}
+
+/// Wrap a PSI table in its section header and CRC.
+fn section(table_id: u8, extension: u16, version: u8, body: &[u8]) -> Vec<u8> {
+    // Extension through section numbers, the body and the CRC
+    let length = 5 + body.len() + 4;
+
+    let mut section = vec![table_id, 0xB0 | (length >> 8) as u8, length as u8];
+    section.extend(&extension.to_be_bytes());
+    section.extend(&[0xC1 | ((version & 0x1F) << 1), 0x00, 0x00]);
+    section.extend(body);
+    // MPEG-2 uses the same CRC as MFS without the final inversion
+    section.extend(&(!crc32(&section)).to_be_bytes());
+
+    section
+}
+
+/// Muxes the records of TiVo streams into an MPEG-2 transport stream with a single program.
+#[derive(Debug)]
+pub struct TransportStreamMuxer<W: Write> {
+    writer: W,
+    demuxer: ElementaryStreamDemuxer,
+    /// Follows the times the TiVo stamped on each record.
+    pcr: SystemClock,
+    /// Streams in the PMT, in the order they were first seen.
+    streams: Vec<ElementaryStreamKind>,
+    pmt_version: u8,
+    /// The clock when the tables were last written.
+    tables_written_at: Option<u64>,
+    continuity_counters: HashMap<u16, u8>,
+}
+
+impl<W: Write> TransportStreamMuxer<W> {
+    pub fn new(writer: W) -> TransportStreamMuxer<W> {
+        TransportStreamMuxer {
+            writer,
+            demuxer: ElementaryStreamDemuxer::new(),
+            pcr: SystemClock::new(BYTES_PER_SECOND),
+            streams: vec![],
+            pmt_version: 0,
+            tables_written_at: None,
+            continuity_counters: HashMap::new(),
+        }
+    }
+
+    fn write_all(&mut self, buffer: &[u8]) -> Result<()> {
+        self.writer.write_all(buffer).map_err(|source| Error::Io {
+            sector: None,
+            source,
+        })
+    }
+
+    fn continuity_counter(&mut self, pid: u16) -> u8 {
+        let counter = self.continuity_counters.entry(pid).or_insert(0);
+        let current = *counter;
+        *counter = (*counter + 1) & 0x0F;
+        current
+    }
+
+    /// Split `payload` into transport packets on `pid`, the first one carrying `pcr`.
+    fn write_packets(&mut self, pid: u16, payload: &[u8], pcr: Option<u64>) -> Result<()> {
+        let mut buffer = Vec::with_capacity((payload.len() / TS_PAYLOAD_SIZE + 1) * TS_PACKET_SIZE);
+        let mut remaining = payload;
+        let mut pcr = pcr;
+        let mut first = true;
+
+        while first || !remaining.is_empty() {
+            // Adaptation field length, flags and the PCR
+            let pcr_length = if pcr.is_some() { 8 } else { 0 };
+            let length = remaining.len().min(TS_PAYLOAD_SIZE - pcr_length);
+            let adaptation_length = TS_PAYLOAD_SIZE - length;
+
+            let start = if first { 0x40 } else { 0x00 };
+            let control = if adaptation_length > 0 { 0x30 } else { 0x10 };
+            let counter = self.continuity_counter(pid);
+
+            buffer.extend(&[
+                TS_SYNC_BYTE,
+                start | (pid >> 8) as u8 & 0x1F,
+                pid as u8,
+                control | counter,
+            ]);
+
+            if adaptation_length > 0 {
+                buffer.push((adaptation_length - 1) as u8);
+            }
+            if adaptation_length > 1 {
+                match pcr.take() {
+                    Some(pcr) => buffer.extend(&[
+                        0x10,
+                        (pcr >> 25) as u8,
+                        (pcr >> 17) as u8,
+                        (pcr >> 9) as u8,
+                        (pcr >> 1) as u8,
+                        ((pcr << 7) as u8 & 0x80) | 0x7E,
+                        0x00,
+                    ]),
+                    None => buffer.push(0x00),
+                }
+                // Stuffing to fill out the last packet
+                buffer.resize(
+                    buffer.len() + TS_PACKET_SIZE - (buffer.len() % TS_PACKET_SIZE) - length,
+                    0xFF,
+                );
+            }
+
+            buffer.extend(&remaining[..length]);
+            remaining = &remaining[length..];
+            first = false;
+        }
+
+        self.write_all(&buffer)
+    }
+
+    /// Write a table section, padding out the rest of the packet.
+    fn write_section(&mut self, pid: u16, section: &[u8]) -> Result<()> {
+        // Pointer field, the section starts straight away
+        let mut payload = vec![0x00];
+        payload.extend(section);
+        payload.resize(TS_PAYLOAD_SIZE, 0xFF);
+
+        self.write_packets(pid, &payload, None)
+    }
+
+    /// The stream carrying the PCR, video when there is any and otherwise the first stream
+    ///  seen, so recordings without video still have a clock.
+    fn pcr_stream(&self) -> Option<ElementaryStreamKind> {
+        if self.streams.contains(&ElementaryStreamKind::Video) {
+            return Some(ElementaryStreamKind::Video);
+        }
+
+        self.streams.first().copied()
+    }
+
+    fn write_tables(&mut self) -> Result<()> {
+        let mut pat = vec![];
+        pat.extend(&PROGRAM_NUMBER.to_be_bytes());
+        pat.extend(&(0xE000 | PMT_PID).to_be_bytes());
+
+        // No stream carries a PCR until the first packet is written
+        let pcr_pid = self.pcr_stream().map_or(0x1FFF, |kind| kind.pid());
+        let mut pmt = vec![];
+        pmt.extend(&(0xE000 | pcr_pid).to_be_bytes());
+        pmt.extend(&[0xF0, 0x00]);
+        for kind in self.streams.iter() {
+            // A registration descriptor identifies the ATSC AC-3 stream type
+            let descriptors: &[u8] = match kind {
+                ElementaryStreamKind::Ac3Audio => &[0x05, 0x04, b'A', b'C', b'-', b'3'],
+                _ => &[],
+            };
+
+            pmt.push(kind.stream_type());
+            pmt.extend(&(0xE000 | kind.pid()).to_be_bytes());
+            pmt.extend(&(0xF000 | descriptors.len() as u16).to_be_bytes());
+            pmt.extend(descriptors);
+        }
+
+        self.write_section(PAT_PID, &section(0x00, TRANSPORT_STREAM_ID, 0, &pat))?;
+        self.write_section(
+            PMT_PID,
+            &section(0x02, PROGRAM_NUMBER, self.pmt_version, &pmt),
+        )
+    }
+
+    pub fn write_packet(&mut self, packet: &ElementaryPacket) -> Result<()> {
+        let mut pes = vec![];
+        write_pes_header(
+            &mut pes,
+            packet.kind.stream_id(),
+            packet.pts,
+            packet.dts,
+            packet.data.len(),
+        );
+        pes.extend(&packet.data);
+
+        let clock = self.pcr.tick(packet, pes.len());
+
+        if !self.streams.contains(&packet.kind) {
+            // The PMT changes as streams turn up
+            if !self.streams.is_empty() {
+                self.pmt_version = (self.pmt_version + 1) & 0x1F;
+            }
+            self.streams.push(packet.kind);
+            self.tables_written_at = None;
+        }
+
+        let tables_due = match self.tables_written_at {
+            Some(written_at) => clock.saturating_sub(written_at) >= TABLE_INTERVAL,
+            None => true,
+        };
+        if tables_due {
+            self.write_tables()?;
+            self.tables_written_at = Some(clock);
+        }
+
+        let pcr = if self.pcr_stream() == Some(packet.kind) {
+            Some(clock)
+        } else {
+            None
+        };
+
+        self.write_packets(packet.kind.pid(), &pes, pcr)
+    }
+
+    pub fn write_record(&mut self, record: &TyRecord) -> Result<()> {
+        for packet in self.demuxer.push(record) {
+            self.write_packet(&packet)?;
+        }
+
+        Ok(())
+    }
+
+    /// Flush the transport stream, returning the writer.
+    pub fn finish(mut self) -> Result<W> {
+        self.writer.flush().map_err(|source| Error::Io {
+            sector: None,
+            source,
+        })?;
+
+        Ok(self.writer)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn packet(kind: ElementaryStreamKind, pts: u64, length: usize) -> ElementaryPacket {
+        ElementaryPacket {
+            kind,
+            pts: Some(pts),
+            dts: None,
+            record_time: None,
+            data: vec![0xAA; length],
+        }
+    }
+
+    fn pid(packet: &[u8]) -> u16 {
+        u16::from(packet[1] & 0x1F) << 8 | u16::from(packet[2])
+    }
+
+    #[test]
+    fn test_mux_packets() {
+        let mut muxer = TransportStreamMuxer::new(vec![]);
+        muxer
+            .write_packet(&packet(ElementaryStreamKind::Video, 90_000, 400))
+            .unwrap();
+        muxer
+            .write_packet(&packet(ElementaryStreamKind::Ac3Audio, 90_000, 100))
+            .unwrap();
+        let output = muxer.finish().unwrap();
+
+        assert_eq!(output.len() % TS_PACKET_SIZE, 0);
+        let packets: Vec<&[u8]> = output.chunks(TS_PACKET_SIZE).collect();
+        assert!(packets.iter().all(|packet| packet[0] == TS_SYNC_BYTE));
+
+        let pids: Vec<u16> = packets.iter().map(|packet| pid(packet)).collect();
+        assert_eq!(
+            pids,
+            vec![PAT_PID, PMT_PID, 0x100, 0x100, 0x100, PAT_PID, PMT_PID, 0x102]
+        );
+
+        // The first video packet starts the PES and carries the PCR
+        assert_eq!(packets[2][1] & 0x40, 0x40);
+        assert_eq!(packets[2][3], 0x30);
+        assert_eq!(packets[2][5], 0x10);
+        assert_eq!(packets[2][12..16], [0x00, 0x00, 0x01, 0xE0]);
+        // Continuity counters count per PID
+        assert_eq!(packets[3][3] & 0x0F, 1);
+        assert_eq!(packets[4][3] & 0x0F, 2);
+        assert_eq!(packets[6][3] & 0x0F, 1);
+
+        // The video stream carries the PCR
+        assert_eq!(pid(&packets[1][12..]), 0x100);
+
+        // The second PMT is a new version listing the AC-3 stream
+        let pmt = &packets[6][5..];
+        assert_eq!(pmt[5], 0xC1 | (1 << 1));
+        assert_eq!(pmt[17], 0x81);
+        assert_eq!(pid(&pmt[17..]), 0x102);
+        let section_length = (usize::from(pmt[1] & 0x0F) << 8 | usize::from(pmt[2])) + 3;
+        assert_eq!(crc32(&pmt[..section_length]), !0);
+    }
+
+    #[test]
+    fn test_pcr_follows_record_times() {
+        let video = |pts, record_time| ElementaryPacket {
+            record_time: Some(record_time),
+            ..packet(ElementaryStreamKind::Video, pts, 100)
+        };
+
+        let mut muxer = TransportStreamMuxer::new(vec![]);
+        muxer.write_packet(&video(900_000, 10_000)).unwrap();
+        // Received a second later, its PTS only half a second on
+        muxer.write_packet(&video(945_000, 100_000)).unwrap();
+        let output = muxer.finish().unwrap();
+
+        let pcrs: Vec<u64> = output
+            .chunks(TS_PACKET_SIZE)
+            .filter(|packet| packet[3] & 0x20 != 0 && packet[5] & 0x10 != 0)
+            .map(|packet| {
+                (u64::from(packet[6]) << 25)
+                    | (u64::from(packet[7]) << 17)
+                    | (u64::from(packet[8]) << 9)
+                    | (u64::from(packet[9]) << 1)
+                    | (u64::from(packet[10]) >> 7)
+            })
+            .collect();
+
+        assert_eq!(pcrs, vec![900_000 - 63_000, 990_000 - 63_000]);
+    }
+
+    #[test]
+    fn test_audio_only_pcr() {
+        let mut muxer = TransportStreamMuxer::new(vec![]);
+        muxer
+            .write_packet(&packet(ElementaryStreamKind::MpegAudio, 90_000, 100))
+            .unwrap();
+        let output = muxer.finish().unwrap();
+        let packets: Vec<&[u8]> = output.chunks(TS_PACKET_SIZE).collect();
+
+        // Without video the audio stream carries the PCR
+        let pmt = &packets[1][5..];
+        assert_eq!(pid(&pmt[7..]), 0x101);
+        assert_eq!(pid(packets[2]), 0x101);
+        assert_eq!(packets[2][3] & 0x20, 0x20);
+        assert_eq!(packets[2][5], 0x10);
+    }
+}
@@ -0,0 +1,220 @@
+use std::env;
+use std::fmt::Write;
+use std::fs;
+use std::path::Path;
+
+// The schema types that get a generated struct, see src/schema_types.rs
+const GENERATED_TYPES: [&str; 10] = [
+    "Recording",
+    "RecordingPart",
+    "Program",
+    "Series",
+    "Showing",
+    "Station",
+    "SeasonPass",
+    "Bookmark",
+    "Person",
+    "Genre",
+];
+
+// Single attributes the code can't do without, read with `required` so objects missing them
+//  fail with `MFSDbError::MissingAttribute`.
+//
+// `single` in schema.txt only says an attribute holds at most one value, as opposed to
+//  `multi`, it isn't a promise that the attribute is there. Objects on real drives leave
+//  plenty of single attributes out, so every other one is generated as an `Option` like the
+//  `optional` ones. Otherwise a whole object would fail to decode over an attribute nothing
+//  here reads.
+const REQUIRED_ATTRIBUTES: [(&str, &str); 1] = [("RecordingPart", "File")];
+
+const KEYWORDS: [&str; 8] = ["type", "ref", "move", "match", "loop", "use", "mod", "self"];
+
+struct Attribute<'a> {
+    type_name: &'a str,
+    id: &'a str,
+    name: &'a str,
+    field: String,
+    kind: &'a str,
+    cardinality: &'a str,
+}
+
+fn field_name(name: &str) -> String {
+    let chars: Vec<char> = name.chars().collect();
+    let mut field = String::new();
+
+    for (index, c) in chars.iter().enumerate() {
+        if c.is_uppercase() && index > 0 {
+            let previous = chars[index - 1];
+            let next_is_lowercase = chars.get(index + 1).is_some_and(|next| next.is_lowercase());
+
+            if previous.is_lowercase()
+                || previous.is_ascii_digit()
+                || (previous.is_uppercase() && next_is_lowercase)
+            {
+                field.push('_');
+            }
+        }
+        field.extend(c.to_lowercase());
+    }
+
+    if KEYWORDS.contains(&field.as_str()) {
+        format!("r#{}", field)
+    } else {
+        field
+    }
+}
+
+fn is_required(attribute: &Attribute) -> bool {
+    attribute.cardinality == "single"
+        && REQUIRED_ATTRIBUTES.contains(&(attribute.type_name, attribute.name))
+}
+
+fn field_type(attribute: &Attribute) -> String {
+    let value = match attribute.kind {
+        "string" => "String",
+        _ => "u32",
+    };
+
+    match attribute.cardinality {
+        "single" | "optional" if !is_required(attribute) => format!("Option<{}>", value),
+        "single" => value.to_string(),
+        _ => format!("Vec<{}>", value),
+    }
+}
+
+fn field_value(attribute: &Attribute) -> String {
+    let values = match attribute.kind {
+        "int" => "ints",
+        "string" => "strings",
+        _ => "references",
+    };
+
+    match attribute.cardinality {
+        "single" if is_required(attribute) => format!(
+            "required({}(subobject, {}), \"{}\", \"{}\")?",
+            values, attribute.id, attribute.type_name, attribute.name
+        ),
+        "single" | "optional" => {
+            format!("{}(subobject, {}).into_iter().next()", values, attribute.id)
+        }
+        _ => format!("{}(subobject, {})", values, attribute.id),
+    }
+}
+
+fn main() {
+    println!("cargo:rerun-if-changed=src/schema.txt");
+    println!("cargo:rerun-if-changed=build.rs");
+
+    let schema = fs::read_to_string("src/schema.txt").expect("Could not read schema.txt");
+    let mut output = String::new();
+
+    for type_name in GENERATED_TYPES.iter() {
+        let lines: Vec<Vec<&str>> = schema
+            .lines()
+            .map(|line| line.split_whitespace().collect::<Vec<&str>>())
+            .filter(|fields| fields.len() == 8 && fields[1] == *type_name)
+            .collect();
+
+        let type_id = lines
+            .first()
+            .unwrap_or_else(|| panic!("{} is missing from schema.txt", type_name))[0];
+
+        let attributes: Vec<Attribute> = lines
+            .iter()
+            .enumerate()
+            .map(|(index, fields)| Attribute {
+                type_name: fields[1],
+                id: fields[2],
+                name: fields[3],
+                // A few types reuse an attribute name, the later ones get their ID appended
+                field: if lines[..index].iter().any(|other| other[3] == fields[3]) {
+                    format!("{}_{}", field_name(fields[3]), fields[2])
+                } else {
+                    field_name(fields[3])
+                },
+                kind: fields[4],
+                cardinality: fields[5],
+            })
+            .collect();
+
+        writeln!(
+            output,
+            "/// Schema type {}, generated from schema.txt.",
+            type_id
+        )
+        .unwrap();
+        writeln!(output, "#[derive(Debug, Clone, PartialEq)]").unwrap();
+        writeln!(output, "pub struct {} {{", type_name).unwrap();
+        writeln!(output, "    /// The subobject this was read from.").unwrap();
+        writeln!(output, "    pub subobject_id: u32,").unwrap();
+        for attribute in attributes.iter() {
+            writeln!(
+                output,
+                "    /// Attribute {}, {} {}",
+                attribute.id, attribute.kind, attribute.cardinality
+            )
+            .unwrap();
+            writeln!(
+                output,
+                "    pub {}: {},",
+                attribute.field,
+                field_type(attribute)
+            )
+            .unwrap();
+        }
+        writeln!(output, "}}\n").unwrap();
+
+        writeln!(output, "impl {} {{", type_name).unwrap();
+        writeln!(output, "    pub const TYPE_ID: u16 = {};\n", type_id).unwrap();
+        writeln!(
+            output,
+            "    pub fn from_subobject(subobject: &MFSDbSubobject) -> Result<{}, MFSDbError> {{",
+            type_name
+        )
+        .unwrap();
+        writeln!(
+            output,
+            "        check_type(subobject, {}::TYPE_ID, \"{}\")?;\n",
+            type_name, type_name
+        )
+        .unwrap();
+        writeln!(output, "        Ok({} {{", type_name).unwrap();
+        writeln!(output, "            subobject_id: subobject.id,").unwrap();
+        for attribute in attributes.iter() {
+            writeln!(
+                output,
+                "            {}: {},",
+                attribute.field,
+                field_value(attribute)
+            )
+            .unwrap();
+        }
+        writeln!(output, "        }})").unwrap();
+        writeln!(output, "    }}\n").unwrap();
+        writeln!(
+            output,
+            "    pub fn from_object(object: &MFSDbObject) -> Result<{}, MFSDbError> {{",
+            type_name
+        )
+        .unwrap();
+        writeln!(output, "        match object.primary() {{").unwrap();
+        writeln!(
+            output,
+            "            Some(subobject) => {}::from_subobject(subobject),",
+            type_name
+        )
+        .unwrap();
+        writeln!(
+            output,
+            "            None => Err(MFSDbError::NoPrimarySubobject),"
+        )
+        .unwrap();
+        writeln!(output, "        }}").unwrap();
+        writeln!(output, "    }}").unwrap();
+        writeln!(output, "}}\n").unwrap();
+    }
+
+    let out_dir = env::var("OUT_DIR").unwrap();
+    fs::write(Path::new(&out_dir).join("schema_types.rs"), output)
+        .expect("Could not write schema_types.rs");
+}
@@ -0,0 +1,152 @@
+use log::warn;
+use ovit_util::{Error, Result};
+
+/// Value the checksum field is set to while the checksum of a structure is computed (from mfstools)
+pub const MFS_CRC_BASE: u32 = 0xDEAD_F00D;
+
+const CRC32_POLYNOMIAL: u32 = 0x04C1_1DB7;
+
+const fn crc32_table() -> [u32; 256] {
+    let mut table = [0u32; 256];
+    let mut index = 0;
+
+    while index < 256 {
+        let mut crc = (index as u32) << 24;
+        let mut bit = 0;
+
+        while bit < 8 {
+            crc = if crc & 0x8000_0000 != 0 {
+                (crc << 1) ^ CRC32_POLYNOMIAL
+            } else {
+                crc << 1
+            };
+            bit += 1;
+        }
+
+        table[index] = crc;
+        index += 1;
+    }
+
+    table
+}
+
+const CRC32_TABLE: [u32; 256] = crc32_table();
+
+fn crc32_update(crc: u32, data: &[u8]) -> u32 {
+    data.iter().fold(crc, |crc, byte| {
+        (crc << 8) ^ CRC32_TABLE[((crc >> 24) ^ u32::from(*byte)) as usize]
+    })
+}
+
+/// The MSB-first CRC32 used across MFS.
+pub fn crc32(data: &[u8]) -> u32 {
+    !crc32_update(!0, data)
+}
+
+/// Compute the checksum of an MFS structure whose checksum field is the big endian
+/// `u32` at `checksum_offset`, treating that field as `MFS_CRC_BASE`.
+pub fn mfs_compute_crc(data: &[u8], checksum_offset: usize) -> u32 {
+    let checksum_end = (checksum_offset + 4).min(data.len());
+    let checksum_offset = checksum_offset.min(checksum_end);
+
+    let crc = crc32_update(!0, &data[..checksum_offset]);
+    let crc = crc32_update(crc, &MFS_CRC_BASE.to_be_bytes());
+    let crc = crc32_update(crc, &data[checksum_end..]);
+
+    !crc
+}
+
+/// An MFS structure that carries a checksum of itself, checked against one computed with
+///  `mfs_compute_crc` when the structure is read.
+pub trait MFSChecksummed {
+    /// What the structure is called in errors and warnings.
+    const STRUCTURE: &'static str;
+
+    /// The checksum stored in the structure.
+    fn stored_checksum(&self) -> u32;
+
+    /// The checksum of the structure as read, computed the same way the TiVo does.
+    fn computed_checksum(&self) -> u32;
+
+    fn is_checksum_valid(&self) -> bool {
+        self.stored_checksum() == self.computed_checksum()
+    }
+
+    fn verify_checksum(&self) -> Result<()> {
+        if self.is_checksum_valid() {
+            Ok(())
+        } else {
+            Err(Error::ChecksumMismatch {
+                structure: Self::STRUCTURE,
+                expected: self.stored_checksum(),
+                actual: self.computed_checksum(),
+            })
+        }
+    }
+}
+
+/// Choose between a structure read from `sector` and its redundant copy. The primary is used if
+///  its checksum is valid, then the copy, then whichever of the two could at least be parsed.
+///  The copy is only read when the primary isn't valid.
+pub fn mfs_checksum_fallback<T, F>(sector: u64, primary: Result<T>, read_copy: F) -> Result<T>
+where
+    T: MFSChecksummed,
+    F: FnOnce() -> Result<T>,
+{
+    if let Ok(structure) = &primary {
+        if structure.is_checksum_valid() {
+            return primary;
+        }
+    }
+
+    match (primary, read_copy()) {
+        (_, Ok(copy)) if copy.is_checksum_valid() => {
+            warn!(
+                "Damaged {} at sector {}, using its copy",
+                T::STRUCTURE,
+                sector
+            );
+            Ok(copy)
+        }
+        (Ok(primary), _) => {
+            warn!(
+                "{}, using it anyway",
+                primary.verify_checksum().unwrap_err()
+            );
+            Ok(primary)
+        }
+        (Err(err), Ok(copy)) => {
+            warn!(
+                "{}, using its copy with {}",
+                err,
+                copy.verify_checksum().unwrap_err()
+            );
+            Ok(copy)
+        }
+        (Err(err), Err(_)) => Err(err),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_crc32_check_value() {
+        assert_eq!(crc32(b"123456789"), 0xFC89_1918);
+    }
+
+    #[test]
+    fn test_mfs_compute_crc_ignores_checksum_field() {
+        let mut data = vec![0x5Au8; 64];
+        let checksum = mfs_compute_crc(&data, 8);
+
+        data[8..12].copy_from_slice(&checksum.to_be_bytes());
+
+        assert_eq!(mfs_compute_crc(&data, 8), checksum);
+
+        data[8..12].copy_from_slice(&MFS_CRC_BASE.to_be_bytes());
+
+        assert_eq!(crc32(&data), checksum);
+    }
+}
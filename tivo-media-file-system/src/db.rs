@@ -0,0 +1,288 @@
+extern crate nom;
+
+use crate::{MFSSchema, MFSSchemaKind};
+use nom::{
+    bytes::complete::take,
+    error::ErrorKind,
+    number::complete::{be_u16, be_u32, be_u8},
+    Err, IResult,
+};
+use ovit_util::{Error, Result};
+use std::convert::TryInto;
+
+const OBJECT_HEADER_SIZE: usize = 8;
+const SUBOBJECT_HEADER_SIZE: usize = 16;
+const ATTRIBUTE_HEADER_SIZE: usize = 4;
+
+#[derive(Debug, PartialEq, Clone)]
+pub enum MFSDbValue {
+    Int(Vec<u32>),
+    String(Vec<String>),
+    /// FSIDs of other objects.
+    Object(Vec<u32>),
+    /// IDs of other subobjects in the same object.
+    Subobject(Vec<u32>),
+    /// FSIDs of files, usually the streams making up a recording.
+    File(Vec<u32>),
+}
+
+fn be_u32_values(input: &[u8]) -> Vec<u32> {
+    input
+        .chunks_exact(4)
+        .map(|value| u32::from_be_bytes(value.try_into().unwrap()))
+        .collect()
+}
+
+impl MFSDbValue {
+    fn from_bytes(element_type: u8, input: &[u8]) -> Option<MFSDbValue> {
+        match element_type {
+            0 => Some(MFSDbValue::Int(be_u32_values(input))),
+            1 => Some(MFSDbValue::String(
+                input
+                    .split(|byte| *byte == 0)
+                    .filter(|string| !string.is_empty())
+                    .map(|string| String::from_utf8_lossy(string).to_string())
+                    .collect(),
+            )),
+            2 => Some(MFSDbValue::Object(be_u32_values(input))),
+            3 => Some(MFSDbValue::File(be_u32_values(input))),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct MFSDbAttribute {
+    pub id: u8,
+    /// From the schema, `None` for attributes it doesn't know about.
+    pub name: Option<String>,
+    pub value: MFSDbValue,
+}
+
+impl MFSDbAttribute {
+    fn parse(input: &[u8]) -> IResult<&[u8], MFSDbAttribute> {
+        let (input, element_type) = be_u8(input)?;
+        let (input, id) = be_u8(input)?;
+        let (input, length) = be_u16(input)?;
+
+        if (length as usize) < ATTRIBUTE_HEADER_SIZE {
+            return Err(Err::Error((input, ErrorKind::LengthValue)));
+        }
+
+        let (input, value) = take(length as usize - ATTRIBUTE_HEADER_SIZE)(input)?;
+        // Attributes are padded to 4 bytes, except possibly the last one
+        let padding = ((4 - length as usize % 4) % 4).min(input.len());
+        let (input, _) = take(padding)(input)?;
+
+        match MFSDbValue::from_bytes(element_type >> 6, value) {
+            Some(value) => Ok((
+                input,
+                MFSDbAttribute {
+                    id,
+                    name: None,
+                    value,
+                },
+            )),
+            None => Err(Err::Error((input, ErrorKind::NoneOf))),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct MFSDbSubobject {
+    pub r#type: u16,
+    /// From the schema, `None` for types it doesn't know about.
+    pub type_name: Option<String>,
+    pub flags: u16,
+    pub id: u32,
+    pub attributes: Vec<MFSDbAttribute>,
+}
+
+impl MFSDbSubobject {
+    fn parse(input: &[u8]) -> IResult<&[u8], MFSDbSubobject> {
+        let (input, length) = be_u16(input)?;
+        let (input, _length1) = be_u16(input)?;
+        let (input, r#type) = be_u16(input)?;
+        let (input, flags) = be_u16(input)?;
+        let (input, _) = take(4usize)(input)?;
+        let (input, id) = be_u32(input)?;
+
+        if (length as usize) < SUBOBJECT_HEADER_SIZE {
+            return Err(Err::Error((input, ErrorKind::LengthValue)));
+        }
+
+        let (input, mut body) = take(length as usize - SUBOBJECT_HEADER_SIZE)(input)?;
+
+        let mut attributes = vec![];
+        while !body.is_empty() {
+            let (remaining, attribute) = MFSDbAttribute::parse(body)?;
+            attributes.push(attribute);
+            body = remaining;
+        }
+
+        Ok((
+            input,
+            MFSDbSubobject {
+                r#type,
+                type_name: None,
+                flags,
+                id,
+                attributes,
+            },
+        ))
+    }
+
+    pub fn attribute(&self, name: &str) -> Option<&MFSDbValue> {
+        self.attributes
+            .iter()
+            .find(|attribute| attribute.name.as_deref() == Some(name))
+            .map(|attribute| &attribute.value)
+    }
+}
+
+/// A tyDb object, the contents of a `Db` inode.
+#[derive(Debug, PartialEq, Clone)]
+pub struct MFSDbObject {
+    pub fsid: u32,
+    pub subobjects: Vec<MFSDbSubobject>,
+}
+
+impl MFSDbObject {
+    fn parse(input: &[u8], fsid: u32) -> IResult<&[u8], MFSDbObject> {
+        let (input, _fill) = be_u32(input)?;
+        let (input, size) = be_u32(input)?;
+
+        let body_size = (size as usize)
+            .saturating_sub(OBJECT_HEADER_SIZE)
+            .min(input.len());
+        let (input, mut body) = take(body_size)(input)?;
+
+        let mut subobjects = vec![];
+        while body.len() >= SUBOBJECT_HEADER_SIZE {
+            let (remaining, subobject) = MFSDbSubobject::parse(body)?;
+            subobjects.push(subobject);
+            body = remaining;
+        }
+
+        Ok((input, MFSDbObject { fsid, subobjects }))
+    }
+
+    /// Decode a tyDb object, naming its types and attributes with `schema`.
+    pub fn from_bytes(input: &[u8], fsid: u32, schema: &MFSSchema) -> Result<MFSDbObject> {
+        let mut object = match MFSDbObject::parse(input, fsid) {
+            Ok((_, object)) => object,
+            Err(err) => return Err(Error::parse("database object", input, err)),
+        };
+
+        for subobject in object.subobjects.iter_mut() {
+            let schema_type = match schema.get_type(subobject.r#type) {
+                Some(schema_type) => schema_type,
+                None => continue,
+            };
+            subobject.type_name = Some(schema_type.name.clone());
+
+            for attribute in subobject.attributes.iter_mut() {
+                let schema_attribute = match schema_type.attribute(attribute.id) {
+                    Some(schema_attribute) => schema_attribute,
+                    None => continue,
+                };
+                attribute.name = Some(schema_attribute.name.clone());
+
+                // Subobject references are stored as object references
+                if let (MFSSchemaKind::Subobject, MFSDbValue::Object(ids)) =
+                    (schema_attribute.kind, &attribute.value)
+                {
+                    attribute.value = MFSDbValue::Subobject(ids.clone());
+                }
+            }
+        }
+
+        Ok(object)
+    }
+
+    /// The main subobject, whose type is the type of the whole object.
+    pub fn primary(&self) -> Option<&MFSDbSubobject> {
+        self.subobjects.first()
+    }
+
+    pub fn subobject(&self, id: u32) -> Option<&MFSDbSubobject> {
+        self.subobjects.iter().find(|subobject| subobject.id == id)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn attribute(element_type: u8, id: u8, value: &[u8]) -> Vec<u8> {
+        let length = (ATTRIBUTE_HEADER_SIZE + value.len()) as u16;
+        let mut bytes = vec![element_type << 6, id];
+        bytes.extend(&length.to_be_bytes());
+        bytes.extend(value);
+        bytes.resize(bytes.len().div_ceil(4) * 4, 0);
+        bytes
+    }
+
+    fn subobject(r#type: u16, id: u32, attributes: &[Vec<u8>]) -> Vec<u8> {
+        let body = attributes.concat();
+        let length = (SUBOBJECT_HEADER_SIZE + body.len()) as u16;
+        let mut bytes = vec![];
+        bytes.extend(&length.to_be_bytes());
+        bytes.extend(&length.to_be_bytes());
+        bytes.extend(&r#type.to_be_bytes());
+        bytes.extend(&[0, 0, 0, 0, 0, 0]);
+        bytes.extend(&id.to_be_bytes());
+        bytes.extend(body);
+        bytes
+    }
+
+    #[test]
+    fn test_parse_recording() {
+        let subobjects = [
+            subobject(
+                14,
+                1,
+                &[
+                    attribute(0, 16, &6u32.to_be_bytes()),
+                    attribute(2, 17, &2u32.to_be_bytes()),
+                    attribute(2, 42, &12345u32.to_be_bytes()),
+                ],
+            ),
+            subobject(7, 2, &[attribute(2, 16, &777u32.to_be_bytes())]),
+            subobject(3, 3, &[attribute(1, 17, b"Hello\0")]),
+        ]
+        .concat();
+        let mut bytes = vec![0, 0, 0, 0];
+        bytes.extend(&((OBJECT_HEADER_SIZE + subobjects.len()) as u32).to_be_bytes());
+        bytes.extend(subobjects);
+
+        let object = MFSDbObject::from_bytes(&bytes, 100, MFSSchema::bundled()).unwrap();
+
+        let recording = object.primary().unwrap();
+        assert_eq!(recording.type_name.as_deref(), Some("Recording"));
+        assert_eq!(
+            recording.attribute("State"),
+            Some(&MFSDbValue::Int(vec![6]))
+        );
+        assert_eq!(
+            recording.attribute("Showing"),
+            Some(&MFSDbValue::Subobject(vec![2]))
+        );
+        assert_eq!(
+            recording.attribute("ProgramSource"),
+            Some(&MFSDbValue::Object(vec![12345]))
+        );
+
+        let showing = object.subobject(2).unwrap();
+        assert_eq!(showing.type_name.as_deref(), Some("Showing"));
+        assert_eq!(
+            showing.attribute("Program"),
+            Some(&MFSDbValue::Object(vec![777]))
+        );
+
+        assert_eq!(
+            object.subobject(3).unwrap().attribute("Title"),
+            Some(&MFSDbValue::String(vec![String::from("Hello")]))
+        );
+    }
+}
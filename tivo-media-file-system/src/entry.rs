@@ -4,10 +4,9 @@ use crate::MFSINodeType;
 use nom::{
     bytes::streaming::take,
     error::ErrorKind,
-    number::streaming::{be_u32, be_u8},
+    number::streaming::{be_u16, be_u32, be_u8},
     Err, IResult,
 };
-use std::convert::TryInto;
 
 fn string(size: usize, input: &[u8]) -> IResult<&[u8], String> {
     let (input, str_bytes) = take(size)(input)?;
@@ -19,10 +18,27 @@ fn string(size: usize, input: &[u8]) -> IResult<&[u8], String> {
     Ok((input, sanitized))
 }
 
+/// The word at the start of a directory's data.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct MFSDirectoryHeader {
+    /// Bytes of directory data, including this header.
+    pub size: u16,
+    pub flags: u16,
+}
+
+impl MFSDirectoryHeader {
+    pub fn parse(input: &[u8]) -> IResult<&[u8], MFSDirectoryHeader> {
+        let (input, size) = be_u16(input)?;
+        let (input, flags) = be_u16(input)?;
+
+        Ok((input, MFSDirectoryHeader { size, flags }))
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct MFSEntry {
     pub fsid: u32,
-    length: u8,
+    pub length: u8,
     pub r#type: MFSINodeType,
     pub name: String,
 }
@@ -37,12 +53,13 @@ impl MFSEntry {
 
         let (input, length) = be_u8(input)?;
 
-        if length == 0 {
+        // Too short to hold the entry's own header
+        if length < 6 {
             return Err(Err::Error((input, ErrorKind::ParseTo)));
         }
 
         let (input, r#type) = MFSINodeType::parse(input)?;
-        let (input, name) = string((length - 6).try_into().unwrap(), input)?;
+        let (input, name) = string(usize::from(length - 6), input)?;
 
         Ok((
             input,
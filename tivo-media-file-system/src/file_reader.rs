@@ -0,0 +1,281 @@
+use crate::{MFSINode, MFSINodeDataBlock, MFSVolumes};
+use log::warn;
+use ovit_util::{BlockSource, Error, Result, SECTOR_SIZE};
+use std::io::{self, Read, Seek, SeekFrom};
+use std::sync::Arc;
+
+/// Most sectors read from the source in one go.
+const MAX_READ_SECTORS: u64 = 256;
+
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct MFSFileReaderOptions {
+    /// Read extents that can't be read from the source as zeros instead of failing.
+    pub zero_fill: bool,
+}
+
+/// Reads the contents of an inode without loading it all into memory.
+#[derive(Debug)]
+pub struct MFSFileReader {
+    source: Arc<dyn BlockSource>,
+    volumes: MFSVolumes,
+    options: MFSFileReaderOptions,
+
+    /// Contents stored in the inode itself, used instead of datablocks.
+    data: Option<Vec<u8>>,
+    datablocks: Vec<MFSINodeDataBlock>,
+    size: u64,
+    position: u64,
+}
+
+impl MFSFileReader {
+    pub fn new(
+        source: Arc<dyn BlockSource>,
+        volumes: &MFSVolumes,
+        inode: &MFSINode,
+    ) -> MFSFileReader {
+        MFSFileReader::new_with_options(source, volumes, inode, MFSFileReaderOptions::default())
+    }
+
+    pub fn new_with_options(
+        source: Arc<dyn BlockSource>,
+        volumes: &MFSVolumes,
+        inode: &MFSINode,
+        options: MFSFileReaderOptions,
+    ) -> MFSFileReader {
+        let data = if inode.datablocks.is_empty() && !inode.data.is_empty() {
+            Some(inode.data.clone())
+        } else {
+            None
+        };
+
+        // Inline data may be shorter than the size claims
+        let size = match &data {
+            Some(data) => inode.stream_size().min(data.len() as u64),
+            None => inode.stream_size(),
+        };
+
+        MFSFileReader {
+            source,
+            volumes: volumes.clone(),
+            options,
+
+            data,
+            datablocks: inode.datablocks.clone(),
+            size,
+            position: 0,
+        }
+    }
+
+    /// Size of the file in bytes.
+    pub fn len(&self) -> u64 {
+        self.size
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.size == 0
+    }
+
+    /// The volume sector holding the file's `sector`, and how many sectors follow it in the
+    ///  same extent.
+    fn locate(&self, sector: u64) -> Option<(u64, u64)> {
+        let mut sector = sector;
+
+        for datablock in self.datablocks.iter() {
+            let count = u64::from(datablock.count);
+
+            if sector < count {
+                return Some((datablock.sector + sector, count - sector));
+            }
+
+            sector -= count;
+        }
+
+        None
+    }
+
+    /// Read from the current position into `buf`, stopping at the end of an extent.
+    fn read_extent(&mut self, buf: &mut [u8]) -> Result<usize> {
+        let remaining = self.size.saturating_sub(self.position);
+        let length = (buf.len() as u64).min(remaining) as usize;
+
+        if length == 0 {
+            return Ok(0);
+        }
+
+        if let Some(data) = &self.data {
+            let start = self.position as usize;
+            buf[..length].copy_from_slice(&data[start..start + length]);
+
+            return Ok(length);
+        }
+
+        let sector = self.position / SECTOR_SIZE as u64;
+        let offset = (self.position % SECTOR_SIZE as u64) as usize;
+
+        let (volume_sector, extent_sectors) = match self.locate(sector) {
+            Some(location) => location,
+            None => return Err(Error::NotFound("datablock")),
+        };
+
+        let sectors = ((offset + length).div_ceil(SECTOR_SIZE) as u64)
+            .min(extent_sectors)
+            .min(MAX_READ_SECTORS);
+        let length = length.min(sectors as usize * SECTOR_SIZE - offset);
+
+        let read = match self
+            .volumes
+            .read_sectors(&*self.source, volume_sector, sectors)
+        {
+            Ok(read) => read,
+            Err(_) if self.options.zero_fill => {
+                self.read_sectors_zero_filled(volume_sector, sectors)
+            }
+            Err(err) => return Err(err),
+        };
+        buf[..length].copy_from_slice(&read[offset..offset + length]);
+
+        Ok(length)
+    }
+
+    /// Read `count` sectors one at a time, so only the sectors that really can't be read are
+    ///  filled with zeros.
+    fn read_sectors_zero_filled(&self, volume_sector: u64, count: u64) -> Vec<u8> {
+        let mut data = Vec::with_capacity(count as usize * SECTOR_SIZE);
+
+        for sector in volume_sector..volume_sector + count {
+            match self.volumes.read_sectors(&*self.source, sector, 1) {
+                Ok(read) => data.extend(read),
+                Err(err) => {
+                    warn!("Zero filling unreadable sector {}: {}", sector, err);
+                    data.resize(data.len() + SECTOR_SIZE, 0);
+                }
+            }
+        }
+
+        data
+    }
+}
+
+impl Read for MFSFileReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let length = self.read_extent(buf).map_err(io::Error::other)?;
+        self.position += length as u64;
+
+        Ok(length)
+    }
+}
+
+impl Seek for MFSFileReader {
+    fn seek(&mut self, position: SeekFrom) -> io::Result<u64> {
+        let (base, offset) = match position {
+            SeekFrom::Start(offset) => {
+                self.position = offset;
+                return Ok(offset);
+            }
+            SeekFrom::End(offset) => (self.size, offset),
+            SeekFrom::Current(offset) => (self.position, offset),
+        };
+
+        let position = if offset >= 0 {
+            base.checked_add(offset as u64)
+        } else {
+            base.checked_sub(offset.unsigned_abs())
+        };
+
+        match position {
+            Some(position) => {
+                self.position = position;
+                Ok(position)
+            }
+            None => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "invalid seek to a negative or overflowing position",
+            )),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test_fixtures::{
+        checksum_inode_sector, inode, inode_sector, numbered_disk, volumes,
+    };
+    use crate::{MFSINodeType, INODE_DATA_IN_HEADER};
+
+    fn file(size: u32, datablocks: &[(u32, u32)]) -> MFSINode {
+        inode(1, MFSINodeType::File, size, datablocks)
+    }
+
+    fn reader(inode: &MFSINode, options: MFSFileReaderOptions) -> MFSFileReader {
+        MFSFileReader::new_with_options(
+            Arc::new(numbered_disk(24)),
+            &volumes(&[10, 20]),
+            inode,
+            options,
+        )
+    }
+
+    #[test]
+    fn test_read_across_volumes() {
+        let inode = file(1280, &[(2, 2), (5, 1)]);
+        let mut reader = reader(&inode, MFSFileReaderOptions::default());
+
+        let mut contents = vec![];
+        reader.read_to_end(&mut contents).unwrap();
+
+        assert_eq!(contents.len(), 1280);
+        assert!(contents[..512].iter().all(|byte| *byte == 12));
+        assert!(contents[512..1024].iter().all(|byte| *byte == 13));
+        assert!(contents[1024..].iter().all(|byte| *byte == 21));
+
+        let mut buffer = [0u8; 48];
+        reader.seek(SeekFrom::Start(1000)).unwrap();
+        reader.read_exact(&mut buffer).unwrap();
+        assert_eq!(buffer[..24], [13; 24]);
+        assert_eq!(buffer[24..], [21; 24]);
+    }
+
+    #[test]
+    fn test_unreadable_extents() {
+        // The extent runs off the end of the disk after one sector
+        let inode = file(1024, &[(7, 2)]);
+
+        let mut contents = vec![];
+        assert!(reader(&inode, MFSFileReaderOptions::default())
+            .read_to_end(&mut contents)
+            .is_err());
+
+        // Read in one go, only the sector that can't be read is zero filled
+        let mut contents = vec![0xAA; 1024];
+        reader(&inode, MFSFileReaderOptions { zero_fill: true })
+            .read_exact(&mut contents)
+            .unwrap();
+        assert!(contents[..512].iter().all(|byte| *byte == 23));
+        assert!(contents[512..].iter().all(|byte| *byte == 0));
+    }
+
+    #[test]
+    fn test_inline_data_shorter_than_size() {
+        let mut sector = inode_sector(1, MFSINodeType::File, 1000, &[]);
+        sector[0x34..0x38].copy_from_slice(&INODE_DATA_IN_HEADER.to_be_bytes());
+        for byte in sector[0x38..].iter_mut() {
+            *byte = 7;
+        }
+        checksum_inode_sector(&mut sector);
+        let (_, inode) = MFSINode::parse(&sector, 0, 0, false).unwrap();
+        let inline = SECTOR_SIZE - 0x38;
+
+        let mut reader = reader(&inode, MFSFileReaderOptions::default());
+        assert_eq!(reader.len(), inline as u64);
+
+        let mut contents = vec![];
+        reader.seek(SeekFrom::Start(600)).unwrap();
+        reader.read_to_end(&mut contents).unwrap();
+        assert!(contents.is_empty());
+
+        reader.seek(SeekFrom::Start(400)).unwrap();
+        reader.read_to_end(&mut contents).unwrap();
+        assert_eq!(contents, vec![7; inline - 400]);
+    }
+}
@@ -2,21 +2,22 @@ extern crate chrono;
 extern crate nom;
 extern crate ovit_util;
 
-use crate::MFSEntry;
+use crate::{
+    mfs_checksum_fallback, mfs_compute_crc, MFSChecksummed, MFSDbObject, MFSDirectoryHeader,
+    MFSEntry, MFSSchema, MFSVolumes,
+};
 use chrono::{DateTime, TimeZone, Utc};
-use log::error;
+use log::{error, warn};
 use nom::{
     bytes::streaming::{tag, take},
     error::ErrorKind,
     multi::count,
-    multi::fold_many0,
-    number::streaming::{be_u16, be_u32, be_u8},
+    number::streaming::{be_u16, be_u32, be_u64, be_u8},
     Err, IResult,
 };
-use ovit_util::{
-    get_block_from_drive_and_correct_order, get_block_from_file, get_blocks_from_file,
-};
-use std::fs::File;
+use ovit_util::{BlockSource, Error, Result, SECTOR_SIZE};
+use std::convert::TryInto;
+use std::sync::Arc;
 
 #[derive(Debug, PartialEq, Eq, Clone)]
 pub enum MFSINodeType {
@@ -60,6 +61,13 @@ impl MFSINodeDataBlock {
             },
         ))
     }
+
+    pub fn parse_64(input: &[u8]) -> IResult<&[u8], MFSINodeDataBlock> {
+        let (input, sector) = be_u64(input)?;
+        let (input, count) = be_u32(input)?;
+
+        Ok((input, MFSINodeDataBlock { sector, count }))
+    }
 }
 
 #[derive(Debug)]
@@ -82,26 +90,32 @@ pub struct MFSINode {
     pub datablocks: Vec<MFSINodeDataBlock>,
 
     //Added for my conveinence
+    /// Where the partition holding the inode table starts on the drive, only used to work
+    ///  out `sector_on_drive`. Data is found through the volumes in `get_data`.
     pub partition_starting_sector: u64,
     pub sector_in_map: u64,
     pub sector_on_drive: u64,
+    computed_checksum: u32,
 }
 
+pub const INODE_SIGNATURE: u32 = 0x9123_1EBC;
 pub const INODE_DATA_IN_HEADER: u32 = 0x4000_0000;
 pub const INODE_CHAINED_FLAG: u32 = 0x8000_0000;
+pub(crate) const INODE_CHECKSUM_OFFSET: usize = 0x30;
 
 impl MFSINode {
     pub fn parse(
         input: &[u8],
         partition_starting_sector: u64,
         sector: u64,
+        is_64_bit: bool,
     ) -> IResult<&[u8], MFSINode> {
         let (input, fsid) = be_u32(input)?;
         let (input, refcount) = be_u32(input)?;
         let (input, bootcycles) = be_u32(input)?;
         let (input, bootsecs) = be_u32(input)?;
         let (input, inode) = be_u32(input)?; // Should be (sectornum - 1122) / 2
-        let (input, _) = take(4 as usize)(input)?;
+        let (input, _) = take(4usize)(input)?;
         let (input, size) = be_u32(input)?;
         let (input, blocksize) = be_u32(input)?;
         let (input, blockused) = be_u32(input)?;
@@ -116,9 +130,8 @@ impl MFSINode {
             let data: Vec<u8> = input
                 .to_vec()
                 .chunks(4)
-                .filter(|chunk| *chunk != &*vec![0xDEu8, 0xADu8, 0xBEu8, 0xEFu8])
-                .map(|chunk| chunk.to_vec())
-                .flatten()
+                .filter(|chunk| *chunk != [0xDEu8, 0xADu8, 0xBEu8, 0xEFu8])
+                .flat_map(|chunk| chunk.to_vec())
                 .collect();
             let input: &[u8] = &[];
             (input, data)
@@ -133,7 +146,12 @@ impl MFSINode {
         let (input, datablocks) = if flags == INODE_DATA_IN_HEADER {
             (input, vec![])
         } else {
-            count(MFSINodeDataBlock::parse, numblocks as usize)(input)?
+            let datablock = if is_64_bit {
+                MFSINodeDataBlock::parse_64
+            } else {
+                MFSINodeDataBlock::parse
+            };
+            count(datablock, numblocks as usize)(input)?
             // let (input, datablock) = MFSINodeDataBlock::parse(input)?;
             // (input, vec![datablock])
         };
@@ -149,7 +167,7 @@ impl MFSINode {
                 size,
                 blocksize,
                 blockused,
-                last_modified: Utc.timestamp(i64::from(last_modified), 0),
+                last_modified: Utc.timestamp_opt(i64::from(last_modified), 0).unwrap(),
                 r#type,
                 zone,
                 checksum,
@@ -162,107 +180,170 @@ impl MFSINode {
                 partition_starting_sector,
                 sector_in_map: sector,
                 sector_on_drive: partition_starting_sector + sector,
+                computed_checksum: 0,
             },
         ))
     }
 
-    pub fn from_path_at_sector(
-        path: &str,
+    pub fn from_source_at_sector(
+        source: &dyn BlockSource,
         partition_starting_sector: u64,
         sector: u64,
-        is_byte_swapped: bool,
-    ) -> Result<MFSINode, String> {
-        let inode_bytes =
-            get_block_from_file(path, partition_starting_sector + sector, is_byte_swapped)?;
-
-        match MFSINode::parse(&inode_bytes, partition_starting_sector, sector) {
-            Ok((_, inode)) => Ok(inode),
-            Err(err) => Err(format!("Could not open inode with err {:?}", err)),
+        is_64_bit: bool,
+    ) -> Result<MFSINode> {
+        let inode_bytes = source.read_bytes(partition_starting_sector + sector, SECTOR_SIZE)?;
+
+        let signature = u32::from_be_bytes(inode_bytes[0x2C..0x30].try_into().unwrap());
+        if signature != INODE_SIGNATURE {
+            return Err(Error::BadMagic {
+                structure: "inode",
+                expected: &[INODE_SIGNATURE],
+                found: signature,
+            });
+        }
+
+        match MFSINode::parse(&inode_bytes, partition_starting_sector, sector, is_64_bit) {
+            Ok((_, inode)) => Ok(MFSINode {
+                computed_checksum: mfs_compute_crc(&inode_bytes, INODE_CHECKSUM_OFFSET),
+                ..inode
+            }),
+            Err(err) => Err(Error::parse("inode", &inode_bytes, err)),
         }
     }
 
-    pub fn from_file_at_sector(
-        file: &mut File,
+    /// Read the inode at `sector`, falling back to the redundant copy in the following
+    ///  sector when the primary is unreadable or fails its checksum.
+    pub fn from_source_at_sector_with_fallback(
+        source: &dyn BlockSource,
         partition_starting_sector: u64,
         sector: u64,
-        is_byte_swapped: bool,
-    ) -> Result<MFSINode, String> {
-        let inode_bytes = get_block_from_drive_and_correct_order(
-            file,
-            partition_starting_sector + sector,
-            is_byte_swapped,
-        )?;
-
-        match MFSINode::parse(&inode_bytes, partition_starting_sector, sector) {
-            Ok((_, inode)) => Ok(inode),
-            Err(err) => Err(format!("Could not open inode with err {:?}", err)),
-        }
-    }
+        is_64_bit: bool,
+    ) -> Result<MFSINode> {
+        let primary =
+            MFSINode::from_source_at_sector(source, partition_starting_sector, sector, is_64_bit);
 
-    pub fn get_entries_from_directory(&self, input_path: String) -> Result<Vec<MFSEntry>, String> {
-        let block = if self.numblocks != 0 {
-            get_blocks_from_file(
-                &input_path,
-                self.partition_starting_sector + self.datablocks[0].sector,
-                self.datablocks[0].count as usize,
-                true,
+        mfs_checksum_fallback(partition_starting_sector + sector, primary, || {
+            MFSINode::from_source_at_sector(
+                source,
+                partition_starting_sector,
+                sector + 1,
+                is_64_bit,
             )
-            .unwrap()
-        } else {
-            self.data.clone()
+        })
+    }
+
+    /// Entries of a `Dir` inode, read across all of its datablocks. A directory is always
+    ///  a single inode, `INODE_CHAINED_FLAG` only links inodes whose FSIDs share a hash.
+    pub fn get_entries_from_directory(
+        &self,
+        source: &dyn BlockSource,
+        volumes: &MFSVolumes,
+    ) -> Result<Vec<MFSEntry>> {
+        let data = self.get_data(source, volumes)?;
+
+        let (entries_data, header) = match MFSDirectoryHeader::parse(&data) {
+            Ok(header) => header,
+            Err(err) => return Err(Error::parse("directory", &data, err)),
         };
 
-        match entries_with_initial_offset(&block) {
+        if header.size < 4 {
+            return Err(Error::Parse {
+                structure: "directory",
+                offset: 0,
+                kind: ErrorKind::Verify,
+            });
+        }
+
+        let mut size = usize::from(header.size);
+        if u32::from(header.size) > self.size {
+            warn!(
+                "Directory {} claims {} bytes but its inode holds {}, only reading those",
+                self.fsid, header.size, self.size
+            );
+            size = (self.size as usize).max(4);
+        }
+
+        match directory_entries(entries_data, size - 4) {
             Ok((_, entries)) => Ok(entries),
-            Err(_err) => Err("Could not get entries from directory".to_string()),
+            Err(err) => Err(Error::parse("directory", &data, err)),
         }
     }
 
-    pub fn get_data(&self, input_path: String) -> Result<Vec<u8>, String> {
+    /// The inode's data, read from its datablocks in MFS volume space unless it's stored
+    ///  in the inode itself.
+    pub fn get_data(&self, source: &dyn BlockSource, volumes: &MFSVolumes) -> Result<Vec<u8>> {
         if !self.data.is_empty() {
             Ok(self.data.clone())
-        } else if !self.datablocks.is_empty() {
-            Ok(self
-                .datablocks
-                .iter()
-                .map(|datablock| {
-                    match get_blocks_from_file(
-                        &input_path,
-                        datablock.sector,
-                        datablock.count as usize,
-                        true,
-                    ) {
-                        Ok(blocks) => blocks,
-                        // Not great, but... fine.
-                        Err(_err) => String::from("Dummy Data").as_bytes().to_vec(),
-                    }
-                })
-                .flatten()
-                .collect())
         } else {
-            Ok(vec![])
+            let mut data = vec![];
+            for datablock in self.datablocks.iter() {
+                data.extend(volumes.read_sectors(
+                    source,
+                    datablock.sector,
+                    u64::from(datablock.count),
+                )?);
+            }
+            Ok(data)
+        }
+    }
+
+    /// Size of the inode's data in bytes, streams track it in blocks rather than `size`.
+    pub fn stream_size(&self) -> u64 {
+        if self.r#type == MFSINodeType::Stream {
+            u64::from(self.blockused) * u64::from(self.blocksize)
+        } else {
+            u64::from(self.size)
         }
     }
+
+    /// Decode the tyDb object stored in a `Db` inode.
+    pub fn get_db_object(
+        &self,
+        source: &dyn BlockSource,
+        volumes: &MFSVolumes,
+        schema: &MFSSchema,
+    ) -> Result<MFSDbObject> {
+        let data = self.get_data(source, volumes)?;
+
+        MFSDbObject::from_bytes(&data, self.fsid, schema)
+    }
+}
+
+impl MFSChecksummed for MFSINode {
+    const STRUCTURE: &'static str = "inode";
+
+    fn stored_checksum(&self) -> u32 {
+        self.checksum
+    }
+
+    fn computed_checksum(&self) -> u32 {
+        self.computed_checksum
+    }
 }
 
-fn entries_with_initial_offset(input: &[u8]) -> IResult<&[u8], Vec<MFSEntry>> {
-    let (input, _offset) = take(4usize)(input)?;
-    let (input, entries) = fold_many0(MFSEntry::parse, Vec::new(), |mut acc: Vec<_>, item| {
-        acc.push(item);
-        acc
-    })(input)?;
+/// Parse the entries of a directory, which fill `length` bytes after its header.
+fn directory_entries(input: &[u8], length: usize) -> IResult<&[u8], Vec<MFSEntry>> {
+    let (input, mut entries_data) = take(length)(input)?;
+
+    let mut entries = vec![];
+    // Anything after the last entry has to be zeroed padding
+    while entries_data.iter().any(|byte| *byte != 0) {
+        let (remaining, entry) = MFSEntry::parse(entries_data)?;
+        entries.push(entry);
+        entries_data = remaining;
+    }
 
     Ok((input, entries))
 }
 
 #[derive(Debug)]
 pub struct MFSINodeIter {
-    pub source_file_path: String,
+    pub source: Arc<dyn BlockSource>,
     pub partition_starting_sector: u64,
-    pub is_source_byte_swapped: bool,
 
     pub next_inode_sector: u64,
     pub last_inode_sector: u64,
+    pub is_64_bit: bool,
 }
 
 impl Iterator for MFSINodeIter {
@@ -270,15 +351,15 @@ impl Iterator for MFSINodeIter {
 
     fn next(&mut self) -> Option<Self::Item> {
         if self.next_inode_sector != self.last_inode_sector + 1 {
-            let inode = match MFSINode::from_path_at_sector(
-                &self.source_file_path,
+            let inode = match MFSINode::from_source_at_sector_with_fallback(
+                &*self.source,
                 self.partition_starting_sector,
                 self.next_inode_sector,
-                self.is_source_byte_swapped,
+                self.is_64_bit,
             ) {
                 Ok(inode) => inode,
-                Err(_err) => {
-                    error!("{:?}", _err);
+                Err(err) => {
+                    error!("{}", err);
                     return None;
                 }
             };
@@ -304,3 +385,124 @@ impl ExactSizeIterator for MFSINodeIter {
         (self.last_inode_sector as usize - self.next_inode_sector as usize) / 2
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test_fixtures::{inode, inode_sector, numbered_disk, volumes};
+    use ovit_util::MemorySource;
+
+    /// Four sectors of directory holding entries 100 to 219, and the directory's size.
+    fn directory_sectors() -> (Vec<u8>, u32) {
+        let mut directory = vec![0u8; 4];
+        for fsid in 100..220u32 {
+            let name = format!("entry-{}", fsid);
+            directory.extend(&fsid.to_be_bytes());
+            directory.push((name.len() + 6) as u8);
+            directory.push(MFSINodeType::Db as u8);
+            directory.extend(name.as_bytes());
+        }
+        let size = directory.len() as u32;
+        directory[0..2].copy_from_slice(&(size as u16).to_be_bytes());
+        directory.resize(SECTOR_SIZE * 4, 0);
+
+        (directory, size)
+    }
+
+    #[test]
+    fn test_inode_falls_back_to_copy() {
+        let sector = inode_sector(1, MFSINodeType::File, 0, &[]);
+        let mut data = [sector.clone(), sector].concat();
+        // Damage the primary copy's size
+        data[0x18] = 0xFF;
+
+        let source = MemorySource::new(data);
+
+        let primary = MFSINode::from_source_at_sector(&source, 0, 0, false).unwrap();
+        assert!(primary.verify_checksum().is_err());
+
+        let inode = MFSINode::from_source_at_sector_with_fallback(&source, 0, 0, false).unwrap();
+        assert!(inode.is_checksum_valid());
+        assert_eq!(inode.sector_in_map, 1);
+        assert_eq!(inode.size, 0);
+    }
+
+    #[test]
+    fn test_64_bit_datablocks() {
+        let mut sector = inode_sector(2, MFSINodeType::File, 0, &[]);
+        sector[0x38..0x3C].copy_from_slice(&1u32.to_be_bytes());
+        sector[0x3C..0x44].copy_from_slice(&0x1_0000_0800u64.to_be_bytes());
+        sector[0x44..0x48].copy_from_slice(&0x800u32.to_be_bytes());
+
+        let (_, inode) = MFSINode::parse(&sector, 0, 0, true).unwrap();
+
+        assert_eq!(inode.datablocks.len(), 1);
+        assert_eq!(inode.datablocks[0].sector, 0x1_0000_0800);
+        assert_eq!(inode.datablocks[0].count, 0x800);
+    }
+
+    #[test]
+    fn test_directory_across_datablocks() {
+        let (directory, size) = directory_sectors();
+
+        // The second half of the directory comes first on disk
+        let disk = [&directory[SECTOR_SIZE * 2..], &directory[..SECTOR_SIZE * 2]].concat();
+        let source = MemorySource::new(disk);
+        let volumes = volumes(&[0]);
+        let mut inode = inode(3, MFSINodeType::Dir, size, &[(2, 2), (0, 2)]);
+
+        let entries = inode.get_entries_from_directory(&source, &volumes).unwrap();
+        assert_eq!(entries.len(), 120);
+        assert_eq!(entries[119].fsid, 219);
+        assert_eq!(entries[119].name, "entry-219");
+
+        // Only what the inode holds is read when the header claims more, the first ten
+        //  entries are 15 bytes each
+        inode.size = 4 + 10 * 15;
+        let entries = inode.get_entries_from_directory(&source, &volumes).unwrap();
+        assert_eq!(entries.len(), 10);
+        assert_eq!(entries[9].name, "entry-109");
+    }
+
+    #[test]
+    fn test_directory_padding() {
+        let (mut directory, size) = directory_sectors();
+        // Claim four bytes of padding after the last entry
+        directory[0..2].copy_from_slice(&(size as u16 + 4).to_be_bytes());
+        let volumes = volumes(&[0]);
+        let inode = inode(3, MFSINodeType::Dir, size + 4, &[(0, 4)]);
+
+        let source = MemorySource::new(directory.clone());
+        let entries = inode.get_entries_from_directory(&source, &volumes).unwrap();
+        assert_eq!(entries.len(), 120);
+
+        directory[size as usize + 2] = 1;
+        let source = MemorySource::new(directory);
+        assert!(inode.get_entries_from_directory(&source, &volumes).is_err());
+    }
+
+    #[test]
+    fn test_extent_across_volumes() {
+        // MFS sectors 2 to 5 are the last two of the first volume and first two of the second
+        let volumes = volumes(&[10, 20]);
+        let file = inode(4, MFSINodeType::File, 4 * SECTOR_SIZE as u32, &[(2, 4)]);
+
+        let data = file.get_data(&numbered_disk(24), &volumes).unwrap();
+        let sectors: Vec<u8> = data.chunks(SECTOR_SIZE).map(|sector| sector[0]).collect();
+        assert_eq!(sectors, [12, 13, 20, 21]);
+
+        let (directory, size) = directory_sectors();
+
+        let mut disk = vec![0u8; SECTOR_SIZE * 24];
+        disk[SECTOR_SIZE * 12..SECTOR_SIZE * 14].copy_from_slice(&directory[..SECTOR_SIZE * 2]);
+        disk[SECTOR_SIZE * 20..SECTOR_SIZE * 22].copy_from_slice(&directory[SECTOR_SIZE * 2..]);
+        let directory = inode(3, MFSINodeType::Dir, size, &[(2, 4)]);
+
+        let entries = directory
+            .get_entries_from_directory(&MemorySource::new(disk), &volumes)
+            .unwrap();
+        assert_eq!(entries.len(), 120);
+        assert_eq!(entries[119].fsid, 219);
+        assert_eq!(entries[119].name, "entry-219");
+    }
+}
@@ -1,14 +1,38 @@
+mod checksum;
+pub use checksum::*;
+
 mod volume_header;
 pub use volume_header::*;
 
 mod zone_map;
 pub use zone_map::*;
 
+mod zone_bitmap;
+pub use zone_bitmap::*;
+
 mod inode;
 pub use inode::*;
 
+mod file_reader;
+pub use file_reader::*;
+
 mod entry;
 pub use entry::*;
 
 mod volume;
 pub use volume::*;
+
+mod schema;
+pub use schema::*;
+
+mod db;
+pub use db::*;
+
+mod schema_types;
+pub use schema_types::*;
+
+mod tystream;
+pub use tystream::*;
+
+#[cfg(test)]
+mod test_fixtures;
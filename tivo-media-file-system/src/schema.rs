@@ -0,0 +1,165 @@
+use log::warn;
+use std::collections::{BTreeMap, HashMap};
+use std::sync::OnceLock;
+
+const BUNDLED_SCHEMA: &str = include_str!("schema.txt");
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum MFSSchemaKind {
+    Int,
+    String,
+    Object,
+    Subobject,
+    File,
+}
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum MFSSchemaCardinality {
+    /// At most one value. Objects on drives still leave these out, so it doesn't mean required.
+    Single,
+    Optional,
+    Multi,
+}
+
+#[derive(Debug, Clone)]
+pub struct MFSSchemaAttribute {
+    pub id: u8,
+    pub name: String,
+    pub kind: MFSSchemaKind,
+    pub cardinality: MFSSchemaCardinality,
+    /// The referenced object is deleted along with this one.
+    pub dependency: bool,
+    /// Computed by the TiVo from other attributes, rather than sent by the service.
+    pub derived: bool,
+}
+
+#[derive(Debug, Clone)]
+pub struct MFSSchemaType {
+    pub id: u16,
+    pub name: String,
+    pub attributes: BTreeMap<u8, MFSSchemaAttribute>,
+}
+
+impl MFSSchemaType {
+    pub fn attribute(&self, id: u8) -> Option<&MFSSchemaAttribute> {
+        self.attributes.get(&id)
+    }
+
+    pub fn attribute_by_name(&self, name: &str) -> Option<&MFSSchemaAttribute> {
+        self.attributes
+            .values()
+            .find(|attribute| attribute.name == name)
+    }
+}
+
+/// The tyDb types and attributes, one attribute per line of `schema.txt`:
+///  `type_id TypeName attr_id AttrName kind cardinality {}|dependency base|derived`
+#[derive(Debug, Clone, Default)]
+pub struct MFSSchema {
+    types: HashMap<u16, MFSSchemaType>,
+}
+
+impl MFSSchema {
+    /// The schema shipped with ovit, parsed once on first use.
+    pub fn bundled() -> &'static MFSSchema {
+        static SCHEMA: OnceLock<MFSSchema> = OnceLock::new();
+
+        SCHEMA.get_or_init(|| MFSSchema::parse(BUNDLED_SCHEMA))
+    }
+
+    /// Parse a schema, lines that don't match the format are skipped.
+    pub fn parse(input: &str) -> MFSSchema {
+        let mut schema = MFSSchema::default();
+
+        for (line_number, line) in input.lines().enumerate() {
+            if line.trim().is_empty() {
+                continue;
+            }
+
+            match MFSSchema::parse_line(line) {
+                Some((type_id, type_name, attribute)) => {
+                    schema
+                        .types
+                        .entry(type_id)
+                        .or_insert_with(|| MFSSchemaType {
+                            id: type_id,
+                            name: type_name.to_string(),
+                            attributes: BTreeMap::new(),
+                        })
+                        .attributes
+                        .insert(attribute.id, attribute);
+                }
+                None => warn!("Skipping schema line {}: {}", line_number + 1, line),
+            }
+        }
+
+        schema
+    }
+
+    fn parse_line(line: &str) -> Option<(u16, &str, MFSSchemaAttribute)> {
+        let fields: Vec<&str> = line.split_whitespace().collect();
+
+        if let [type_id, type_name, id, name, kind, cardinality, dependency, derived] = fields[..] {
+            Some((
+                type_id.parse().ok()?,
+                type_name,
+                MFSSchemaAttribute {
+                    id: id.parse().ok()?,
+                    name: name.to_string(),
+                    kind: match kind {
+                        "int" => MFSSchemaKind::Int,
+                        "string" => MFSSchemaKind::String,
+                        "object" => MFSSchemaKind::Object,
+                        "subobject" => MFSSchemaKind::Subobject,
+                        "file" => MFSSchemaKind::File,
+                        _ => return None,
+                    },
+                    cardinality: match cardinality {
+                        "single" => MFSSchemaCardinality::Single,
+                        "optional" => MFSSchemaCardinality::Optional,
+                        "multi" => MFSSchemaCardinality::Multi,
+                        _ => return None,
+                    },
+                    dependency: dependency == "dependency",
+                    derived: derived == "derived",
+                },
+            ))
+        } else {
+            None
+        }
+    }
+
+    pub fn get_type(&self, id: u16) -> Option<&MFSSchemaType> {
+        self.types.get(&id)
+    }
+
+    pub fn type_by_name(&self, name: &str) -> Option<&MFSSchemaType> {
+        self.types.values().find(|r#type| r#type.name == name)
+    }
+
+    pub fn types(&self) -> impl Iterator<Item = &MFSSchemaType> {
+        self.types.values()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_bundled_schema() {
+        let schema = MFSSchema::bundled();
+
+        let recording = schema.type_by_name("Recording").unwrap();
+        assert_eq!(recording.id, 14);
+        assert_eq!(schema.get_type(3).unwrap().name, "Program");
+
+        let title = schema
+            .type_by_name("Program")
+            .unwrap()
+            .attribute_by_name("Title")
+            .unwrap();
+        assert_eq!(title.kind, MFSSchemaKind::String);
+        assert!(!title.derived);
+    }
+}
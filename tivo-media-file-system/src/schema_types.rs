@@ -0,0 +1,171 @@
+use crate::{MFSDbObject, MFSDbSubobject, MFSDbValue};
+use ovit_util::Error;
+use std::fmt;
+
+/// A database object that doesn't fit the generated type it was read as.
+#[derive(Debug, Clone, PartialEq)]
+pub enum MFSDbError {
+    /// The object has no subobject of its own type.
+    NoPrimarySubobject,
+    /// The object lacks an attribute the code depends on.
+    MissingAttribute {
+        object: &'static str,
+        attribute: &'static str,
+    },
+    /// The object isn't of the schema type it was read as.
+    WrongObjectType { expected: &'static str, found: u16 },
+}
+
+impl fmt::Display for MFSDbError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            MFSDbError::NoPrimarySubobject => write!(f, "Object has no primary subobject"),
+            MFSDbError::MissingAttribute { object, attribute } => {
+                write!(f, "{} is missing attribute {}", object, attribute)
+            }
+            MFSDbError::WrongObjectType { expected, found } => write!(
+                f,
+                "Expected a {} object but found schema type {}",
+                expected, found
+            ),
+        }
+    }
+}
+
+impl std::error::Error for MFSDbError {}
+
+impl From<MFSDbError> for Error {
+    fn from(err: MFSDbError) -> Error {
+        Error::Schema(Box::new(err))
+    }
+}
+
+fn value(subobject: &MFSDbSubobject, id: u8) -> Option<&MFSDbValue> {
+    subobject
+        .attributes
+        .iter()
+        .find(|attribute| attribute.id == id)
+        .map(|attribute| &attribute.value)
+}
+
+fn ints(subobject: &MFSDbSubobject, id: u8) -> Vec<u32> {
+    match value(subobject, id) {
+        Some(MFSDbValue::Int(values)) => values.clone(),
+        _ => vec![],
+    }
+}
+
+fn strings(subobject: &MFSDbSubobject, id: u8) -> Vec<String> {
+    match value(subobject, id) {
+        Some(MFSDbValue::String(values)) => values.clone(),
+        _ => vec![],
+    }
+}
+
+/// FSIDs for object and file attributes, subobject IDs for subobject attributes.
+fn references(subobject: &MFSDbSubobject, id: u8) -> Vec<u32> {
+    match value(subobject, id) {
+        Some(MFSDbValue::Object(values))
+        | Some(MFSDbValue::Subobject(values))
+        | Some(MFSDbValue::File(values)) => values.clone(),
+        _ => vec![],
+    }
+}
+
+fn required<T>(
+    values: Vec<T>,
+    object: &'static str,
+    attribute: &'static str,
+) -> Result<T, MFSDbError> {
+    values
+        .into_iter()
+        .next()
+        .ok_or(MFSDbError::MissingAttribute { object, attribute })
+}
+
+fn check_type(
+    subobject: &MFSDbSubobject,
+    type_id: u16,
+    name: &'static str,
+) -> Result<(), MFSDbError> {
+    if subobject.r#type == type_id {
+        Ok(())
+    } else {
+        Err(MFSDbError::WrongObjectType {
+            expected: name,
+            found: subobject.r#type,
+        })
+    }
+}
+
+include!(concat!(env!("OUT_DIR"), "/schema_types.rs"));
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::MFSDbAttribute;
+
+    fn program_object(attributes: Vec<MFSDbAttribute>) -> MFSDbObject {
+        MFSDbObject {
+            fsid: 1,
+            subobjects: vec![MFSDbSubobject {
+                r#type: Program::TYPE_ID,
+                type_name: None,
+                flags: 0,
+                id: 1,
+                attributes,
+            }],
+        }
+    }
+
+    #[test]
+    fn test_program_from_object() {
+        let object = program_object(vec![
+            MFSDbAttribute {
+                id: 17,
+                name: None,
+                value: MFSDbValue::String(vec![String::from("Cosmos")]),
+            },
+            MFSDbAttribute {
+                id: 19,
+                name: None,
+                value: MFSDbValue::String(vec![String::from("A personal voyage")]),
+            },
+        ]);
+
+        let program = Program::from_object(&object).unwrap();
+
+        assert_eq!(program.title.as_deref(), Some("Cosmos"));
+        assert_eq!(program.description.as_deref(), Some("A personal voyage"));
+        assert!(program.genre.is_empty());
+
+        assert!(matches!(
+            Series::from_object(&object),
+            Err(MFSDbError::WrongObjectType { found: 3, .. })
+        ));
+        // Single attributes the code doesn't depend on are optional
+        assert_eq!(
+            Program::from_object(&program_object(vec![])).unwrap().title,
+            None
+        );
+    }
+
+    #[test]
+    fn test_required_attribute() {
+        let subobject = MFSDbSubobject {
+            r#type: RecordingPart::TYPE_ID,
+            type_name: None,
+            flags: 0,
+            id: 2,
+            attributes: vec![],
+        };
+
+        assert_eq!(
+            RecordingPart::from_subobject(&subobject),
+            Err(MFSDbError::MissingAttribute {
+                object: "RecordingPart",
+                attribute: "File",
+            })
+        );
+    }
+}
@@ -0,0 +1,71 @@
+use crate::{
+    mfs_compute_crc, MFSINode, MFSINodeType, MFSVolume, MFSVolumes, INODE_CHECKSUM_OFFSET,
+    INODE_SIGNATURE,
+};
+use ovit_util::{MemorySource, SECTOR_SIZE};
+
+/// A disk of `count` sectors, every one filled with its own number.
+pub(crate) fn numbered_disk(count: u8) -> MemorySource {
+    MemorySource::new(
+        (0..count)
+            .flat_map(|sector| vec![sector; SECTOR_SIZE])
+            .collect(),
+    )
+}
+
+/// Volumes of four sectors each, at `disk_sectors` on the disk.
+pub(crate) fn volumes(disk_sectors: &[u32]) -> MFSVolumes {
+    MFSVolumes::from_volumes(
+        disk_sectors
+            .iter()
+            .enumerate()
+            .map(|(index, disk_sector)| MFSVolume {
+                disk_sector: *disk_sector,
+                sector_start: index as u32 * 4,
+                sector_count: 4,
+            })
+            .collect(),
+    )
+}
+
+/// An inode sector with a valid checksum and 32-bit `datablocks` of `(sector, count)`.
+pub(crate) fn inode_sector(
+    fsid: u32,
+    r#type: MFSINodeType,
+    size: u32,
+    datablocks: &[(u32, u32)],
+) -> Vec<u8> {
+    let mut sector = vec![0u8; SECTOR_SIZE];
+    sector[0..4].copy_from_slice(&fsid.to_be_bytes());
+    sector[0x18..0x1C].copy_from_slice(&size.to_be_bytes());
+    sector[0x28] = r#type as u8;
+    sector[0x2C..0x30].copy_from_slice(&INODE_SIGNATURE.to_be_bytes());
+    sector[0x38..0x3C].copy_from_slice(&(datablocks.len() as u32).to_be_bytes());
+    for (index, (block_sector, count)) in datablocks.iter().enumerate() {
+        let offset = 0x3C + index * 8;
+        sector[offset..offset + 4].copy_from_slice(&block_sector.to_be_bytes());
+        sector[offset + 4..offset + 8].copy_from_slice(&count.to_be_bytes());
+    }
+
+    checksum_inode_sector(&mut sector);
+
+    sector
+}
+
+/// Recompute the checksum of an inode sector after changing it.
+pub(crate) fn checksum_inode_sector(sector: &mut [u8]) {
+    let checksum = mfs_compute_crc(sector, INODE_CHECKSUM_OFFSET);
+    sector[INODE_CHECKSUM_OFFSET..INODE_CHECKSUM_OFFSET + 4]
+        .copy_from_slice(&checksum.to_be_bytes());
+}
+
+pub(crate) fn inode(
+    fsid: u32,
+    r#type: MFSINodeType,
+    size: u32,
+    datablocks: &[(u32, u32)],
+) -> MFSINode {
+    let sector = inode_sector(fsid, r#type, size, datablocks);
+
+    MFSINode::parse(&sector, 0, 0, false).unwrap().1
+}
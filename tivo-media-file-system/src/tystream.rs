@@ -0,0 +1,305 @@
+extern crate nom;
+
+use crate::{MFSINode, MFSINodeDataBlock, MFSVolumes};
+use nom::{
+    bytes::complete::take,
+    multi::count,
+    number::complete::{be_u64, be_u8, le_u16},
+    IResult,
+};
+use ovit_util::{BlockSource, Error, Result, SECTOR_SIZE};
+use std::collections::VecDeque;
+use std::sync::Arc;
+
+/// Recordings are stored as a series of fixed size chunks.
+pub const TY_CHUNK_SIZE: usize = 128 * 1024;
+/// Chunks starting with this hold stream metadata rather than records.
+pub const TY_STREAM_HEADER_MAGIC: u32 = 0xF546_7ABD;
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum TyRecordType {
+    /// MPEG-2 video PES data.
+    Video,
+    /// MPEG-1 layer 2 or AC-3 audio PES data.
+    Audio,
+    /// A pair of EIA-608 closed caption bytes.
+    ClosedCaption,
+    /// A pair of EIA-608 Extended Data Services bytes.
+    Xds,
+    TivoPrivate,
+    Other(u8),
+}
+
+impl From<u8> for TyRecordType {
+    fn from(r#type: u8) -> TyRecordType {
+        match r#type {
+            0xE0 => TyRecordType::Video,
+            0xC0 => TyRecordType::Audio,
+            0x01 => TyRecordType::ClosedCaption,
+            0x02 => TyRecordType::Xds,
+            0x03 => TyRecordType::TivoPrivate,
+            other => TyRecordType::Other(other),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct TyRecord {
+    pub r#type: TyRecordType,
+    pub subtype: u8,
+    /// Size of the payload, zero for extended records.
+    pub size: u32,
+    pub timestamp: u64,
+    /// The payload, or the two bytes extended records like closed captions and XDS carry in
+    ///  their header.
+    pub data: Vec<u8>,
+    /// Index of the chunk the record was read from.
+    pub chunk: u64,
+}
+
+impl TyRecord {
+    pub fn is_ac3(&self) -> bool {
+        self.r#type == TyRecordType::Audio && self.subtype == 0x09
+    }
+
+    /// Parse a record header, the payload is filled in afterwards from the end of the chunk.
+    ///
+    /// The first three bytes hold a 20-bit payload size then a 4-bit subtype. Extended records
+    ///  set the top bit instead and carry two data bytes in the nibbles that would be the size.
+    fn parse_header(input: &[u8]) -> IResult<&[u8], TyRecord> {
+        let (input, header) = take(3usize)(input)?;
+        let (input, r#type) = be_u8(input)?;
+        let (input, _) = take(4usize)(input)?;
+        let (input, timestamp) = be_u64(input)?;
+
+        let (size, data) = if header[0] & 0x80 != 0 {
+            let data = vec![
+                (header[0] & 0x0F) << 4 | header[1] >> 4,
+                (header[1] & 0x0F) << 4 | header[2] >> 4,
+            ];
+            (0, data)
+        } else {
+            let size = ((u32::from(header[0]) << 8 | u32::from(header[1])) << 4)
+                | u32::from(header[2] >> 4);
+            (size, vec![])
+        };
+
+        Ok((
+            input,
+            TyRecord {
+                r#type: TyRecordType::from(r#type),
+                subtype: header[2] & 0x0F,
+                size,
+                timestamp,
+                data,
+                chunk: 0,
+            },
+        ))
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct TyChunk {
+    pub sequence: u16,
+    pub records: Vec<TyRecord>,
+}
+
+impl TyChunk {
+    fn parse(input: &[u8], index: u64) -> IResult<&[u8], TyChunk> {
+        let (input, record_count) = le_u16(input)?;
+        let (input, sequence) = le_u16(input)?;
+        let (mut payload, mut records) =
+            count(TyRecord::parse_header, record_count as usize)(input)?;
+
+        // Payloads follow the headers in the same order
+        for record in records.iter_mut() {
+            let (remaining, data) = take(record.size as usize)(payload)?;
+            payload = remaining;
+
+            if record.data.is_empty() {
+                record.data = data.to_vec();
+            }
+            record.chunk = index;
+        }
+
+        Ok((payload, TyChunk { sequence, records }))
+    }
+
+    /// Parse chunk number `index` of a stream, `None` for stream header chunks.
+    pub fn from_bytes(input: &[u8], index: u64) -> Result<Option<TyChunk>> {
+        if input.len() >= 4 && input[0..4] == TY_STREAM_HEADER_MAGIC.to_be_bytes() {
+            return Ok(None);
+        }
+
+        match TyChunk::parse(input, index) {
+            Ok((_, chunk)) => Ok(Some(chunk)),
+            Err(err) => Err(Error::parse("ty chunk", input, err)),
+        }
+    }
+}
+
+/// The records of a `Stream` inode, read a chunk at a time.
+#[derive(Debug)]
+pub struct TyStream {
+    source: Arc<dyn BlockSource>,
+    volumes: MFSVolumes,
+    datablocks: Vec<MFSINodeDataBlock>,
+
+    chunk_count: u64,
+    next_chunk: u64,
+    records: VecDeque<TyRecord>,
+}
+
+impl TyStream {
+    pub fn new(
+        source: Arc<dyn BlockSource>,
+        volumes: &MFSVolumes,
+        inode: &MFSINode,
+    ) -> Result<TyStream> {
+        Ok(TyStream {
+            source,
+            volumes: volumes.clone(),
+            datablocks: inode.datablocks.clone(),
+
+            chunk_count: inode.stream_size() / TY_CHUNK_SIZE as u64,
+            next_chunk: 0,
+            records: VecDeque::new(),
+        })
+    }
+
+    pub fn chunk_count(&self) -> u64 {
+        self.chunk_count
+    }
+
+    /// Read the raw bytes of chunk number `index`.
+    pub fn read_chunk(&self, index: u64) -> Result<Vec<u8>> {
+        const CHUNK_SECTORS: u64 = (TY_CHUNK_SIZE / SECTOR_SIZE) as u64;
+
+        let mut offset = index * CHUNK_SECTORS;
+        let mut remaining = CHUNK_SECTORS;
+        let mut chunk = Vec::with_capacity(TY_CHUNK_SIZE);
+
+        // Chunks may straddle the end of an extent
+        for datablock in self.datablocks.iter() {
+            let count = u64::from(datablock.count);
+
+            if offset >= count {
+                offset -= count;
+                continue;
+            }
+
+            let sectors = (count - offset).min(remaining);
+            chunk.extend(self.volumes.read_sectors(
+                &*self.source,
+                datablock.sector + offset,
+                sectors,
+            )?);
+
+            remaining -= sectors;
+            offset = 0;
+
+            if remaining == 0 {
+                return Ok(chunk);
+            }
+        }
+
+        Err(Error::NotFound("ty chunk"))
+    }
+
+    pub fn chunk(&self, index: u64) -> Result<Option<TyChunk>> {
+        TyChunk::from_bytes(&self.read_chunk(index)?, index)
+    }
+}
+
+impl Iterator for TyStream {
+    type Item = Result<TyRecord>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while self.records.is_empty() {
+            if self.next_chunk >= self.chunk_count {
+                return None;
+            }
+
+            let index = self.next_chunk;
+            self.next_chunk += 1;
+
+            match self.chunk(index) {
+                Ok(Some(chunk)) => self.records.extend(chunk.records),
+                Ok(None) => {}
+                Err(err) => return Some(Err(err)),
+            }
+        }
+
+        self.records.pop_front().map(Ok)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn record_header(r#type: u8, subtype: u8, size: u32) -> Vec<u8> {
+        let mut header = vec![
+            (size >> 12) as u8,
+            (size >> 4) as u8,
+            ((size & 0x0F) as u8) << 4 | subtype,
+            r#type,
+            0,
+            0,
+            0,
+            0,
+        ];
+        header.extend(&0x1234u64.to_be_bytes());
+        header
+    }
+
+    #[test]
+    fn test_parse_record_headers() {
+        // Headers as a Series 2 writes them, a video PES record of 0x7F6 bytes then an
+        //  extended closed caption record carrying 0x94 0x2C
+        let headers = [
+            0x00, 0x7F, 0x66, 0xE0, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x01, 0x2A, 0x05, 0xF2,
+            0x1D, 0x80, 0x89, 0x42, 0xC1, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x01, 0x2A,
+            0x05, 0xF3, 0x52, 0x40,
+        ];
+
+        let (input, video) = TyRecord::parse_header(&headers).unwrap();
+        let (_, caption) = TyRecord::parse_header(input).unwrap();
+
+        assert_eq!(video.r#type, TyRecordType::Video);
+        assert_eq!(video.subtype, 0x06);
+        assert_eq!(video.size, 0x7F6);
+        assert_eq!(video.timestamp, 0x012A_05F2_1D80);
+        assert!(video.data.is_empty());
+
+        assert_eq!(caption.r#type, TyRecordType::ClosedCaption);
+        assert_eq!(caption.subtype, 0x01);
+        assert_eq!(caption.size, 0);
+        assert_eq!(caption.data, vec![0x94, 0x2C]);
+    }
+
+    #[test]
+    fn test_parse_chunk() {
+        let mut chunk = vec![2, 0, 7, 0];
+        chunk.extend(record_header(0xE0, 0x06, 0x1001));
+        chunk.extend(record_header(0xC0, 0x09, 4));
+        chunk.extend(vec![0xAA; 0x1001]);
+        chunk.extend(vec![0x0B, 0x77, 0, 0]);
+        chunk.resize(TY_CHUNK_SIZE, 0);
+
+        let chunk = TyChunk::from_bytes(&chunk, 5).unwrap().unwrap();
+
+        assert_eq!(chunk.sequence, 7);
+        assert_eq!(chunk.records.len(), 2);
+        assert_eq!(chunk.records[0].r#type, TyRecordType::Video);
+        assert_eq!(chunk.records[0].size, 0x1001);
+        assert_eq!(chunk.records[0].timestamp, 0x1234);
+        assert!(chunk.records[1].is_ac3());
+        assert_eq!(chunk.records[1].data, vec![0x0B, 0x77, 0, 0]);
+        assert_eq!(chunk.records[1].chunk, 5);
+
+        let mut header_chunk = TY_STREAM_HEADER_MAGIC.to_be_bytes().to_vec();
+        header_chunk.resize(TY_CHUNK_SIZE, 0);
+        assert_eq!(TyChunk::from_bytes(&header_chunk, 0).unwrap(), None);
+    }
+}
@@ -1,6 +1,7 @@
 extern crate apple_partition_map;
 
 use apple_partition_map::ApplePartitionMap;
+use ovit_util::{BlockSource, Error, Result};
 
 #[derive(Debug, Clone, Copy)]
 pub struct MFSVolume {
@@ -19,7 +20,6 @@ impl MFSVolumes {
         let volumes: Vec<MFSVolume> = partition_map
             .partitions
             .iter()
-            .cloned()
             .filter(|partition| partition.r#type == "MFS")
             .fold(vec![], |mut acc, partition| {
                 acc.push(MFSVolume {
@@ -37,28 +37,78 @@ impl MFSVolumes {
         MFSVolumes { volumes }
     }
 
-    pub fn find_sector_volume(&self, sector: u64) -> MFSVolume {
-        *self
-            .clone()
-            .volumes
+    /// Volumes laid out by hand rather than read from a partition map.
+    pub fn from_volumes(volumes: Vec<MFSVolume>) -> MFSVolumes {
+        MFSVolumes { volumes }
+    }
+
+    pub fn find_sector_volume(&self, sector: u64) -> Result<MFSVolume> {
+        self.volumes
             .iter()
-            // 34254847
             .find(|volume| {
                 (volume.sector_start as u64) <= sector
-                    && sector <= volume.sector_start as u64 + volume.sector_count as u64
+                    && sector < volume.sector_start as u64 + volume.sector_count as u64
             })
-            .expect(&format!(
-                "Could not find volume containing sector {}",
-                sector
-            ))
+            .copied()
+            .ok_or(Error::VolumeSectorOutOfRange(sector))
     }
 
-    pub fn sector_to_disk_location(self, sector: u64) -> u64 {
-        let volume = self.find_sector_volume(sector);
+    pub fn sector_to_disk_location(&self, sector: u64) -> Result<u64> {
+        let volume = self.find_sector_volume(sector)?;
 
         let volume_relative_sector = sector - volume.sector_start as u64;
-        let sector_on_disk = volume.disk_sector as u64 + volume_relative_sector;
 
-        sector_on_disk
+        Ok(volume.disk_sector as u64 + volume_relative_sector)
+    }
+
+    /// Read `count` sectors starting at `sector` in MFS volume space, following the extent
+    ///  from the end of one volume onto the start of the next.
+    pub fn read_sectors(
+        &self,
+        source: &dyn BlockSource,
+        sector: u64,
+        count: u64,
+    ) -> Result<Vec<u8>> {
+        let mut sector = sector;
+        let mut remaining = count;
+        let mut data = vec![];
+
+        while remaining > 0 {
+            let volume = self.find_sector_volume(sector)?;
+            let volume_end = u64::from(volume.sector_start) + u64::from(volume.sector_count);
+            let sectors = remaining.min(volume_end - sector);
+
+            let disk_sector =
+                u64::from(volume.disk_sector) + sector - u64::from(volume.sector_start);
+            data.extend(source.read_sectors(disk_sector, sectors as usize)?);
+
+            sector += sectors;
+            remaining -= sectors;
+        }
+
+        Ok(data)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::test_fixtures::{numbered_disk, volumes};
+    use ovit_util::SECTOR_SIZE;
+
+    #[test]
+    fn test_read_sectors_across_volumes() {
+        // The volumes aren't contiguous on disk
+        let source = numbered_disk(16);
+        let volumes = volumes(&[2, 10]);
+
+        assert_eq!(volumes.sector_to_disk_location(3).unwrap(), 5);
+        assert_eq!(volumes.sector_to_disk_location(4).unwrap(), 10);
+        assert!(volumes.sector_to_disk_location(8).is_err());
+
+        let data = volumes.read_sectors(&source, 2, 4).unwrap();
+        let sectors: Vec<u8> = data.chunks(SECTOR_SIZE).map(|sector| sector[0]).collect();
+        assert_eq!(sectors, vec![4, 5, 10, 11]);
+
+        assert!(volumes.read_sectors(&source, 6, 3).is_err());
     }
 }
@@ -6,32 +6,53 @@ use apple_partition_map::Partition;
 use nom::{
     bytes::streaming::{tag, take},
     error::ErrorKind,
-    number::streaming::be_u32,
+    number::streaming::{be_u32, be_u64},
     Err, IResult,
 };
-use ovit_util::get_block_from_drive_and_correct_order;
-use std::fs::File;
+use ovit_util::{BlockSource, Error, Result};
+use std::convert::TryInto;
 
-fn string(input: &[u8]) -> IResult<&[u8], String> {
-    let (input, str_bytes) = take(128 as usize)(input)?;
+use crate::{mfs_compute_crc, MFSChecksummed};
+
+fn string(input: &[u8], length: usize) -> IResult<&[u8], String> {
+    let (input, str_bytes) = take(length)(input)?;
     match String::from_utf8(str_bytes.to_vec()) {
         Ok(string) => Ok((input, string.trim_matches(char::from(0)).to_string())),
         Err(_) => Err(Err::Error((input, ErrorKind::ParseTo))),
     }
 }
 
-#[derive(Debug, PartialEq)]
+pub const MFS_VOLUME_HEADER_MAGIC: u32 = 0xABBA_FEED;
+pub const MFS_VOLUME_HEADER_SIZE: usize = 0xE8;
+/// Series 2 and later drives use 64-bit sector pointers
+pub const MFS_VOLUME_HEADER_64_MAGIC: u32 = 0xEBBA_FEED;
+pub const MFS_VOLUME_HEADER_64_SIZE: usize = 0x108;
+const MFS_VOLUME_HEADER_CHECKSUM_OFFSET: usize = 0x08;
+
+#[derive(Debug, Clone, PartialEq)]
 pub struct MFSVolumeHeader {
     pub state: u32,
     pub checksum: u32,
     pub root_fsid: u32,
     pub firstpartsize: u32,
     pub partitionlist: String,
-    pub total_sectors: u32,
+    pub total_sectors: u64,
+    pub logstart: u64,
+    pub lognsectors: u32,
+    pub volhdrlogstamp: u32,
+    pub unkstart: u64,
+    pub unksectors: u32,
+    pub unkstamp: u32,
     pub next_zonemap_sector: u64,
     pub next_zonemap_backup_sector: u64,
-    pub next_zonemap_partition_size: u32,
+    pub next_zonemap_sector_length: u64,
+    pub next_zonemap_partition_size: u64,
+    pub next_zonemap_min_allocation: u64,
     pub next_fsid: u32,
+    pub bootcycles: u32,
+    pub bootsecs: u32,
+    pub is_64_bit: bool,
+    computed_checksum: u32,
 }
 
 impl MFSVolumeHeader {
@@ -39,30 +60,30 @@ impl MFSVolumeHeader {
         let (input, state) = be_u32(input)?;
         let (input, _) = tag([0xAB, 0xBA, 0xFE, 0xED])(input)?;
         let (input, checksum) = be_u32(input)?;
-        let (input, _) = take(4 as usize)(input)?;
+        let (input, _) = take(4usize)(input)?;
         let (input, root_fsid) = be_u32(input)?;
-        let (input, _) = take(4 as usize)(input)?;
+        let (input, _) = take(4usize)(input)?;
         let (input, firstpartsize) = be_u32(input)?;
-        let (input, _) = take(4 as usize)(input)?;
-        let (input, _) = take(4 as usize)(input)?;
-        let (input, partitionlist) = string(input)?;
+        let (input, _) = take(4usize)(input)?;
+        let (input, _) = take(4usize)(input)?;
+        let (input, partitionlist) = string(input, 128)?;
         let (input, total_sectors) = be_u32(input)?;
-        let (input, _) = take(4 as usize)(input)?;
-        let (input, _logstart) = be_u32(input)?;
-        let (input, _lognsectors) = be_u32(input)?;
-        let (input, _volhdrlogstamp) = be_u32(input)?;
-        let (input, _unkstart) = be_u32(input)?;
-        let (input, _unksectors) = be_u32(input)?;
-        let (input, _unkstamp) = be_u32(input)?;
+        let (input, _) = take(4usize)(input)?;
+        let (input, logstart) = be_u32(input)?;
+        let (input, lognsectors) = be_u32(input)?;
+        let (input, volhdrlogstamp) = be_u32(input)?;
+        let (input, unkstart) = be_u32(input)?;
+        let (input, unksectors) = be_u32(input)?;
+        let (input, unkstamp) = be_u32(input)?;
         let (input, next_zonemap_sector) = be_u32(input)?;
         let (input, next_zonemap_backup_sector) = be_u32(input)?;
-        let (input, _next_zonemap_sector_length) = be_u32(input)?;
+        let (input, next_zonemap_sector_length) = be_u32(input)?;
         let (input, next_zonemap_partition_size) = be_u32(input)?;
-        let (input, _next_zonemap_min_allocation) = be_u32(input)?;
+        let (input, next_zonemap_min_allocation) = be_u32(input)?;
         let (input, next_fsid) = be_u32(input)?;
-        let (input, _bootcycles) = be_u32(input)?;
-        let (input, _bootsecs) = be_u32(input)?;
-        let (input, _) = take(4 as usize)(input)?;
+        let (input, bootcycles) = be_u32(input)?;
+        let (input, bootsecs) = be_u32(input)?;
+        let (input, _) = take(4usize)(input)?;
 
         Ok((
             input,
@@ -72,29 +93,228 @@ impl MFSVolumeHeader {
                 root_fsid,
                 firstpartsize,
                 partitionlist,
-                total_sectors,
+                total_sectors: u64::from(total_sectors),
+                logstart: u64::from(logstart),
+                lognsectors,
+                volhdrlogstamp,
+                unkstart: u64::from(unkstart),
+                unksectors,
+                unkstamp,
                 next_zonemap_sector: u64::from(next_zonemap_sector),
                 next_zonemap_backup_sector: u64::from(next_zonemap_backup_sector),
+                next_zonemap_sector_length: u64::from(next_zonemap_sector_length),
+                next_zonemap_partition_size: u64::from(next_zonemap_partition_size),
+                next_zonemap_min_allocation: u64::from(next_zonemap_min_allocation),
+                next_fsid,
+                bootcycles,
+                bootsecs,
+                is_64_bit: false,
+                computed_checksum: 0,
+            },
+        ))
+    }
+
+    fn parse_64(input: &[u8]) -> IResult<&[u8], MFSVolumeHeader> {
+        let (input, state) = be_u32(input)?;
+        let (input, _) = tag([0xEB, 0xBA, 0xFE, 0xED])(input)?;
+        let (input, checksum) = be_u32(input)?;
+        let (input, _) = take(4usize)(input)?;
+        let (input, root_fsid) = be_u32(input)?;
+        let (input, _) = take(4usize)(input)?;
+        let (input, firstpartsize) = be_u32(input)?;
+        let (input, _) = take(4usize)(input)?;
+        let (input, _) = take(4usize)(input)?;
+        // Longer than the 32-bit list to keep the 64-bit fields aligned
+        let (input, partitionlist) = string(input, 132)?;
+        let (input, total_sectors) = be_u64(input)?;
+        let (input, logstart) = be_u64(input)?;
+        let (input, unkstart) = be_u64(input)?;
+        let (input, lognsectors) = be_u32(input)?;
+        let (input, volhdrlogstamp) = be_u32(input)?;
+        let (input, unksectors) = be_u32(input)?;
+        let (input, unkstamp) = be_u32(input)?;
+        let (input, next_zonemap_sector) = be_u64(input)?;
+        let (input, next_zonemap_backup_sector) = be_u64(input)?;
+        let (input, next_zonemap_sector_length) = be_u64(input)?;
+        let (input, next_zonemap_partition_size) = be_u64(input)?;
+        let (input, next_zonemap_min_allocation) = be_u64(input)?;
+        let (input, next_fsid) = be_u32(input)?;
+        let (input, bootcycles) = be_u32(input)?;
+        let (input, bootsecs) = be_u32(input)?;
+        let (input, _) = take(4usize)(input)?;
+
+        Ok((
+            input,
+            MFSVolumeHeader {
+                state,
+                checksum,
+                root_fsid,
+                firstpartsize,
+                partitionlist,
+                total_sectors,
+                logstart,
+                lognsectors,
+                volhdrlogstamp,
+                unkstart,
+                unksectors,
+                unkstamp,
+                next_zonemap_sector,
+                next_zonemap_backup_sector,
+                next_zonemap_sector_length,
                 next_zonemap_partition_size,
+                next_zonemap_min_allocation,
                 next_fsid,
+                bootcycles,
+                bootsecs,
+                is_64_bit: true,
+                computed_checksum: 0,
             },
         ))
     }
 
+    fn from_source_at_sector(source: &dyn BlockSource, sector: u64) -> Result<MFSVolumeHeader> {
+        let block = source.read_bytes(sector, MFS_VOLUME_HEADER_64_SIZE)?;
+
+        let magic = u32::from_be_bytes(block[4..8].try_into().unwrap());
+        let (parsed, size) = match magic {
+            MFS_VOLUME_HEADER_MAGIC => (MFSVolumeHeader::parse(&block), MFS_VOLUME_HEADER_SIZE),
+            MFS_VOLUME_HEADER_64_MAGIC => {
+                (MFSVolumeHeader::parse_64(&block), MFS_VOLUME_HEADER_64_SIZE)
+            }
+            _ => {
+                return Err(Error::BadMagic {
+                    structure: "volume header",
+                    expected: &[MFS_VOLUME_HEADER_MAGIC, MFS_VOLUME_HEADER_64_MAGIC],
+                    found: magic,
+                });
+            }
+        };
+
+        match parsed {
+            Ok((_, header)) => Ok(MFSVolumeHeader {
+                computed_checksum: mfs_compute_crc(
+                    &block[..size],
+                    MFS_VOLUME_HEADER_CHECKSUM_OFFSET,
+                ),
+                ..header
+            }),
+            Err(err) => Err(Error::parse("volume header", &block, err)),
+        }
+    }
+
+    /// Read the primary volume header from the first sector of the first MFS partition.
     pub fn from_partition(
         partition: &Partition,
-        source: &mut File,
-        is_byte_swapped: bool,
-    ) -> Result<MFSVolumeHeader, String> {
-        let block = get_block_from_drive_and_correct_order(
-            source,
-            u64::from(partition.starting_sector),
-            is_byte_swapped,
-        )?;
-
-        match MFSVolumeHeader::parse(&block) {
-            Ok((_, header)) => Ok(header),
-            Err(err) => Err(format!("Could not parse volume header: {:X?}", err)),
+        source: &dyn BlockSource,
+    ) -> Result<MFSVolumeHeader> {
+        MFSVolumeHeader::from_source_at_sector(source, u64::from(partition.starting_sector))
+    }
+
+    /// Read the backup volume header from the last sector of the first MFS partition.
+    pub fn backup_from_partition(
+        partition: &Partition,
+        source: &dyn BlockSource,
+    ) -> Result<MFSVolumeHeader> {
+        let sector = u64::from(partition.starting_sector) + u64::from(partition.sector_size);
+
+        MFSVolumeHeader::from_source_at_sector(source, sector.saturating_sub(1))
+    }
+}
+
+impl MFSChecksummed for MFSVolumeHeader {
+    const STRUCTURE: &'static str = "volume header";
+
+    fn stored_checksum(&self) -> u32 {
+        self.checksum
+    }
+
+    fn computed_checksum(&self) -> u32 {
+        self.computed_checksum
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use ovit_util::{MemorySource, SECTOR_SIZE};
+
+    fn mfs_partition(starting_sector: u32, sector_size: u32) -> Partition {
+        Partition {
+            partitions_total: 1,
+            starting_sector,
+            sector_size,
+            name: String::from("MFS application region"),
+            r#type: String::from("MFS"),
+            starting_data_sector: 0,
+            data_sectors: sector_size,
+            status: 0,
+            boot_code_starting_sector: 0,
+            boot_code_size: 0,
+            bootloader_address: 0,
+            boot_code_entry_point: 0,
+            boot_code_checksum: 0,
+            processor_type: String::new(),
         }
     }
+
+    fn volume_header(root_fsid: u32) -> Vec<u8> {
+        let mut sector = vec![0u8; SECTOR_SIZE];
+        sector[4..8].copy_from_slice(&MFS_VOLUME_HEADER_MAGIC.to_be_bytes());
+        sector[0x10..0x14].copy_from_slice(&root_fsid.to_be_bytes());
+
+        let checksum = mfs_compute_crc(&sector[..MFS_VOLUME_HEADER_SIZE], 8);
+        sector[8..12].copy_from_slice(&checksum.to_be_bytes());
+
+        sector
+    }
+
+    #[test]
+    fn test_64_bit_header() {
+        let mut sector = vec![0u8; SECTOR_SIZE];
+        sector[4..8].copy_from_slice(&MFS_VOLUME_HEADER_64_MAGIC.to_be_bytes());
+        sector[0xA8..0xB0].copy_from_slice(&0x1_0000_0000u64.to_be_bytes());
+        sector[0xD0..0xD8].copy_from_slice(&1121u64.to_be_bytes());
+        let checksum = mfs_compute_crc(&sector[..MFS_VOLUME_HEADER_64_SIZE], 8);
+        sector[8..12].copy_from_slice(&checksum.to_be_bytes());
+
+        let source = MemorySource::new(sector);
+        let header = MFSVolumeHeader::from_partition(&mfs_partition(0, 1), &source).unwrap();
+
+        assert!(header.is_64_bit);
+        assert!(header.is_checksum_valid());
+        assert_eq!(header.total_sectors, 0x1_0000_0000);
+        assert_eq!(header.next_zonemap_sector, 1121);
+    }
+
+    #[test]
+    fn test_primary_and_backup_headers() {
+        let mut data = vec![0u8; 8 * SECTOR_SIZE];
+        data[2 * SECTOR_SIZE..3 * SECTOR_SIZE].copy_from_slice(&volume_header(1));
+        data[7 * SECTOR_SIZE..].copy_from_slice(&volume_header(2));
+        // Corrupt the primary header without touching its magic
+        data[2 * SECTOR_SIZE + 0x20] = 0xFF;
+
+        let source = MemorySource::new(data);
+        let partition = mfs_partition(2, 6);
+
+        let primary = MFSVolumeHeader::from_partition(&partition, &source).unwrap();
+        assert_eq!(primary.root_fsid, 1);
+        assert!(!primary.is_checksum_valid());
+        assert!(primary.verify_checksum().is_err());
+
+        let backup = MFSVolumeHeader::backup_from_partition(&partition, &source).unwrap();
+        assert_eq!(backup.root_fsid, 2);
+        assert!(backup.verify_checksum().is_ok());
+    }
+
+    #[test]
+    fn test_bad_magic_lists_both_magics() {
+        let source = MemorySource::new(vec![0u8; SECTOR_SIZE]);
+        let err = MFSVolumeHeader::from_partition(&mfs_partition(0, 1), &source).unwrap_err();
+
+        assert_eq!(
+            err.to_string(),
+            "Bad volume header magic, expected 0xABBAFEED or 0xEBBAFEED but found 0x0"
+        );
+    }
 }
@@ -0,0 +1,86 @@
+extern crate nom;
+
+use nom::{bytes::streaming::take, number::streaming::be_u32, IResult};
+use std::convert::TryInto;
+
+/// One level of a zone's buddy allocator.
+///
+/// Level `n` of a zone tracks blocks of `min_allocations << n` sectors, a set bit
+///  (most significant bit first) marks a free block.
+#[derive(Debug, Clone, PartialEq)]
+pub struct MFSZoneBitmap {
+    pub nbits: u32,
+    pub free_blocks: u32,
+    pub last: u32,
+    pub block_sectors: u64,
+    pub bits: Vec<u32>,
+}
+
+impl MFSZoneBitmap {
+    pub fn parse(input: &[u8], block_sectors: u64) -> IResult<&[u8], MFSZoneBitmap> {
+        let (input, nbits) = be_u32(input)?;
+        let (input, free_blocks) = be_u32(input)?;
+        let (input, last) = be_u32(input)?;
+        let (input, nints) = be_u32(input)?;
+        let (input, bits) = take(nints as usize * 4)(input)?;
+
+        Ok((
+            input,
+            MFSZoneBitmap {
+                nbits,
+                free_blocks,
+                last,
+                block_sectors,
+                bits: bits
+                    .chunks(4)
+                    .map(|word| u32::from_be_bytes(word.try_into().unwrap()))
+                    .collect(),
+            },
+        ))
+    }
+
+    pub fn is_free(&self, block: u64) -> bool {
+        if block >= u64::from(self.nbits) {
+            return false;
+        }
+
+        match self.bits.get((block / 32) as usize) {
+            Some(word) => word & (0x8000_0000 >> (block % 32)) != 0,
+            None => false,
+        }
+    }
+
+    /// Indexes of every free block on this level.
+    pub fn free_blocks(&self) -> impl Iterator<Item = u64> + '_ {
+        (0..u64::from(self.nbits)).filter(move |block| self.is_free(*block))
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct MFSFreeExtent {
+    pub sector: u64,
+    pub sectors: u64,
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_bitmap_bits_are_msb_first() {
+        let bytes = [
+            0, 0, 0, 40, // nbits
+            0, 0, 0, 3, // freeblocks
+            0, 0, 0, 0, // last
+            0, 0, 0, 2, // nints
+            0x80, 0, 0, 0x01, //
+            0x40, 0, 0, 0,
+        ];
+
+        let (_, bitmap) = MFSZoneBitmap::parse(&bytes, 4).unwrap();
+
+        assert_eq!(bitmap.free_blocks().collect::<Vec<_>>(), vec![0, 31, 33]);
+        assert!(!bitmap.is_free(32));
+        assert!(!bitmap.is_free(64));
+    }
+}
@@ -1,10 +1,19 @@
 extern crate nom;
 extern crate ovit_util;
 
-use super::{MFSINodeIter, MFSVolumes};
-use log::warn;
-use nom::{bytes::streaming::tag, error::ErrorKind, number::streaming::be_u32, Err, IResult};
-use ovit_util::get_blocks_from_file;
+use super::{
+    mfs_checksum_fallback, mfs_compute_crc, MFSChecksummed, MFSFreeExtent, MFSINodeIter,
+    MFSVolumes, MFSZoneBitmap,
+};
+use nom::{
+    bytes::streaming::{tag, take},
+    error::ErrorKind,
+    number::streaming::{be_u32, be_u64},
+    Err, IResult,
+};
+use ovit_util::{BlockSource, Error, Result, SECTOR_SIZE};
+use std::convert::TryInto;
+use std::sync::Arc;
 
 #[derive(Debug, PartialEq, Eq, Copy, Clone)]
 pub enum MFSZoneType {
@@ -35,17 +44,46 @@ pub struct MFSZone {
     pub next_zonemap_ptr: u64,
     pub backup_next_zonemap_ptr: u64,
     pub next_zonemap_size: u32,
-    pub next_zonemap_partition_size: u32,
+    pub next_zonemap_partition_size: u64,
     pub next_zonemap_min_allocation: u32,
     pub logstamp: u32,
     pub r#type: MFSZoneType,
     pub checksum: u32,
     pub first_sector: u64,
     pub last_sector: u64,
-    pub size: u32,
+    pub size: u64,
     pub min_allocations: u32,
-    pub free_space: u32,
+    pub free_space: u64,
     pub bitmap_num: u32,
+    pub bitmaps: Vec<MFSZoneBitmap>,
+    pub is_64_bit: bool,
+    computed_checksum: u32,
+}
+
+const ZONE_CHECKSUM_OFFSET: usize = 0x28;
+const ZONE_64_CHECKSUM_OFFSET: usize = 0x60;
+/// Far more than the bitmaps of the largest zones need, anything bigger is corruption.
+const MAX_ZONEMAP_SECTORS: u64 = 4096;
+
+fn bitmaps(
+    input: &[u8],
+    bitmap_num: u32,
+    min_allocations: u32,
+) -> IResult<&[u8], Vec<MFSZoneBitmap>> {
+    // In memory pointers to each bitmap, only meaningful on the TiVo itself
+    let (mut input, _) = take(bitmap_num as usize * 4)(input)?;
+
+    let mut bitmaps = vec![];
+    for level in 0..bitmap_num {
+        let (remaining, bitmap) = MFSZoneBitmap::parse(
+            input,
+            u64::from(min_allocations).checked_shl(level).unwrap_or(0),
+        )?;
+        bitmaps.push(bitmap);
+        input = remaining;
+    }
+
+    Ok((input, bitmaps))
 }
 
 impl MFSZone {
@@ -68,6 +106,7 @@ impl MFSZone {
         let (input, free_space) = be_u32(input)?;
         let (input, _) = tag([0, 0, 0, 0])(input)?;
         let (input, bitmap_num) = be_u32(input)?;
+        let (input, bitmaps) = bitmaps(input, bitmap_num, min_allocations)?;
 
         Ok((
             input,
@@ -78,167 +117,442 @@ impl MFSZone {
                 next_zonemap_ptr: u64::from(next_zonemap_ptr),
                 backup_next_zonemap_ptr: u64::from(backup_next_zonemap_ptr),
                 next_zonemap_size,
-                next_zonemap_partition_size,
+                next_zonemap_partition_size: u64::from(next_zonemap_partition_size),
                 next_zonemap_min_allocation,
                 r#type,
                 logstamp,
                 checksum,
                 first_sector: u64::from(first_sector),
                 last_sector: u64::from(last_sector),
+                size: u64::from(size),
+                min_allocations,
+                free_space: u64::from(free_space),
+                bitmap_num,
+                bitmaps,
+                is_64_bit: false,
+                computed_checksum: 0,
+            },
+        ))
+    }
+
+    fn parse_64(input: &[u8]) -> IResult<&[u8], MFSZone> {
+        let (input, sector) = be_u64(input)?;
+        let (input, backup_sector) = be_u64(input)?;
+        let (input, next_zonemap_ptr) = be_u64(input)?;
+        let (input, backup_next_zonemap_ptr) = be_u64(input)?;
+        let (input, next_zonemap_partition_size) = be_u64(input)?;
+        let (input, first_sector) = be_u64(input)?;
+        let (input, last_sector) = be_u64(input)?;
+        let (input, size) = be_u64(input)?;
+        let (input, free_space) = be_u64(input)?;
+        let (input, next_zonemap_size) = be_u32(input)?;
+        let (input, zonemap_size) = be_u32(input)?;
+        let (input, min_allocations) = be_u32(input)?;
+        let (input, next_zonemap_min_allocation) = be_u32(input)?;
+        let (input, logstamp) = be_u32(input)?;
+        let (input, r#type) = MFSZoneType::parse(input)?;
+        let (input, checksum) = be_u32(input)?;
+        let (input, _) = tag([0, 0, 0, 0])(input)?;
+        let (input, bitmap_num) = be_u32(input)?;
+        let (input, bitmaps) = bitmaps(input, bitmap_num, min_allocations)?;
+
+        Ok((
+            input,
+            MFSZone {
+                sector,
+                backup_sector,
+                zonemap_size,
+                next_zonemap_ptr,
+                backup_next_zonemap_ptr,
+                next_zonemap_size,
+                next_zonemap_partition_size,
+                next_zonemap_min_allocation,
+                r#type,
+                logstamp,
+                checksum,
+                first_sector,
+                last_sector,
                 size,
                 min_allocations,
                 free_space,
                 bitmap_num,
+                bitmaps,
+                is_64_bit: true,
+                computed_checksum: 0,
             },
         ))
     }
 
-    fn from_file_at_sector(
-        path: &str,
+    /// Read the zone map at `sector`, which can't run past the `max_sectors` left in its volume.
+    fn read(
+        source: &dyn BlockSource,
         sector: u64,
-        backup_sector: u64,
-        size: usize,
-        is_byte_swapped: bool,
-    ) -> Result<MFSZone, String> {
-        let zonemap_bytes = &match get_blocks_from_file(
-            path,
-            sector,
-            // size,
-            1,
-            is_byte_swapped,
-        ) {
-            Ok(blocks) => blocks.to_vec(),
-            Err(err) => {
-                return Err(format!(
-                    "Couldn't load block at sector {} and size {} with error {:?}:",
-                    sector, size, err
-                ));
-            }
+        max_sectors: u64,
+        is_64_bit: bool,
+    ) -> Result<Vec<u8>> {
+        let first_sector = source.read_sector(sector)?;
+        let length_offset = if is_64_bit { 0x4C } else { 0x08 };
+        let zonemap_size = u32::from_be_bytes(
+            first_sector[length_offset..length_offset + 4]
+                .try_into()
+                .unwrap(),
+        );
+
+        if u64::from(zonemap_size) > max_sectors.min(MAX_ZONEMAP_SECTORS) {
+            return Err(Error::Parse {
+                structure: "zone map",
+                offset: length_offset,
+                kind: ErrorKind::TooLarge,
+            });
+        }
+
+        if zonemap_size > 1 {
+            source.read_sectors(sector, zonemap_size as usize)
+        } else {
+            Ok(first_sector)
+        }
+    }
+
+    fn from_bytes(bytes: &[u8], is_64_bit: bool) -> Result<MFSZone> {
+        let (parsed, checksum_offset) = if is_64_bit {
+            (MFSZone::parse_64(bytes), ZONE_64_CHECKSUM_OFFSET)
+        } else {
+            (MFSZone::parse(bytes), ZONE_CHECKSUM_OFFSET)
         };
 
-        match MFSZone::parse(&zonemap_bytes) {
-            Ok((_, zonemap)) => Ok(zonemap),
-            Err(_) => {
-                warn!("Couldn't load zonemap, trying backup");
-                let backup_zonemap_bytes = &match get_blocks_from_file(
-                    path,
-                    backup_sector,
-                    // size,
-                    1,
-                    is_byte_swapped,
-                ) {
-                    Ok(blocks) => blocks.to_vec(),
-                    Err(err) => {
-                        return Err(format!(
-                            "Couldn't load block at sector {} and size {} with error {:?}:",
-                            sector, size, err
-                        ));
-                    }
-                };
-                match MFSZone::parse(&backup_zonemap_bytes) {
-                    Ok((_, backup_zonemap)) => Ok(backup_zonemap),
-                    Err(backup_err) => Err(format!(
-                        "Couldn't parse zonemap blocks at sector {} and size {} with err {:?}:,",
-                        sector, size, backup_err
-                    )),
+        match parsed {
+            Ok((_, zone)) => Ok(MFSZone {
+                computed_checksum: mfs_compute_crc(
+                    &bytes[..(zone.zonemap_size as usize * SECTOR_SIZE).min(bytes.len())],
+                    checksum_offset,
+                ),
+                ..zone
+            }),
+            Err(err) => Err(Error::parse("zone map", bytes, err)),
+        }
+    }
+
+    /// Read the zone map at `sector`, falling back to the copy at `backup`
+    ///  when the primary is unreadable, too large or fails its checksum.
+    ///  Each location is a disk sector with the number of sectors left in its volume,
+    ///  a backup that couldn't be located leaves only the primary.
+    fn from_source_at_sector(
+        source: &dyn BlockSource,
+        (sector, max_sectors): (u64, u64),
+        backup: Result<(u64, u64)>,
+        is_64_bit: bool,
+    ) -> Result<MFSZone> {
+        let primary = MFSZone::read(source, sector, max_sectors, is_64_bit)
+            .and_then(|bytes| MFSZone::from_bytes(&bytes, is_64_bit));
+
+        mfs_checksum_fallback(sector, primary, || {
+            let (backup_sector, backup_max_sectors) = backup?;
+            MFSZone::read(source, backup_sector, backup_max_sectors, is_64_bit)
+                .and_then(|bytes| MFSZone::from_bytes(&bytes, is_64_bit))
+        })
+    }
+
+    pub fn contains_sector(&self, sector: u64) -> bool {
+        self.first_sector <= sector && sector <= self.last_sector
+    }
+
+    /// Whether the block containing `sector` is in use, `None` if the sector isn't in this zone.
+    ///
+    /// A block is free if it, or any larger block containing it, is marked free.
+    pub fn is_sector_allocated(&self, sector: u64) -> Option<bool> {
+        if !self.contains_sector(sector) {
+            return None;
+        }
+
+        let offset = sector - self.first_sector;
+
+        Some(
+            !self
+                .bitmaps
+                .iter()
+                .filter(|bitmap| bitmap.block_sectors != 0)
+                .any(|bitmap| bitmap.is_free(offset / bitmap.block_sectors)),
+        )
+    }
+
+    /// Every free run of sectors in the zone, sorted and with neighbouring blocks merged.
+    pub fn free_extents(&self) -> Vec<MFSFreeExtent> {
+        let mut extents: Vec<MFSFreeExtent> = self
+            .bitmaps
+            .iter()
+            .flat_map(|bitmap| {
+                bitmap.free_blocks().map(move |block| MFSFreeExtent {
+                    sector: self.first_sector + block * bitmap.block_sectors,
+                    sectors: bitmap.block_sectors,
+                })
+            })
+            .collect();
+        extents.sort();
+
+        extents.into_iter().fold(vec![], |mut acc, extent| {
+            match acc.last_mut() {
+                Some(last) if last.sector + last.sectors >= extent.sector => {
+                    last.sectors = last
+                        .sectors
+                        .max(extent.sector + extent.sectors - last.sector);
                 }
+                _ => acc.push(extent),
             }
+            acc
+        })
+    }
+
+    pub fn free_sectors(&self) -> u64 {
+        self.free_extents()
+            .iter()
+            .map(|extent| extent.sectors)
+            .sum()
+    }
+
+    /// Fraction of the zone in use, between 0 and 1.
+    pub fn utilisation(&self) -> f64 {
+        if self.size == 0 {
+            return 0.0;
         }
+
+        1.0 - self.free_sectors() as f64 / self.size as f64
+    }
+}
+
+impl MFSChecksummed for MFSZone {
+    const STRUCTURE: &'static str = "zone map";
+
+    fn stored_checksum(&self) -> u32 {
+        self.checksum
+    }
+
+    fn computed_checksum(&self) -> u32 {
+        self.computed_checksum
     }
 }
 
 #[derive(Debug, Clone)]
 pub struct MFSZoneMap {
-    source_file_path: String,
-    is_source_byte_swapped: bool,
+    source: Arc<dyn BlockSource>,
 
     next_zonemap_ptr: u64,
     backup_next_zonemap_ptr: u64,
-    next_zonemap_size: u32,
 
     volumes: MFSVolumes,
+    is_64_bit: bool,
 }
 
 impl MFSZoneMap {
     pub fn new(
-        path: &str,
+        source: Arc<dyn BlockSource>,
         volumes: &MFSVolumes,
         sector: u64,
         backup_sector: u64,
-        size: usize,
-        is_byte_swapped: bool,
-    ) -> Result<MFSZoneMap, String> {
+        is_64_bit: bool,
+    ) -> Result<MFSZoneMap> {
         Ok(MFSZoneMap {
-            source_file_path: path.to_string(),
-            is_source_byte_swapped: is_byte_swapped,
+            source,
 
             next_zonemap_ptr: sector,
             backup_next_zonemap_ptr: backup_sector,
-            next_zonemap_size: size as u32,
 
             volumes: volumes.clone(),
+            is_64_bit,
         })
     }
 
-    pub fn inode_iter(&mut self) -> Result<MFSINodeIter, String> {
-        let inode_zone = match self.find(|node| node.r#type == MFSZoneType::INode) {
-            Some(node_zone) => node_zone,
+    pub fn inode_iter(&mut self) -> Result<MFSINodeIter> {
+        let inode_zone = match self.find(|zone| {
+            zone.as_ref()
+                .map_or(true, |zone| zone.r#type == MFSZoneType::INode)
+        }) {
+            Some(zone) => zone?,
             None => {
-                return Err("Could not load inode zone".to_string());
+                return Err(Error::NotFound("inode zone"));
             }
         };
 
+        let volume = self.volumes.find_sector_volume(inode_zone.first_sector)?;
+
+        // The iterator's sectors are relative to the volume holding the zone
         Ok(MFSINodeIter {
-            source_file_path: String::from(&self.source_file_path),
-            partition_starting_sector: self
-                .volumes
-                .find_sector_volume(inode_zone.first_sector)
-                .disk_sector
-                .into(),
-            is_source_byte_swapped: self.is_source_byte_swapped,
-
-            next_inode_sector: inode_zone.first_sector,
-            last_inode_sector: inode_zone.last_sector,
+            source: Arc::clone(&self.source),
+            partition_starting_sector: volume.disk_sector.into(),
+
+            next_inode_sector: inode_zone.first_sector - u64::from(volume.sector_start),
+            last_inode_sector: inode_zone.last_sector - u64::from(volume.sector_start),
+            is_64_bit: self.is_64_bit,
         })
     }
 
-    pub fn inode_count(&mut self) -> u32 {
-        self.filter(|zone| zone.r#type == MFSZoneType::INode)
-            .map(|zone| zone.size)
-            .sum::<u32>()
-            / 2
+    pub fn inode_count(&mut self) -> Result<u64> {
+        let mut count = 0;
+        for zone in self {
+            let zone = zone?;
+            if zone.r#type == MFSZoneType::INode {
+                count += zone.size / 2;
+            }
+        }
+
+        Ok(count)
     }
 }
 
+/// The disk sector holding the volume `sector`, and how many sectors are left in its volume.
+fn disk_location(volumes: &MFSVolumes, sector: u64) -> Result<(u64, u64)> {
+    let volume = volumes.find_sector_volume(sector)?;
+    let volume_end = u64::from(volume.sector_start) + u64::from(volume.sector_count);
+
+    Ok((
+        volumes.sector_to_disk_location(sector)?,
+        volume_end - sector,
+    ))
+}
+
+/// Each zone map in the chain, in order. A zone map that can't be read ends the chain
+///  with its error, so a damaged chain isn't mistaken for a short one.
 impl Iterator for MFSZoneMap {
-    type Item = MFSZone;
+    type Item = Result<MFSZone>;
 
     fn next(&mut self) -> Option<Self::Item> {
         if self.next_zonemap_ptr != 0 {
-            let zonemap = match MFSZone::from_file_at_sector(
-                &self.source_file_path,
-                self.volumes
-                    .clone()
-                    .sector_to_disk_location(self.next_zonemap_ptr),
-                self.volumes
-                    .clone()
-                    .sector_to_disk_location(self.backup_next_zonemap_ptr),
-                self.next_zonemap_size as usize,
-                self.is_source_byte_swapped,
-            ) {
+            let zonemap =
+                disk_location(&self.volumes, self.next_zonemap_ptr).and_then(|location| {
+                    MFSZone::from_source_at_sector(
+                        &*self.source,
+                        location,
+                        disk_location(&self.volumes, self.backup_next_zonemap_ptr),
+                        self.is_64_bit,
+                    )
+                });
+
+            let zonemap = match zonemap {
                 Ok(map) => map,
-                Err(_) => {
-                    return None;
+                Err(err) => {
+                    self.next_zonemap_ptr = 0;
+                    return Some(Err(err));
                 }
             };
 
             self.next_zonemap_ptr = zonemap.next_zonemap_ptr;
-            self.next_zonemap_size = zonemap.next_zonemap_size;
             self.backup_next_zonemap_ptr = zonemap.backup_next_zonemap_ptr;
 
-            Some(zonemap)
+            Some(Ok(zonemap))
         } else {
             None
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn be_words(words: &[u32]) -> Vec<u8> {
+        words.iter().flat_map(|word| word.to_be_bytes()).collect()
+    }
+
+    /// A zone map sector with a valid checksum and no next zone map.
+    fn zone_sector(zonemap_size: u32) -> Vec<u8> {
+        let mut sector = be_words(&[
+            1,
+            2,
+            zonemap_size,
+            0,
+            0,
+            0,
+            0,
+            0, // sector, backup, length, next zone map
+            0,
+            0,
+            0, // type, logstamp, checksum
+            100,
+            115,
+            16,
+            2,
+            0,
+            0,
+            0, // first, last, size, min, free, zero, num
+        ]);
+        sector.resize(SECTOR_SIZE, 0);
+        let checksum = mfs_compute_crc(&sector, ZONE_CHECKSUM_OFFSET);
+        sector[ZONE_CHECKSUM_OFFSET..ZONE_CHECKSUM_OFFSET + 4]
+            .copy_from_slice(&checksum.to_be_bytes());
+        sector
+    }
+
+    #[test]
+    fn test_zone_bitmaps() {
+        // 16 sectors starting at 100, allocated in blocks of 2, 4 and 8 sectors
+        let mut bytes = be_words(&[
+            10, 20, 1, 0, 0, 0, 0, 0, // sector, backup, length, next zone map
+            2, 0, 0, // type, logstamp, checksum
+            100, 115, 16, 2, 8, 0, 3, // first, last, size, min, free, zero, num
+            0, 0, 0, // bitmap pointers
+        ]);
+        // Level 0, block 1 (sectors 102 and 103) is free
+        bytes.extend(be_words(&[8, 1, 0, 1, 0x4000_0000]));
+        // Level 1, block 2 (sectors 108 to 111) is free
+        bytes.extend(be_words(&[4, 1, 0, 1, 0x2000_0000]));
+        // Level 2, nothing free
+        bytes.extend(be_words(&[2, 0, 0, 1, 0]));
+
+        let (_, zone) = MFSZone::parse(&bytes).unwrap();
+
+        assert_eq!(zone.bitmaps.len(), 3);
+        assert_eq!(zone.bitmaps[2].block_sectors, 8);
+        assert_eq!(zone.is_sector_allocated(99), None);
+        assert_eq!(zone.is_sector_allocated(100), Some(true));
+        assert_eq!(zone.is_sector_allocated(103), Some(false));
+        assert_eq!(zone.is_sector_allocated(109), Some(false));
+        assert_eq!(zone.is_sector_allocated(112), Some(true));
+        assert_eq!(
+            zone.free_extents(),
+            vec![
+                MFSFreeExtent {
+                    sector: 102,
+                    sectors: 2
+                },
+                MFSFreeExtent {
+                    sector: 108,
+                    sectors: 4
+                }
+            ]
+        );
+        assert_eq!(zone.free_sectors(), 6);
+        assert!((zone.utilisation() - 0.625).abs() < f64::EPSILON);
+    }
+
+    #[test]
+    fn test_oversized_zone_map_uses_backup() {
+        let mut data = vec![0u8; SECTOR_SIZE];
+        data.extend(zone_sector(0xFFFF_FFFF));
+        data.extend(zone_sector(1));
+        let source = ovit_util::MemorySource::new(data);
+
+        assert!(MFSZone::read(&source, 1, 2, false).is_err());
+
+        let zone = MFSZone::from_source_at_sector(&source, (1, 2), Ok((2, 1)), false).unwrap();
+        assert_eq!(zone.zonemap_size, 1);
+        assert!(zone.is_checksum_valid());
+    }
+
+    #[test]
+    fn test_zone_map_chain_errors() {
+        let mut data = vec![0u8; SECTOR_SIZE];
+        data.extend(zone_sector(1));
+        let source: Arc<dyn BlockSource> = Arc::new(ovit_util::MemorySource::new(data));
+        let volumes = crate::test_fixtures::volumes(&[0]);
+
+        // A backup that's off the volumes still leaves the primary
+        let zones = MFSZoneMap::new(Arc::clone(&source), &volumes, 1, 100, false).unwrap();
+        let zones: Vec<Result<MFSZone>> = zones.collect();
+        assert_eq!(zones.len(), 1);
+        assert_eq!(zones[0].as_ref().unwrap().zonemap_size, 1);
+
+        // A zone map that can't be read ends the chain with its error
+        let mut zones = MFSZoneMap::new(source, &volumes, 100, 100, false).unwrap();
+        assert!(zones.next().unwrap().is_err());
+        assert!(zones.next().is_none());
+    }
+}